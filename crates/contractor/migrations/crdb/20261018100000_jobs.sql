@@ -0,0 +1,12 @@
+CREATE TABLE IF NOT EXISTS jobs (
+    id UUID PRIMARY KEY,
+    repository TEXT NOT NULL,
+    triggered_by TEXT NOT NULL,
+    requester TEXT,
+    status TEXT NOT NULL,
+    output TEXT,
+    started_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    finished_at TIMESTAMPTZ
+);
+
+CREATE INDEX IF NOT EXISTS jobs_repository_started_at_idx ON jobs (repository, started_at DESC);
@@ -0,0 +1,10 @@
+CREATE TABLE IF NOT EXISTS job_logs (
+    job_id UUID NOT NULL REFERENCES jobs (id) ON DELETE CASCADE,
+    seq INT8 NOT NULL,
+    stream TEXT NOT NULL,
+    level TEXT,
+    time TEXT,
+    message TEXT NOT NULL,
+    fields JSONB,
+    PRIMARY KEY (job_id, seq)
+);
@@ -0,0 +1,6 @@
+CREATE TABLE IF NOT EXISTS onboarding (
+    repository TEXT PRIMARY KEY,
+    status TEXT NOT NULL,
+    pull_request INT8,
+    updated_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
@@ -0,0 +1,5 @@
+CREATE TABLE IF NOT EXISTS pending_pushes (
+    repository TEXT PRIMARY KEY,
+    requester TEXT,
+    due_at TIMESTAMPTZ NOT NULL
+);
@@ -1,29 +1,53 @@
-use std::net::SocketAddr;
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
 
 use anyhow::Context;
 use axum::{
-    extract::{MatchedPath, State},
-    http::Request,
-    response::IntoResponse,
+    body::Bytes,
+    extract::{MatchedPath, Path, Query, State},
+    http::{header, HeaderMap, Request, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
     routing::{get, post},
     Json, Router,
 };
 use serde::{Deserialize, Serialize};
 use tower_http::trace::TraceLayer;
+use uuid::Uuid;
 
 use crate::{
     services::{
         bot::{BotRequest, BotState},
-        gitea::Repository,
+        engines::dagger::{EngineConnection, EngineUnavailable},
+        events::{EventHandlerState, GiteaEvent, PullRequestEvent, PushEvent, RepositoryEvent},
+        gitea::{GiteaClientState, Repository, RepositoryMetadata},
     },
     SharedState,
 };
 
 pub async fn serve_axum(state: &SharedState, host: &SocketAddr) -> Result<(), anyhow::Error> {
     tracing::info!("running webhook server");
+    if state.config.api.token.is_none() {
+        tracing::warn!("CONTRACTOR_API_TOKEN is not set, /api is disabled");
+    }
+
+    let api = Router::new()
+        .route("/api/jobs", get(list_jobs))
+        .route("/api/jobs/{id}", get(get_job))
+        .route("/api/jobs/{id}/logs", get(get_job_logs))
+        .route("/api/queue", get(get_queue))
+        .route("/api/onboarding", get(list_onboarding))
+        .route_layer(middleware::from_fn_with_state(
+            state.to_owned(),
+            require_api_token,
+        ));
+
     let app = Router::new()
         .route("/", get(root))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
         .route("/webhooks/gitea", post(gitea_webhook))
+        .merge(api)
+        .route("/metrics", get(metrics))
         .with_state(state.to_owned())
         .layer(
             TraceLayer::new_for_http().make_span_with(|request: &Request<_>| {
@@ -54,26 +78,179 @@ async fn root() -> &'static str {
     "Hello, contractor!"
 }
 
+const READINESS_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CheckStatus {
+    Ok,
+    Failed,
+    /// The dependency isn't configured
+    Skipped,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Check {
+    status: CheckStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl From<anyhow::Result<()>> for Check {
+    fn from(value: anyhow::Result<()>) -> Self {
+        match value {
+            Ok(()) => Check {
+                status: CheckStatus::Ok,
+                error: None,
+            },
+            Err(e) => Check {
+                status: CheckStatus::Failed,
+                error: Some(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct Readiness {
+    ready: bool,
+    checks: BTreeMap<&'static str, Check>,
+}
+
+/// The process is up and serving requests
+async fn healthz() -> impl IntoResponse {
+    Json(serde_json::json!({ "status": "ok" }))
+}
+
+/// Contractor can run renovate: dagger is connected, gitea accepts the token
+/// and the database, when configured, is reachable
+async fn readyz(State(state): State<SharedState>) -> impl IntoResponse {
+    let (dagger, gitea, database) = tokio::join!(
+        async { check_dagger(&state) },
+        check_gitea(&state),
+        check_database(&state)
+    );
+
+    let checks = BTreeMap::from([("dagger", dagger), ("gitea", gitea), ("database", database)]);
+    let ready = checks.values().all(|c| c.status != CheckStatus::Failed);
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    (status, Json(Readiness { ready, checks }))
+}
+
+fn check_dagger(state: &SharedState) -> Check {
+    match state.engine.connection() {
+        EngineConnection::Connected => Ok(()),
+        connection => Err(EngineUnavailable { connection }.into()),
+    }
+    .into()
+}
+
+async fn check_gitea(state: &SharedState) -> Check {
+    state
+        .gitea_client()
+        .get_current_user()
+        .await
+        .map(|_| ())
+        .into()
+}
+
+async fn check_database(state: &SharedState) -> Check {
+    let Some(db) = &state.db else {
+        return Check {
+            status: CheckStatus::Skipped,
+            error: None,
+        };
+    };
+
+    match tokio::time::timeout(READINESS_TIMEOUT, sqlx::query("SELECT 1;").fetch_one(db)).await {
+        Ok(res) => res.map(|_| ()).map_err(anyhow::Error::from),
+        Err(_) => Err(anyhow::anyhow!("database did not respond in time")),
+    }
+    .into()
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GiteaWebhookComment {
     body: String,
 }
 #[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaWebhookIssue {
+    number: u64,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaWebhookUser {
+    login: String,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct GiteaWebhookRepository {
     full_name: String,
+    #[serde(default)]
+    default_branch: String,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaWebhookCommit {
+    #[serde(default)]
+    added: Vec<String>,
+    #[serde(default)]
+    removed: Vec<String>,
+    #[serde(default)]
+    modified: Vec<String>,
+}
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaWebhookPullRequest {
+    title: String,
 }
 
+/// `issue_comment` events, gitea also sends comments on pull requests as these
 #[derive(Serialize, Deserialize, Clone, Debug)]
-#[serde(untagged)]
-pub enum GiteaWebhook {
-    Issue {
-        comment: GiteaWebhookComment,
-        repository: GiteaWebhookRepository,
-    },
+pub struct GiteaIssueCommentEvent {
+    action: Option<String>,
+    comment: GiteaWebhookComment,
+    issue: GiteaWebhookIssue,
+    repository: GiteaWebhookRepository,
+    sender: Option<GiteaWebhookUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaPushEvent {
+    #[serde(rename = "ref")]
+    git_ref: String,
+    #[serde(default)]
+    before: String,
+    #[serde(default)]
+    after: String,
+    #[serde(default)]
+    commits: Vec<GiteaWebhookCommit>,
+    repository: GiteaWebhookRepository,
+    pusher: Option<GiteaWebhookUser>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaPullRequestEvent {
+    action: String,
+    number: u64,
+    pull_request: GiteaWebhookPullRequest,
+    repository: GiteaWebhookRepository,
+}
+
+/// Only delivered by organisation or system webhooks
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct GiteaRepositoryEvent {
+    action: String,
+    repository: GiteaWebhookRepository,
 }
 
 pub enum ApiError {
     InternalError(anyhow::Error),
+    BadRequest(anyhow::Error),
+    Unauthorized(anyhow::Error),
+    NotFound(String),
 }
 
 impl IntoResponse for ApiError {
@@ -84,53 +261,468 @@ impl IntoResponse for ApiError {
 
                 (axum::http::StatusCode::INTERNAL_SERVER_ERROR, e.to_string())
             }
+            ApiError::BadRequest(e) => {
+                tracing::warn!("rejected bad request: {:#}", e);
+
+                (axum::http::StatusCode::BAD_REQUEST, format!("{:#}", e))
+            }
+            ApiError::Unauthorized(e) => {
+                tracing::warn!("rejected unauthorized request: {}", e);
+
+                (axum::http::StatusCode::UNAUTHORIZED, "unauthorized".into())
+            }
+            ApiError::NotFound(e) => (axum::http::StatusCode::NOT_FOUND, e),
         }
         .into_response()
     }
 }
 
+/// Gitea events recorded by name in metrics, anything else is recorded as
+/// `other` to keep the label bounded
+const KNOWN_EVENTS: &[&str] = &[
+    "create",
+    "delete",
+    "issue_comment",
+    "issues",
+    "pull_request",
+    "pull_request_comment",
+    "push",
+    "release",
+    "repository",
+];
+
 async fn gitea_webhook(
     State(state): State<SharedState>,
-    Json(json): Json<GiteaWebhook>,
+    headers: HeaderMap,
+    body: Bytes,
 ) -> Result<impl IntoResponse, ApiError> {
-    tracing::info!(
-        "called: {}",
-        serde_json::to_string(&json)
-            .context("failed to serialize webhook")
-            .map_err(ApiError::InternalError)?
-    );
+    let event = headers
+        .get("X-Gitea-Event")
+        .and_then(|e| e.to_str().ok())
+        .filter(|e| KNOWN_EVENTS.contains(e))
+        .unwrap_or("other");
+
+    let result = handle_gitea_webhook(&state, &headers, &body).await;
+
+    let outcome = match &result {
+        Ok((StatusCode::ACCEPTED, _)) => "ignored",
+        Ok(_) => "ok",
+        Err(ApiError::Unauthorized(_)) => "unauthorized",
+        Err(ApiError::BadRequest(_)) => "invalid",
+        Err(_) => "error",
+    };
+    state
+        .metrics
+        .webhook_requests
+        .with_label_values(&[event, outcome])
+        .inc();
+
+    result
+}
+
+async fn handle_gitea_webhook(
+    state: &SharedState,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<(StatusCode, &'static str), ApiError> {
+    let signature = headers
+        .get("X-Gitea-Signature")
+        .and_then(|s| s.to_str().ok());
+
+    state
+        .webhook_signature
+        .verify(signature, body)
+        .map_err(ApiError::Unauthorized)?;
+
+    let event = headers
+        .get("X-Gitea-Event")
+        .and_then(|e| e.to_str().ok())
+        .unwrap_or_default();
 
-    let bot_req: BotRequest = json.try_into().map_err(ApiError::InternalError)?;
+    tracing::info!("called with event: {}", event);
+
+    let event = match event {
+        "issue_comment" => {
+            let comment: GiteaIssueCommentEvent = parse_webhook(body)?;
+            if comment.action.as_deref().is_some_and(|a| a != "created") {
+                return Ok((StatusCode::ACCEPTED, "ignored"));
+            }
+
+            let bot_req: BotRequest = comment.try_into().map_err(ApiError::BadRequest)?;
+
+            state
+                .bot()
+                .handle_request(bot_req)
+                .await
+                .map_err(ApiError::InternalError)?;
+
+            return Ok((StatusCode::OK, "Hello, contractor!"));
+        }
+        "push" => GiteaEvent::Push(
+            parse_webhook::<GiteaPushEvent>(body)?
+                .try_into()
+                .map_err(ApiError::BadRequest)?,
+        ),
+        "pull_request" => GiteaEvent::PullRequest(
+            parse_webhook::<GiteaPullRequestEvent>(body)?
+                .try_into()
+                .map_err(ApiError::BadRequest)?,
+        ),
+        "repository" => GiteaEvent::Repository(
+            parse_webhook::<GiteaRepositoryEvent>(body)?
+                .try_into()
+                .map_err(ApiError::BadRequest)?,
+        ),
+        _ => {
+            tracing::debug!("ignoring unhandled event: {}", event);
+            return Ok((StatusCode::ACCEPTED, "ignored"));
+        }
+    };
 
     state
-        .bot()
-        .handle_request(bot_req)
+        .event_handler()
+        .handle(event)
         .await
         .map_err(ApiError::InternalError)?;
 
-    Ok("Hello, contractor!")
+    Ok((StatusCode::OK, "Hello, contractor!"))
+}
+
+fn parse_webhook<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+    serde_json::from_slice(body)
+        .context("failed to deserialize webhook")
+        .map_err(ApiError::BadRequest)
+}
+
+/// Job logs and history can contain anything renovate printed, so `/api` is
+/// only served to callers presenting `api.token` as a bearer token
+async fn require_api_token(
+    State(state): State<SharedState>,
+    headers: HeaderMap,
+    request: axum::extract::Request,
+    next: Next,
+) -> Result<Response, ApiError> {
+    let Some(token) = &state.config.api.token else {
+        return Err(ApiError::Unauthorized(anyhow::anyhow!(
+            "api token is not configured, /api is disabled"
+        )));
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .ok_or(ApiError::Unauthorized(anyhow::anyhow!(
+            "bearer token is missing"
+        )))?;
+
+    if !constant_time_eq(provided.trim().as_bytes(), token.as_bytes()) {
+        return Err(ApiError::Unauthorized(anyhow::anyhow!(
+            "bearer token did not match"
+        )));
+    }
+
+    Ok(next.run(request).await)
 }
 
-impl TryFrom<GiteaWebhook> for BotRequest {
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ListJobsQuery {
+    repository: Option<String>,
+    limit: Option<i64>,
+}
+
+async fn list_jobs(
+    State(state): State<SharedState>,
+    Query(query): Query<ListJobsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    let jobs = state
+        .jobs
+        .list_jobs(
+            query.repository.as_deref(),
+            query.limit.unwrap_or(50).clamp(1, 500),
+        )
+        .await
+        .map_err(ApiError::InternalError)?;
+
+    Ok(Json(jobs))
+}
+
+async fn get_job(
+    State(state): State<SharedState>,
+    Path(id): Path<Uuid>,
+) -> Result<impl IntoResponse, ApiError> {
+    let job = state
+        .jobs
+        .get_job(id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or(ApiError::NotFound(format!("job: {} was not found", id)))?;
+
+    Ok(Json(job))
+}
+
+#[derive(Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Ndjson,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct JobLogsQuery {
+    format: Option<LogFormat>,
+}
+
+async fn get_job_logs(
+    State(state): State<SharedState>,
+    Path(id): Path<Uuid>,
+    Query(query): Query<JobLogsQuery>,
+) -> Result<impl IntoResponse, ApiError> {
+    state
+        .jobs
+        .get_job(id)
+        .await
+        .map_err(ApiError::InternalError)?
+        .ok_or(ApiError::NotFound(format!("job: {} was not found", id)))?;
+
+    let entries = state
+        .jobs
+        .get_logs(id)
+        .await
+        .map_err(ApiError::InternalError)?;
+
+    let (content_type, body) = match query.format.unwrap_or_default() {
+        LogFormat::Text => (
+            "text/plain; charset=utf-8",
+            entries
+                .iter()
+                .map(|e| format!("{}\n", e))
+                .collect::<String>(),
+        ),
+        LogFormat::Ndjson => (
+            "application/x-ndjson",
+            entries
+                .iter()
+                .map(|e| serde_json::to_string(e).map(|e| e + "\n"))
+                .collect::<Result<String, _>>()
+                .context("failed to serialize logs")
+                .map_err(ApiError::InternalError)?,
+        ),
+    };
+
+    Ok(([(header::CONTENT_TYPE, content_type)], body))
+}
+
+async fn get_queue(State(state): State<SharedState>) -> impl IntoResponse {
+    Json(state.queue.depth())
+}
+
+async fn list_onboarding(State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
+    let onboarding = state
+        .onboarding
+        .list()
+        .await
+        .map_err(ApiError::InternalError)?;
+
+    Ok(Json(onboarding))
+}
+
+async fn metrics(State(state): State<SharedState>) -> Result<impl IntoResponse, ApiError> {
+    let depth = state.queue.depth();
+    state.metrics.queue_pending.set(depth.pending as i64);
+    state.metrics.queue_running.set(depth.running as i64);
+
+    let body = state.metrics.render().map_err(ApiError::InternalError)?;
+
+    Ok(([(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], body))
+}
+
+impl TryFrom<GiteaWebhookRepository> for Repository {
     type Error = anyhow::Error;
-    fn try_from(value: GiteaWebhook) -> Result<Self, Self::Error> {
-        match value {
-            GiteaWebhook::Issue {
-                comment,
-                repository,
-            } => {
-                let (owner, name) = repository.full_name.split_once('/').ok_or(anyhow::anyhow!(
-                    "{} did not contain a valid owner/repository",
-                    &repository.full_name
-                ))?;
-
-                Ok(BotRequest {
-                    repo: Repository {
-                        owner: owner.into(),
-                        name: name.into(),
-                    },
-                    command: comment.body,
-                })
+    fn try_from(value: GiteaWebhookRepository) -> Result<Self, Self::Error> {
+        let (owner, name) = value.full_name.split_once('/').ok_or(anyhow::anyhow!(
+            "{} did not contain a valid owner/repository",
+            &value.full_name
+        ))?;
+
+        Ok(Repository {
+            owner: owner.into(),
+            name: name.into(),
+            metadata: RepositoryMetadata {
+                default_branch: value.default_branch,
+                ..Default::default()
+            },
+        })
+    }
+}
+
+impl TryFrom<GiteaIssueCommentEvent> for BotRequest {
+    type Error = anyhow::Error;
+    fn try_from(value: GiteaIssueCommentEvent) -> Result<Self, Self::Error> {
+        Ok(BotRequest {
+            repo: value.repository.try_into()?,
+            issue: value.issue.number,
+            requester: value.sender.map(|s| s.login),
+            command: value.comment.body,
+        })
+    }
+}
+
+impl TryFrom<GiteaPushEvent> for PushEvent {
+    type Error = anyhow::Error;
+    fn try_from(value: GiteaPushEvent) -> Result<Self, Self::Error> {
+        let mut changed_files = value
+            .commits
+            .into_iter()
+            .flat_map(|c| c.added.into_iter().chain(c.removed).chain(c.modified))
+            .collect::<Vec<_>>();
+        changed_files.sort();
+        changed_files.dedup();
+
+        Ok(PushEvent {
+            repo: value.repository.try_into()?,
+            git_ref: value.git_ref,
+            // gitea sends a zero commit id when the ref was created
+            before: Some(value.before).filter(|b| !b.is_empty() && b.chars().any(|c| c != '0')),
+            after: value.after,
+            changed_files,
+            pusher: value.pusher.map(|p| p.login),
+        })
+    }
+}
+
+impl TryFrom<GiteaPullRequestEvent> for PullRequestEvent {
+    type Error = anyhow::Error;
+    fn try_from(value: GiteaPullRequestEvent) -> Result<Self, Self::Error> {
+        Ok(PullRequestEvent {
+            repo: value.repository.try_into()?,
+            action: value.action,
+            number: value.number,
+            title: value.pull_request.title,
+        })
+    }
+}
+
+impl TryFrom<GiteaRepositoryEvent> for RepositoryEvent {
+    type Error = anyhow::Error;
+    fn try_from(value: GiteaRepositoryEvent) -> Result<Self, Self::Error> {
+        Ok(RepositoryEvent {
+            repo: value.repository.try_into()?,
+            action: value.action,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hmac::{Hmac, Mac};
+    use sha2::Sha256;
+
+    use super::*;
+    use crate::{config::Config, state::State};
+
+    fn state() -> SharedState {
+        let mut config = Config::default();
+        config.webhook.secret = "current".into();
+        config.webhook.previous_secret = Some("previous".into());
+
+        State::in_memory(config).unwrap()
+    }
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn headers(event: &str, signature: Option<&str>) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert("X-Gitea-Event", event.parse().unwrap());
+        if let Some(signature) = signature {
+            headers.insert("X-Gitea-Signature", signature.parse().unwrap());
+        }
+
+        headers
+    }
+
+    async fn status(state: &SharedState, headers: HeaderMap, body: &[u8]) -> StatusCode {
+        match handle_gitea_webhook(state, &headers, body).await {
+            Ok((status, _)) => status,
+            Err(e) => e.into_response().status(),
+        }
+    }
+
+    const BODY: &[u8] = br#"{"action":"published"}"#;
+
+    #[tokio::test]
+    async fn accepts_the_current_and_previous_secret() {
+        let state = state();
+
+        for secret in ["current", "previous"] {
+            let signature = sign(secret, BODY);
+            assert_eq!(
+                status(&state, headers("release", Some(&signature)), BODY).await,
+                StatusCode::ACCEPTED,
+                "{}",
+                secret
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_unsigned_webhooks_with_401() {
+        let state = state();
+
+        for signature in [
+            None,
+            Some("not hex".to_string()),
+            Some(sign("wrong", BODY)),
+            Some(sign("current", b"{}")),
+        ] {
+            assert_eq!(
+                status(&state, headers("release", signature.as_deref()), BODY).await,
+                StatusCode::UNAUTHORIZED,
+                "{:?}",
+                signature
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn ignores_unknown_events_with_202() {
+        let state = state();
+        let signature = sign("current", BODY);
+
+        for event in ["release", "fork", "wiki", ""] {
+            assert_eq!(
+                status(&state, headers(event, Some(&signature)), BODY).await,
+                StatusCode::ACCEPTED,
+                "{}",
+                event
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_malformed_bodies_with_400() {
+        let state = state();
+
+        for event in ["issue_comment", "push", "pull_request", "repository"] {
+            for body in [&b"not json"[..], b"[]", b"{}"] {
+                let signature = sign("current", body);
+                assert_eq!(
+                    status(&state, headers(event, Some(&signature)), body).await,
+                    StatusCode::BAD_REQUEST,
+                    "{} {}",
+                    event,
+                    String::from_utf8_lossy(body)
+                );
             }
         }
     }
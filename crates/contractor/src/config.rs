@@ -0,0 +1,649 @@
+use std::{
+    collections::HashMap,
+    fmt::Display,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use anyhow::Context;
+use itertools::Itertools;
+use reqwest::Url;
+use serde::Deserialize;
+
+use crate::services::{images::validate_image, secrets::Secret};
+
+const DEFAULT_ONBOARDING_TEMPLATE: &str = r#"{
+  "$schema": "https://docs.renovatebot.com/renovate-schema.json",
+  "extends": ["config:recommended"]
+}
+"#;
+
+const MIN_API_TOKEN_LENGTH: usize = 16;
+
+/// Configuration of contractor, read from an optional toml or yaml file. Every
+/// value can be overridden by its environment variable, which is also how
+/// contractor was configured before the config file existed.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub gitea: GiteaConfig,
+    pub webhook: WebhookConfig,
+    pub api: ApiConfig,
+    pub renovate: RenovateRunnerConfig,
+    pub jobs: JobsConfig,
+    pub database: DatabaseConfig,
+    pub bot: BotConfig,
+    pub onboarding: OnboardingConfig,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct GiteaConfig {
+    /// GITEA_URL
+    pub url: String,
+    /// GITEA_TOKEN
+    pub token: String,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct WebhookConfig {
+    /// The url gitea reaches contractor on, CONTRACTOR_URL
+    pub url: String,
+    /// CONTRACTOR_WEBHOOK_SECRET
+    pub secret: String,
+    /// Still accepted while rotating the secret, CONTRACTOR_WEBHOOK_SECRET_PREVIOUS
+    pub previous_secret: Option<String>,
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ApiConfig {
+    /// Bearer token required on `/api`, which is disabled when unset,
+    /// CONTRACTOR_API_TOKEN
+    pub token: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RenovateRunnerConfig {
+    /// CONTRACTOR_DOCKER_HOST
+    pub docker_host: String,
+    /// CONTRACTOR_GITHUB_COM_TOKEN
+    pub github_com_token: String,
+    /// CONTRACTOR_RENOVATE_TOKEN
+    pub token: String,
+    /// CONTRACTOR_RENOVATE_SECRETS
+    pub secrets: String,
+    /// CONTRACTOR_RENOVATE_CONFIG_URL
+    pub config_url: String,
+    /// Image renovate runs in, may be pinned by digest, CONTRACTOR_RENOVATE_IMAGE
+    pub image: String,
+    /// Images used instead of `image` for the repositories of an owner, keyed
+    /// by owner. Only configurable in the config file.
+    pub images: HashMap<String, String>,
+    /// Credentials used instead of the ones above for repositories matching
+    /// the key, a glob on `owner/name`. Only configurable in the config file.
+    pub credentials: HashMap<String, CredentialsConfig>,
+}
+
+/// Every value can be given inline or as a file, which is read on every run
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CredentialsConfig {
+    pub github_com_token: Option<Secret>,
+    pub github_com_token_file: Option<PathBuf>,
+    pub token: Option<Secret>,
+    pub token_file: Option<PathBuf>,
+    /// Merged into the secrets of less specific matches key by key
+    pub secrets: Option<Secret>,
+    pub secrets_file: Option<PathBuf>,
+}
+
+impl Default for RenovateRunnerConfig {
+    fn default() -> Self {
+        Self {
+            docker_host: String::new(),
+            github_com_token: String::new(),
+            token: String::new(),
+            secrets: String::new(),
+            config_url: String::new(),
+            image: "renovate/renovate:37".into(),
+            images: HashMap::new(),
+            credentials: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JobsConfig {
+    /// CONTRACTOR_MAX_CONCURRENT_JOBS
+    pub max_concurrent: usize,
+    /// Days renovate logs are kept for, CONTRACTOR_LOG_RETENTION_DAYS
+    pub log_retention_days: u32,
+    /// Seconds to wait for further pushes before running renovate after a
+    /// config change, CONTRACTOR_PUSH_DEBOUNCE_SECONDS
+    pub push_debounce_seconds: u64,
+}
+
+impl Default for JobsConfig {
+    fn default() -> Self {
+        Self {
+            max_concurrent: 2,
+            log_retention_days: 14,
+            push_debounce_seconds: 60,
+        }
+    }
+}
+
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// Job history is only kept in memory when unset, DATABASE_URL
+    pub url: Option<String>,
+}
+
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct BotConfig {
+    /// CONTRACTOR_COMMAND_NAME
+    pub command_name: String,
+}
+
+impl Default for BotConfig {
+    fn default() -> Self {
+        Self {
+            command_name: "contractor".into(),
+        }
+    }
+}
+
+/// Pull requests opened by `reconcile --onboard` for repositories without a
+/// renovate config
+#[derive(Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct OnboardingConfig {
+    /// The branch the config is committed to, CONTRACTOR_ONBOARDING_BRANCH
+    pub branch: String,
+    /// CONTRACTOR_ONBOARDING_TITLE
+    pub title: String,
+    /// The `renovate.json` proposed to repositories, CONTRACTOR_ONBOARDING_TEMPLATE
+    pub template: String,
+    /// Templates used instead of `template` for the repositories of an owner,
+    /// keyed by owner. Only configurable in the config file.
+    pub templates: HashMap<String, String>,
+}
+
+impl Default for OnboardingConfig {
+    fn default() -> Self {
+        Self {
+            branch: "contractor/onboarding".into(),
+            title: "Configure Renovate".into(),
+            template: DEFAULT_ONBOARDING_TEMPLATE.into(),
+            templates: HashMap::new(),
+        }
+    }
+}
+
+impl OnboardingConfig {
+    /// The template proposed to the repositories of `owner`
+    pub fn template_for(&self, owner: &str) -> &str {
+        self.templates
+            .get(owner)
+            .map(String::as_str)
+            .unwrap_or(&self.template)
+    }
+}
+
+/// Every problem found in the config, so they can be fixed in one go
+#[derive(Debug)]
+pub struct ConfigError {
+    pub problems: Vec<String>,
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "config is invalid:")?;
+        for problem in &self.problems {
+            // Parse errors span several lines, which are indented under the problem
+            write!(f, "\n  - {}", problem.trim_end().replace('\n', "\n    "))?;
+        }
+
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// What the config is loaded for, settings are only required by the commands
+/// using them
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum ConfigUsage {
+    /// Talks to gitea and runs renovate
+    Serve,
+    /// Only talks to gitea
+    Reconcile,
+}
+
+impl Config {
+    /// Loads the config from `path` if given, applies environment overrides and
+    /// validates the result. Settings which are set are always validated, but
+    /// only those `usage` needs are required.
+    pub fn load(path: Option<&Path>, usage: Option<ConfigUsage>) -> anyhow::Result<Self> {
+        let mut problems = Vec::new();
+
+        let mut config = match path.map(Self::from_file).transpose() {
+            Ok(config) => config.unwrap_or_default(),
+            Err(e) => {
+                problems.push(format!("{:#}", e));
+                Self::default()
+            }
+        };
+
+        config.apply_env(&mut problems);
+        config.validate(usage, &mut problems);
+
+        if !problems.is_empty() {
+            return Err(ConfigError { problems }.into());
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let content = std::fs::read_to_string(path)
+            .with_context(|| format!("failed to read config file: {}", path.display()))?;
+
+        match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&content)
+                .with_context(|| format!("failed to parse config file: {}", path.display())),
+            Some("yaml") | Some("yml") => serde_yaml::from_str(&content)
+                .with_context(|| format!("failed to parse config file: {}", path.display())),
+            _ => anyhow::bail!(
+                "config file should end in .toml, .yaml or .yml: {}",
+                path.display()
+            ),
+        }
+    }
+
+    fn apply_env(&mut self, problems: &mut Vec<String>) {
+        env_string("GITEA_URL", &mut self.gitea.url);
+        env_string("GITEA_TOKEN", &mut self.gitea.token);
+
+        env_string("CONTRACTOR_URL", &mut self.webhook.url);
+        env_string("CONTRACTOR_WEBHOOK_SECRET", &mut self.webhook.secret);
+        env_option(
+            "CONTRACTOR_WEBHOOK_SECRET_PREVIOUS",
+            &mut self.webhook.previous_secret,
+        );
+
+        env_option("CONTRACTOR_API_TOKEN", &mut self.api.token);
+
+        env_string("CONTRACTOR_DOCKER_HOST", &mut self.renovate.docker_host);
+        env_string(
+            "CONTRACTOR_GITHUB_COM_TOKEN",
+            &mut self.renovate.github_com_token,
+        );
+        env_string("CONTRACTOR_RENOVATE_TOKEN", &mut self.renovate.token);
+        env_string("CONTRACTOR_RENOVATE_SECRETS", &mut self.renovate.secrets);
+        env_string(
+            "CONTRACTOR_RENOVATE_CONFIG_URL",
+            &mut self.renovate.config_url,
+        );
+        env_string("CONTRACTOR_RENOVATE_IMAGE", &mut self.renovate.image);
+
+        env_parsed(
+            "CONTRACTOR_MAX_CONCURRENT_JOBS",
+            &mut self.jobs.max_concurrent,
+            problems,
+        );
+        env_parsed(
+            "CONTRACTOR_LOG_RETENTION_DAYS",
+            &mut self.jobs.log_retention_days,
+            problems,
+        );
+        env_parsed(
+            "CONTRACTOR_PUSH_DEBOUNCE_SECONDS",
+            &mut self.jobs.push_debounce_seconds,
+            problems,
+        );
+
+        env_option("DATABASE_URL", &mut self.database.url);
+
+        env_string("CONTRACTOR_COMMAND_NAME", &mut self.bot.command_name);
+
+        env_string("CONTRACTOR_ONBOARDING_BRANCH", &mut self.onboarding.branch);
+        env_string("CONTRACTOR_ONBOARDING_TITLE", &mut self.onboarding.title);
+        env_string(
+            "CONTRACTOR_ONBOARDING_TEMPLATE",
+            &mut self.onboarding.template,
+        );
+    }
+
+    fn validate(&self, usage: Option<ConfigUsage>, problems: &mut Vec<String>) {
+        let requires_gitea = usage.is_some();
+        let requires_runner = usage == Some(ConfigUsage::Serve);
+
+        require_url(
+            problems,
+            requires_gitea,
+            "gitea.url",
+            "GITEA_URL",
+            &self.gitea.url,
+        );
+        require(
+            problems,
+            requires_gitea,
+            "gitea.token",
+            "GITEA_TOKEN",
+            &self.gitea.token,
+        );
+
+        require_url(
+            problems,
+            requires_gitea,
+            "webhook.url",
+            "CONTRACTOR_URL",
+            &self.webhook.url,
+        );
+        require(
+            problems,
+            requires_gitea,
+            "webhook.secret",
+            "CONTRACTOR_WEBHOOK_SECRET",
+            &self.webhook.secret,
+        );
+
+        if let Some(token) = &self.api.token {
+            if token.trim().len() < MIN_API_TOKEN_LENGTH {
+                problems.push(format!(
+                    "api.token (CONTRACTOR_API_TOKEN) should be at least {} characters",
+                    MIN_API_TOKEN_LENGTH
+                ));
+            }
+        }
+
+        require(
+            problems,
+            requires_runner,
+            "renovate.docker_host",
+            "CONTRACTOR_DOCKER_HOST",
+            &self.renovate.docker_host,
+        );
+        require(
+            problems,
+            requires_runner,
+            "renovate.github_com_token",
+            "CONTRACTOR_GITHUB_COM_TOKEN",
+            &self.renovate.github_com_token,
+        );
+        require(
+            problems,
+            requires_runner,
+            "renovate.token",
+            "CONTRACTOR_RENOVATE_TOKEN",
+            &self.renovate.token,
+        );
+        require(
+            problems,
+            requires_runner,
+            "renovate.secrets",
+            "CONTRACTOR_RENOVATE_SECRETS",
+            &self.renovate.secrets,
+        );
+        require_url(
+            problems,
+            requires_runner,
+            "renovate.config_url",
+            "CONTRACTOR_RENOVATE_CONFIG_URL",
+            &self.renovate.config_url,
+        );
+        if let Err(e) = validate_image(&self.renovate.image) {
+            problems.push(format!(
+                "renovate.image (CONTRACTOR_RENOVATE_IMAGE) is invalid: {}",
+                e
+            ));
+        }
+        for (owner, image) in self.renovate.images.iter().sorted_by_key(|(o, _)| *o) {
+            if let Err(e) = validate_image(image) {
+                problems.push(format!("renovate.images.{} is invalid: {}", owner, e));
+            }
+        }
+        for (pattern, credentials) in self.renovate.credentials.iter().sorted_by_key(|(p, _)| *p) {
+            validate_credentials(problems, pattern, credentials);
+        }
+        if self
+            .renovate
+            .credentials
+            .values()
+            .any(|c| c.secrets.is_some() || c.secrets_file.is_some())
+            && (requires_runner || !self.renovate.secrets.is_empty())
+            && !is_json_object(&self.renovate.secrets)
+        {
+            problems.push(
+                "renovate.secrets (CONTRACTOR_RENOVATE_SECRETS) should be a json object to be merged with renovate.credentials"
+                    .into(),
+            );
+        }
+
+        if self.jobs.max_concurrent == 0 {
+            problems.push(
+                "jobs.max_concurrent (CONTRACTOR_MAX_CONCURRENT_JOBS) should be at least 1".into(),
+            );
+        }
+
+        if self.jobs.log_retention_days == 0 {
+            problems.push(
+                "jobs.log_retention_days (CONTRACTOR_LOG_RETENTION_DAYS) should be at least 1"
+                    .into(),
+            );
+        }
+
+        if let Some(url) = &self.database.url {
+            if !url.starts_with("postgres://") && !url.starts_with("postgresql://") {
+                problems.push("database.url (DATABASE_URL) should be a postgres:// url".into());
+            }
+        }
+
+        if self.bot.command_name.trim().is_empty()
+            || self.bot.command_name.contains(char::is_whitespace)
+        {
+            problems
+                .push("bot.command_name (CONTRACTOR_COMMAND_NAME) should be a single word".into());
+        }
+
+        require(
+            problems,
+            true,
+            "onboarding.branch",
+            "CONTRACTOR_ONBOARDING_BRANCH",
+            &self.onboarding.branch,
+        );
+        require(
+            problems,
+            true,
+            "onboarding.title",
+            "CONTRACTOR_ONBOARDING_TITLE",
+            &self.onboarding.title,
+        );
+        if let Err(e) = serde_json::from_str::<serde_json::Value>(&self.onboarding.template) {
+            problems.push(format!(
+                "onboarding.template (CONTRACTOR_ONBOARDING_TEMPLATE) is not valid json: {}",
+                e
+            ));
+        }
+        for (owner, template) in self.onboarding.templates.iter().sorted_by_key(|(o, _)| *o) {
+            if let Err(e) = serde_json::from_str::<serde_json::Value>(template) {
+                problems.push(format!(
+                    "onboarding.templates.{} is not valid json: {}",
+                    owner, e
+                ));
+            }
+        }
+    }
+}
+
+fn env_string(name: &str, value: &mut String) {
+    if let Ok(env) = std::env::var(name) {
+        *value = env;
+    }
+}
+
+fn env_option(name: &str, value: &mut Option<String>) {
+    if let Ok(env) = std::env::var(name) {
+        *value = Some(env).filter(|v| !v.is_empty());
+    }
+}
+
+fn env_parsed<T: FromStr>(name: &str, value: &mut T, problems: &mut Vec<String>)
+where
+    T::Err: Display,
+{
+    if let Ok(env) = std::env::var(name) {
+        match env.parse() {
+            Ok(parsed) => *value = parsed,
+            Err(e) => problems.push(format!("{} is invalid: {}", name, e)),
+        }
+    }
+}
+
+fn validate_credentials(
+    problems: &mut Vec<String>,
+    pattern: &str,
+    credentials: &CredentialsConfig,
+) {
+    let key = format!("renovate.credentials.\"{}\"", pattern);
+
+    if pattern.trim().is_empty() {
+        problems.push(format!("{} should have a non empty pattern", key));
+    }
+
+    let fields = [
+        (
+            "github_com_token",
+            credentials.github_com_token.is_some(),
+            credentials.github_com_token_file.is_some(),
+        ),
+        (
+            "token",
+            credentials.token.is_some(),
+            credentials.token_file.is_some(),
+        ),
+        (
+            "secrets",
+            credentials.secrets.is_some(),
+            credentials.secrets_file.is_some(),
+        ),
+    ];
+
+    if fields.iter().all(|(_, inline, file)| !inline && !file) {
+        problems.push(format!("{} doesn't set any credentials", key));
+    }
+    for (field, _, _) in fields.iter().filter(|(_, inline, file)| *inline && *file) {
+        problems.push(format!("{} sets both {} and {}_file", key, field, field));
+    }
+
+    // Only the kind of problem is reported, as the value is a secret
+    if let Some(secrets) = &credentials.secrets {
+        if !is_json_object(secrets.expose()) {
+            problems.push(format!("{}.secrets should be a json object", key));
+        }
+    }
+}
+
+fn is_json_object(value: &str) -> bool {
+    matches!(
+        serde_json::from_str::<serde_json::Value>(value),
+        Ok(serde_json::Value::Object(_))
+    )
+}
+
+fn require(problems: &mut Vec<String>, required: bool, key: &str, env: &str, value: &str) {
+    if required && value.trim().is_empty() {
+        problems.push(format!("{} ({}) is required", key, env));
+    }
+}
+
+/// Urls are validated whenever they are set
+fn require_url(problems: &mut Vec<String>, required: bool, key: &str, env: &str, value: &str) {
+    if value.trim().is_empty() {
+        require(problems, required, key, env, value);
+    } else if let Err(e) = Url::parse(value) {
+        problems.push(format!("{} ({}) is not a valid url: {}", key, env, e));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(config: &Config, usage: Option<ConfigUsage>) -> Vec<String> {
+        let mut problems = Vec::new();
+        config.validate(usage, &mut problems);
+        problems
+    }
+
+    fn gitea_config() -> Config {
+        let mut config = Config::default();
+        config.gitea.url = "https://git.example.com".into();
+        config.gitea.token = "token".into();
+        config.webhook.url = "https://contractor.example.com".into();
+        config.webhook.secret = "secret".into();
+        config
+    }
+
+    #[test]
+    fn reconcile_only_requires_gitea_settings() {
+        assert!(problems(&gitea_config(), Some(ConfigUsage::Reconcile)).is_empty());
+
+        let problems = problems(&Config::default(), Some(ConfigUsage::Reconcile));
+        assert_eq!(problems.len(), 4);
+        assert!(problems.iter().all(|p| !p.starts_with("renovate.")));
+    }
+
+    #[test]
+    fn serve_requires_renovate_settings() {
+        let problems = problems(&gitea_config(), Some(ConfigUsage::Serve));
+
+        assert_eq!(
+            problems,
+            vec![
+                "renovate.docker_host (CONTRACTOR_DOCKER_HOST) is required",
+                "renovate.github_com_token (CONTRACTOR_GITHUB_COM_TOKEN) is required",
+                "renovate.token (CONTRACTOR_RENOVATE_TOKEN) is required",
+                "renovate.secrets (CONTRACTOR_RENOVATE_SECRETS) is required",
+                "renovate.config_url (CONTRACTOR_RENOVATE_CONFIG_URL) is required",
+            ]
+        );
+    }
+
+    #[test]
+    fn validates_settings_which_are_set_without_usage() {
+        assert!(problems(&Config::default(), None).is_empty());
+
+        let mut config = Config::default();
+        config.gitea.url = "git.example.com".into();
+        config.renovate.image = "renovate/renovate@sha256:short".into();
+
+        let problems = problems(&config, None);
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].starts_with("gitea.url (GITEA_URL) is not a valid url"));
+        assert!(problems[1].starts_with("renovate.image (CONTRACTOR_RENOVATE_IMAGE) is invalid"));
+    }
+
+    #[test]
+    fn indents_multiline_problems() {
+        let error = ConfigError {
+            problems: vec![
+                "failed to parse\n  |\n3 | token = 5\n".into(),
+                "other".into(),
+            ],
+        };
+
+        assert_eq!(
+            error.to_string(),
+            "config is invalid:\n  - failed to parse\n      |\n    3 | token = 5\n  - other"
+        );
+    }
+}
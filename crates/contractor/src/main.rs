@@ -1,12 +1,16 @@
-use std::{net::SocketAddr, sync::Arc};
+use std::{net::SocketAddr, path::PathBuf, sync::Arc};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use futures::{stream::FuturesUnordered, StreamExt};
 use tokio::task;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None, subcommand_required = true)]
 struct Command {
+    /// Path to a toml or yaml config file, environment variables override its values
+    #[arg(long, global = true, env = "CONTRACTOR_CONFIG")]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -16,37 +20,77 @@ enum Commands {
     Serve {
         #[arg(env = "SERVICE_HOST", long, default_value = "127.0.0.1:3000")]
         host: SocketAddr,
+
+        #[command(flatten)]
+        schedule: ScheduleOptions,
     },
 
     Reconcile {
-        #[arg(long)]
-        user: Option<String>,
-        #[arg(long)]
-        org: Option<Vec<String>>,
+        #[command(flatten)]
+        options: ReconcileOptions,
+
+        /// Print the planned changes without applying them
+        #[arg(long = "dry-run")]
+        dry_run: bool,
 
-        #[arg(long, env = "CONTRACTOR_FILTER")]
-        filter: Option<String>,
+        /// Also run renovate on every repository with renovate enabled, and
+        /// wait for the runs to finish
+        #[arg(long, conflicts_with = "dry_run")]
+        run: bool,
+
+        #[arg(long, value_enum, default_value_t = PlanOutput::Text)]
+        output: PlanOutput,
+    },
 
-        #[arg(long = "force-refresh", env = "CONTRACTOR_FORCE_REFRESH")]
-        force_refresh: bool,
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum ConfigCommands {
+    /// Load the config and report every problem with it
+    Validate {
+        /// Also require every setting this command needs
+        #[arg(long = "for", value_enum)]
+        usage: Option<ConfigUsage>,
     },
 }
 
+#[derive(Clone, ValueEnum)]
+enum PlanOutput {
+    Text,
+    Json,
+}
+
 mod api;
+mod config;
 mod schedule;
 
 #[tokio::main]
 async fn main() -> anyhow::Result<()> {
     dotenv::dotenv().ok();
-    tracing_subscriber::fmt::init();
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .init();
 
     let cli = Command::parse();
 
     match cli.command {
-        Some(Commands::Serve { host }) => {
+        Some(Commands::Serve { host, schedule }) => {
             tracing::info!("Starting service");
 
-            let state = SharedState::from(Arc::new(State::new().await?));
+            let config = Config::load(cli.config.as_deref(), Some(ConfigUsage::Serve))?;
+            let state = SharedState::from(Arc::new(
+                State::new(config, schedule.reconcile.clone()).await?,
+            ));
+
+            if state.db.is_none() {
+                tracing::warn!(
+                    "DATABASE_URL is not set, leader election only spans this process and every replica will run scheduled work. Run a single replica or configure a database"
+                );
+            }
 
             let mut tasks = FuturesUnordered::new();
 
@@ -58,8 +102,40 @@ async fn main() -> anyhow::Result<()> {
                 })
             });
 
+            tasks.push({
+                let state = state.clone();
+                task::spawn(async move {
+                    state.queue.run().await?;
+                    Ok::<(), anyhow::Error>(())
+                })
+            });
+
+            tasks.push({
+                let state = state.clone();
+                task::spawn(async move {
+                    state.push_debouncer.run().await?;
+                    Ok::<(), anyhow::Error>(())
+                })
+            });
+
+            tasks.push({
+                let state = state.clone();
+                task::spawn(async move {
+                    state.leader.run().await?;
+                    Ok::<(), anyhow::Error>(())
+                })
+            });
+
+            tasks.push({
+                let state = state.clone();
+                task::spawn(async move {
+                    serve_log_retention(&state).await?;
+                    Ok::<(), anyhow::Error>(())
+                })
+            });
+
             tasks.push(task::spawn(async move {
-                serve_cron_jobs(&state).await?;
+                serve_cron_jobs(&state, schedule).await?;
                 Ok::<(), anyhow::Error>(())
             }));
 
@@ -68,21 +144,65 @@ async fn main() -> anyhow::Result<()> {
             }
         }
         Some(Commands::Reconcile {
-            user,
-            org,
-            filter,
-            force_refresh,
+            options,
+            dry_run,
+            run,
+            output,
         }) => {
             tracing::info!("running reconcile");
 
-            let state = SharedState::from(Arc::new(State::new().await?));
+            let usage = if run {
+                ConfigUsage::Serve
+            } else {
+                ConfigUsage::Reconcile
+            };
+            let config = Config::load(cli.config.as_deref(), Some(usage))?;
 
-            state
-                .reconciler()
-                .reconcile(user, org, filter, force_refresh)
-                .await?;
+            // Reconciling only talks to gitea and reads onboarding statuses, a
+            // dry run doesn't touch the database schema either
+            let onboarding = match &config.database.url {
+                Some(database_url) => {
+                    OnboardingStore::postgres(connect_database(database_url, !dry_run).await?)
+                }
+                None => OnboardingStore::in_memory(),
+            };
+            let metrics = Metrics::new()?;
+            let reconciler = Reconciler::new(
+                GiteaClient::new(&config, metrics.clone()),
+                metrics,
+                onboarding,
+                config.onboarding.clone(),
+            );
+
+            let plan = reconciler.plan(&options).await?;
+
+            if dry_run {
+                match output {
+                    PlanOutput::Text => print!("{}", plan),
+                    PlanOutput::Json => println!("{}", serde_json::to_string_pretty(&plan)?),
+                }
+            } else {
+                reconciler.apply(&plan).await?;
+            }
 
             tracing::info!("done running reconcile");
+
+            if run {
+                let state = SharedState::from(Arc::new(State::new(config, options).await?));
+
+                run_renovate(
+                    &state,
+                    plan.renovate_enabled.into_iter().map(|r| r.repository),
+                )
+                .await?;
+            }
+        }
+        Some(Commands::Config {
+            command: ConfigCommands::Validate { usage },
+        }) => {
+            Config::load(cli.config.as_deref(), usage)?;
+
+            println!("config is valid");
         }
         None => {}
     }
@@ -90,8 +210,73 @@ async fn main() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Runs renovate on each of `repos` through the job queue, recorded as cli
+/// jobs. Fails when any of the runs failed.
+async fn run_renovate(
+    state: &SharedState,
+    repos: impl IntoIterator<Item = Repository>,
+) -> anyhow::Result<()> {
+    let queue = {
+        let state = state.clone();
+        task::spawn(async move { state.queue.run().await })
+    };
+
+    let mut runs = repos
+        .into_iter()
+        .map(|repo| {
+            tracing::info!("submitting renovate for: {}", repo);
+
+            let (_, result) = state.queue.submit(JobRequest {
+                repo: repo.clone(),
+                trigger: JobTrigger::Cli,
+                requester: None,
+                renovate_version: None,
+            });
+
+            async move { (repo, result.await) }
+        })
+        .collect::<FuturesUnordered<_>>();
+
+    let total = runs.len();
+    let mut failed = 0;
+    while let Some((repo, result)) = runs.next().await {
+        match result {
+            Ok(Ok(run)) => tracing::info!("renovate finished for: {}, {}", repo, run.summary),
+            Ok(Err(e)) => {
+                tracing::error!("renovate failed for: {}, error: {:#}", repo, e);
+                failed += 1;
+            }
+            Err(_) => {
+                tracing::error!("renovate run for: {} was dropped", repo);
+                failed += 1;
+            }
+        }
+    }
+
+    queue.abort();
+
+    if failed > 0 {
+        anyhow::bail!("{} of {} renovate runs failed", failed, total);
+    }
+
+    Ok(())
+}
+
 mod state;
 pub use crate::state::{SharedState, State};
-use crate::{api::serve_axum, schedule::serve_cron_jobs, services::reconciler::ReconcilerState};
+use crate::{
+    api::serve_axum,
+    config::{Config, ConfigUsage},
+    schedule::{serve_cron_jobs, serve_log_retention, ScheduleOptions},
+    services::{
+        gitea::{GiteaClient, Repository},
+        jobs::JobTrigger,
+        metrics::Metrics,
+        onboarding::OnboardingStore,
+        queue::JobRequest,
+        reconciler::{ReconcileOptions, Reconciler},
+    },
+    state::connect_database,
+};
 
 mod services;
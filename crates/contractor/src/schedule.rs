@@ -1,16 +1,240 @@
-use crate::SharedState;
+use std::{str::FromStr, time::Duration};
 
-pub async fn serve_cron_jobs(state: &SharedState) -> Result<(), anyhow::Error> {
-    let _state = state.clone();
-    tokio::spawn(async move {
-        loop {
-            tracing::info!("running cronjobs");
+use anyhow::Context;
+use futures::{stream::FuturesUnordered, StreamExt};
+use rand::Rng;
 
-            tokio::time::sleep(std::time::Duration::from_secs(10_000)).await;
+use crate::{
+    services::{
+        gitea::Repository,
+        jobs::JobTrigger,
+        queue::JobRequest,
+        reconciler::{ReconcileOptions, ReconcilerState},
+    },
+    SharedState,
+};
+
+const LOG_RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ScheduleOptions {
+    /// Cron expression (with seconds) for when to reconcile and run renovate
+    #[arg(long, env = "CONTRACTOR_SCHEDULE", default_value = "0 0 * * * *")]
+    pub schedule: String,
+
+    /// Each scheduled renovate run is delayed by a random amount of seconds up to this value
+    #[arg(
+        long = "schedule-jitter",
+        env = "CONTRACTOR_SCHEDULE_JITTER",
+        default_value_t = 300
+    )]
+    pub jitter: u64,
+
+    #[command(flatten)]
+    pub reconcile: ReconcileOptions,
+}
+
+pub async fn serve_cron_jobs(
+    state: &SharedState,
+    options: ScheduleOptions,
+) -> Result<(), anyhow::Error> {
+    let schedule = cron::Schedule::from_str(&options.schedule)
+        .context("schedule is not a valid cron expression")?;
+
+    let mut last = None;
+
+    loop {
+        let (next, wait) = next_run(&schedule, chrono::Utc::now(), last)?;
+        last = Some(next);
+
+        tracing::info!("next scheduled run at: {}", next);
+
+        tokio::time::sleep(wait).await;
+
+        if !state.leader.is_leader() {
+            tracing::debug!("not the leader, skipping scheduled run");
+            continue;
         }
-        Ok::<(), anyhow::Error>(())
-    })
-    .await??;
+
+        tracing::info!("running cronjobs");
+        if let Err(e) = run_scheduled(state, &options).await {
+            tracing::error!("scheduled run failed: {}", e);
+        }
+    }
+}
+
+/// The next run of `schedule` after `now` and how long to wait for it. Runs
+/// are never before `last`, so waking up early can't run the same slot twice.
+fn next_run(
+    schedule: &cron::Schedule,
+    now: chrono::DateTime<chrono::Utc>,
+    last: Option<chrono::DateTime<chrono::Utc>>,
+) -> anyhow::Result<(chrono::DateTime<chrono::Utc>, Duration)> {
+    let after = last.map_or(now, |last| last.max(now));
+
+    let next = schedule
+        .after(&after)
+        .next()
+        .ok_or(anyhow::anyhow!("schedule doesn't have any upcoming runs"))?;
+
+    Ok((next, (next - now).to_std().unwrap_or_default()))
+}
+
+/// Deletes renovate logs older than the configured retention, runs until the
+/// process exits
+pub async fn serve_log_retention(state: &SharedState) -> anyhow::Result<()> {
+    let retention = time::Duration::days(state.config.jobs.log_retention_days.into());
+
+    loop {
+        if state.leader.is_leader() {
+            let before = time::OffsetDateTime::now_utc() - retention;
+
+            match state.jobs.delete_logs_before(before).await {
+                Ok(deleted) => tracing::debug!("deleted {} expired log entries", deleted),
+                Err(e) => tracing::warn!("failed to delete expired logs: {}", e),
+            }
+        }
+
+        tokio::time::sleep(LOG_RETENTION_INTERVAL).await;
+    }
+}
+
+async fn run_scheduled(state: &SharedState, options: &ScheduleOptions) -> anyhow::Result<()> {
+    if options.reconcile.user.is_none() && !options.reconcile.own && options.reconcile.org.is_none()
+    {
+        tracing::warn!("no user, self or orgs are configured for scheduled runs, skipping");
+        return Ok(());
+    }
+
+    let reconciler = state.reconciler();
+    let plan = reconciler.plan(&options.reconcile).await?;
+
+    // A failing webhook or onboarding change shouldn't hold back renovate for
+    // every other repository
+    if let Err(e) = reconciler.apply(&plan).await {
+        tracing::warn!("reconcile before scheduled runs was incomplete: {:#}", e);
+    }
+
+    submit_scheduled(
+        state,
+        plan.renovate_enabled.into_iter().map(|r| r.repository),
+        options.jitter,
+    )
+    .await;
 
     Ok(())
 }
+
+/// Submits renovate for each of `repos` after a random delay of up to `jitter`
+/// seconds, unless this replica lost leadership in the meantime
+async fn submit_scheduled(
+    state: &SharedState,
+    repos: impl IntoIterator<Item = Repository>,
+    jitter: u64,
+) {
+    let mut runs = FuturesUnordered::new();
+
+    for repo in repos {
+        let delay = Duration::from_secs(rand::thread_rng().gen_range(0..=jitter));
+
+        runs.push(async move {
+            tokio::time::sleep(delay).await;
+
+            // Leadership may have moved to another replica during the jitter
+            if !state.leader.is_leader() {
+                tracing::warn!(
+                    "no longer the leader, skipping scheduled renovate for: {}",
+                    repo
+                );
+                return;
+            }
+
+            tracing::info!("submitting scheduled renovate for: {}", repo);
+
+            state.queue.submit(JobRequest {
+                repo,
+                trigger: JobTrigger::Schedule,
+                requester: None,
+                renovate_version: None,
+            });
+        });
+    }
+
+    while runs.next().await.is_some() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::{config::Config, services::gitea::RepositoryMetadata, state::State};
+
+    fn at(hour: u32, minute: u32, second: u32) -> chrono::DateTime<chrono::Utc> {
+        chrono::Utc
+            .with_ymd_and_hms(2026, 10, 18, hour, minute, second)
+            .unwrap()
+    }
+
+    fn repos(count: usize) -> Vec<Repository> {
+        (0..count)
+            .map(|i| Repository {
+                owner: "someone".into(),
+                name: format!("repo-{}", i),
+                metadata: RepositoryMetadata::default(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn waits_for_the_next_run() {
+        let schedule = cron::Schedule::from_str("0 0 * * * *").unwrap();
+
+        let (next, wait) = next_run(&schedule, at(10, 59, 30), None).unwrap();
+        assert_eq!(next, at(11, 0, 0));
+        assert_eq!(wait, Duration::from_secs(30));
+
+        let (next, wait) = next_run(&schedule, at(11, 0, 0), None).unwrap();
+        assert_eq!(next, at(12, 0, 0));
+        assert_eq!(wait, Duration::from_secs(60 * 60));
+    }
+
+    #[test]
+    fn never_runs_a_slot_twice() {
+        let schedule = cron::Schedule::from_str("0 0 * * * *").unwrap();
+
+        // Woke up just before the run at 11:00
+        let (next, wait) = next_run(&schedule, at(10, 59, 59), Some(at(11, 0, 0))).unwrap();
+        assert_eq!(next, at(12, 0, 0));
+        assert_eq!(wait, Duration::from_secs(60 * 60 + 1));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn submits_every_repository_within_the_jitter() {
+        let state = State::in_memory(Config::default()).unwrap();
+        state.leader.set_leader(true);
+
+        let started = tokio::time::Instant::now();
+        submit_scheduled(&state, repos(5), 300).await;
+
+        assert!(started.elapsed() <= Duration::from_secs(300));
+        assert_eq!(state.queue.depth().pending, 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn skips_runs_once_leadership_is_lost_during_the_jitter() {
+        let state = State::in_memory(Config::default()).unwrap();
+        state.leader.set_leader(true);
+
+        let submitting = tokio::spawn({
+            let state = state.clone();
+            async move { submit_scheduled(&state, repos(5), 300).await }
+        });
+
+        // The spawned task only starts polling once this one yields
+        state.leader.set_leader(false);
+        submitting.await.unwrap();
+
+        assert_eq!(state.queue.depth().pending, 0);
+    }
+}
@@ -1,5 +1,15 @@
 pub mod bot;
+pub mod debouncer;
 pub mod engines;
+pub mod events;
 pub mod gitea;
+pub mod images;
+pub mod jobs;
+pub mod leader;
+pub mod metrics;
+pub mod onboarding;
+pub mod queue;
 pub mod reconciler;
 pub mod renovate;
+pub mod secrets;
+pub mod signature;
@@ -1,13 +1,30 @@
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 
-use crate::{services::renovate::RenovateConfig, SharedState};
+use crate::SharedState;
 
-use super::{engines::dagger::Dagger, gitea::Repository};
+use super::{
+    engines::dagger::EngineUnavailable,
+    gitea::{GiteaClient, GiteaClientState, Repository},
+    images::validate_tag,
+    jobs::JobTrigger,
+    metrics::Metrics,
+    queue::{JobQueue, JobRequest, Submission},
+    reconciler::{ReconcileOptions, Reconciler, ReconcilerState},
+    renovate::RenovateFailed,
+};
 
+const LOG_EXCERPT_LINES: usize = 30;
+const MAX_COMMAND_LENGTH: usize = 256;
+
+#[derive(Clone)]
 pub struct Bot {
     command_name: String,
 
-    dagger: Dagger,
+    gitea_client: GiteaClient,
+    queue: JobQueue,
+    metrics: Metrics,
+    reconciler: Reconciler,
+    reconcile_options: ReconcileOptions,
 }
 
 #[derive(Parser)]
@@ -19,58 +36,274 @@ struct BotCommand {
 
 #[derive(Subcommand)]
 enum BotCommands {
+    /// Run renovate against this repository
     Refresh {
         #[arg(long)]
         all: bool,
+
+        /// Run this version of renovate instead of the configured one, e.g. 38
+        #[arg(long = "renovate-version", value_parser = parse_renovate_version)]
+        renovate_version: Option<String>,
     },
 }
 
+/// Errors caused by the contents of a comment addressed to the bot. These are
+/// reported back to the commenter rather than bubbled up to the webhook.
+#[derive(Debug)]
+pub enum BotError {
+    /// The commenter asked for the help or version output
+    Help(String),
+    /// The command couldn't be parsed, contains the rendered parse error
+    InvalidCommand(String),
+    /// The command was longer than [`MAX_COMMAND_LENGTH`]
+    CommandTooLong(usize),
+}
+
+impl std::fmt::Display for BotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BotError::Help(help) => f.write_str(help),
+            BotError::InvalidCommand(e) => f.write_fmt(format_args!("invalid command: {e}")),
+            BotError::CommandTooLong(len) => f.write_fmt(format_args!(
+                "command is {len} characters long, at most {MAX_COMMAND_LENGTH} are allowed"
+            )),
+        }
+    }
+}
+
+impl std::error::Error for BotError {}
+
+impl From<clap::Error> for BotError {
+    fn from(value: clap::Error) -> Self {
+        let rendered = value.render().to_string();
+
+        match value.kind() {
+            clap::error::ErrorKind::DisplayHelp | clap::error::ErrorKind::DisplayVersion => {
+                BotError::Help(rendered)
+            }
+            _ => BotError::InvalidCommand(rendered),
+        }
+    }
+}
+
 impl Bot {
-    pub fn new(dagger: Dagger) -> Self {
+    pub fn new(
+        command_name: String,
+        gitea_client: GiteaClient,
+        queue: JobQueue,
+        metrics: Metrics,
+        reconciler: Reconciler,
+        reconcile_options: ReconcileOptions,
+    ) -> Self {
         Self {
-            command_name: std::env::var("CONTRACTOR_COMMAND_NAME").unwrap_or("contractor".into()),
+            command_name,
 
-            dagger,
+            gitea_client,
+            queue,
+            metrics,
+            reconciler,
+            reconcile_options,
         }
     }
 
     pub async fn handle_request(&self, req: impl Into<BotRequest>) -> anyhow::Result<()> {
         let req: BotRequest = req.into();
 
-        if !req.command.starts_with(&self.command_name) {
-            return Ok(());
-        }
+        let cmd = match parse_command(&self.command_name, &req.command) {
+            Ok(Some(cmd)) => cmd,
+            Ok(None) => return Ok(()),
+            Err(e) => {
+                tracing::info!("failed to parse command for: {}, error: {}", req.repo, e);
+
+                let command = match &e {
+                    BotError::Help(_) => "help",
+                    _ => "invalid",
+                };
+                self.metrics
+                    .bot_commands
+                    .with_label_values(&[command])
+                    .inc();
 
-        let cmd = BotCommand::parse_from(req.command.split_whitespace());
+                let reply = match &e {
+                    BotError::Help(help) => format!("```\n{}\n```", help.trim_end()),
+                    BotError::InvalidCommand(e) => format!(
+                        "I couldn't understand that command.\n\n```\n{}\n```\n\n{}",
+                        e.trim_end(),
+                        self.help()
+                    ),
+                    BotError::CommandTooLong(_) => format!("{e}.\n\n{}", self.help()),
+                };
+
+                self.reply(&req, &reply).await;
+                return Ok(());
+            }
+        };
+
+        let command = match &cmd.command {
+            Some(BotCommands::Refresh { .. }) => "refresh",
+            None => "help",
+        };
+        self.metrics
+            .bot_commands
+            .with_label_values(&[command])
+            .inc();
 
         match cmd.command {
-            Some(BotCommands::Refresh { all }) => {
+            Some(BotCommands::Refresh {
+                all,
+                renovate_version,
+            }) => {
+                if self
+                    .reconciler
+                    .opted_out(&self.reconcile_options, &req.repo)
+                    .await?
+                {
+                    tracing::info!("not refreshing: {}, it opted out by topic", req.repo);
+
+                    let reply = format!(
+                        "`{}` opted out of contractor with the `{}` topic, remove the topic to run renovate.",
+                        req.repo, self.reconcile_options.disabled_topic
+                    );
+                    self.reply(&req, &reply).await;
+                    return Ok(());
+                }
+
                 tracing::info!("triggering refresh for: {}, all: {}", req.repo, all);
 
-                let dagger = self.dagger.clone();
+                let with_version = renovate_version
+                    .as_deref()
+                    .map(|v| format!(" with renovate `{}`", v))
+                    .unwrap_or_default();
+
+                let (submission, result) = self.queue.submit(JobRequest {
+                    repo: req.repo.clone(),
+                    trigger: JobTrigger::BotComment,
+                    requester: req.requester.clone(),
+                    renovate_version,
+                });
+
+                let ack = match submission {
+                    Submission::Queued => format!(
+                        "Queued renovate for `{}`{}, I'll report back here once it is done.",
+                        req.repo, with_version
+                    ),
+                    Submission::Coalesced => format!(
+                        "Renovate is already queued for `{}`{}, I'll report back here once it is done.",
+                        req.repo, with_version
+                    ),
+                };
+
+                // Replies are posted in the background, so the webhook is
+                // answered without waiting on gitea
+                let bot = self.clone();
                 tokio::spawn(async move {
-                    match dagger
-                        .execute_renovate(&RenovateConfig {
-                            repo: format!("{}/{}", &req.repo.owner, &req.repo.name),
-                        })
+                    bot.reply(&req, &ack).await;
+
+                    let reply = match result.await {
+                        Ok(Ok(run)) => format!(
+                            "Renovate finished for `{}`.\n\n{}\n\n{}",
+                            req.repo,
+                            run.summary,
+                            log_excerpt(&run.log.to_text())
+                        ),
+                        Ok(Err(e)) if e.downcast_ref::<EngineUnavailable>().is_some() => format!(
+                            "Renovate couldn't run for `{}`, {}. Please try again later.",
+                            req.repo, e
+                        ),
+                        Ok(Err(e)) => match e.downcast_ref::<RenovateFailed>() {
+                            Some(failed) => format!(
+                                "Renovate failed for `{}`, {}.\n\n{}\n\n{}",
+                                req.repo,
+                                failed,
+                                failed.run.summary,
+                                log_excerpt(&failed.run.log.to_text())
+                            ),
+                            None => {
+                                format!("Renovate failed for `{}`.\n\n```\n{}\n```", req.repo, e)
+                            }
+                        },
+                        Err(_) => format!("Renovate run for `{}` was dropped.", req.repo),
+                    };
+
+                    if let Err(e) = bot
+                        .gitea_client
+                        .create_issue_comment(&req.repo, req.issue, &reply)
                         .await
                     {
-                        Ok(_) => {}
-                        Err(e) => tracing::error!("failed to execute renovate: {}", e),
-                    };
+                        tracing::warn!("failed to report renovate result: {}", e);
+                    }
                 });
             }
             None => {
-                // TODO: Send back the help menu
+                self.reply(&req, &self.help()).await;
             }
         }
 
         Ok(())
     }
+
+    /// Failing to reply is only logged, the command itself was still handled
+    async fn reply(&self, req: &BotRequest, body: &str) {
+        if let Err(e) = self
+            .gitea_client
+            .create_issue_comment(&req.repo, req.issue, body)
+            .await
+        {
+            tracing::warn!(
+                "failed to reply on: {} #{}, error: {}",
+                req.repo,
+                req.issue,
+                e
+            );
+        }
+    }
+
+    fn help(&self) -> String {
+        let mut cmd = BotCommand::command();
+        cmd.set_bin_name(&self.command_name);
+
+        format!("```\n{}\n```", cmd.render_help())
+    }
+}
+
+/// Parses the first line of a comment as a bot command. Returns `None` when
+/// the comment isn't addressed to the bot at all.
+fn parse_command(command_name: &str, comment: &str) -> Result<Option<BotCommand>, BotError> {
+    let line = comment.lines().next().unwrap_or_default().trim();
+
+    if line.split_whitespace().next() != Some(command_name) {
+        return Ok(None);
+    }
+
+    let len = line.chars().count();
+    if len > MAX_COMMAND_LENGTH {
+        return Err(BotError::CommandTooLong(len));
+    }
+
+    let cmd = BotCommand::try_parse_from(line.split_whitespace())?;
+
+    Ok(Some(cmd))
+}
+
+fn parse_renovate_version(version: &str) -> Result<String, String> {
+    validate_tag(version).map_err(|e| e.to_string())?;
+
+    Ok(version.into())
+}
+
+/// Keeps the tail of a renovate log, as that is where the result or failure
+/// is reported. Wrapped in a collapsed block to keep the thread readable.
+fn log_excerpt(log: &str) -> String {
+    let lines = log.lines().collect::<Vec<_>>();
+    let excerpt = lines[lines.len().saturating_sub(LOG_EXCERPT_LINES)..].join("\n");
+
+    format!("<details>\n<summary>Log excerpt</summary>\n\n```\n{excerpt}\n```\n</details>")
 }
 
 pub struct BotRequest {
     pub repo: Repository,
+    pub issue: u64,
+    pub requester: Option<String>,
     pub command: String,
 }
 
@@ -79,6 +312,185 @@ pub trait BotState {
 }
 impl BotState for SharedState {
     fn bot(&self) -> Bot {
-        Bot::new(self.engine.clone())
+        Bot::new(
+            self.config.bot.command_name.clone(),
+            self.gitea_client(),
+            self.queue.clone(),
+            self.metrics.clone(),
+            self.reconciler(),
+            self.reconcile.clone(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        services::gitea::{
+            testing::{self, writes, FakeGitea},
+            RepositoryMetadata,
+        },
+        State,
+    };
+
+    fn parse(comment: &str) -> Result<Option<BotCommand>, BotError> {
+        parse_command("contractor", comment)
+    }
+
+    #[test]
+    fn ignores_comments_not_addressed_to_the_bot() {
+        for comment in [
+            "",
+            "   ",
+            "\n\ncontractor refresh",
+            "lgtm",
+            "contractors refresh",
+        ] {
+            assert!(matches!(parse(comment), Ok(None)), "{:?}", comment);
+        }
+    }
+
+    #[test]
+    fn parses_refresh() {
+        let cmd = parse("contractor refresh --all --renovate-version 38.1.0")
+            .unwrap()
+            .unwrap();
+
+        match cmd.command {
+            Some(BotCommands::Refresh {
+                all,
+                renovate_version,
+            }) => {
+                assert!(all);
+                assert_eq!(renovate_version.as_deref(), Some("38.1.0"));
+            }
+            None => panic!("expected refresh"),
+        }
+    }
+
+    #[test]
+    fn only_reads_the_first_line() {
+        let cmd = parse("contractor refresh\nthanks!\ncontractor --unknown").unwrap();
+
+        assert!(matches!(
+            cmd,
+            Some(BotCommand {
+                command: Some(BotCommands::Refresh { all: false, .. })
+            })
+        ));
+    }
+
+    #[test]
+    fn help_and_version_are_reported_instead_of_exiting() {
+        for comment in [
+            "contractor --help",
+            "contractor refresh --help",
+            "contractor --version",
+        ] {
+            match parse(comment) {
+                Err(BotError::Help(output)) => assert!(!output.is_empty()),
+                _ => panic!("expected help for: {:?}", comment),
+            }
+        }
+    }
+
+    #[test]
+    fn reports_invalid_commands() {
+        for comment in [
+            "contractor",
+            "contractor refresh --unknown",
+            "contractor -x",
+            "contractor unknown",
+            "contractor refresh --renovate-version ../../etc",
+            "contractor réfresh 🚀",
+            "contractor refresh\u{200b}",
+        ] {
+            assert!(
+                matches!(parse(comment), Err(BotError::InvalidCommand(_))),
+                "{:?}",
+                comment
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_long_commands() {
+        let comment = format!("contractor refresh {}", "🚀".repeat(MAX_COMMAND_LENGTH));
+
+        match parse(&comment) {
+            Err(BotError::CommandTooLong(len)) => {
+                assert_eq!(len, MAX_COMMAND_LENGTH + "contractor refresh ".len())
+            }
+            _ => panic!("expected the command to be too long"),
+        }
+    }
+
+    #[tokio::test]
+    async fn doesnt_refresh_repositories_which_opted_out() {
+        let (url, requests) = FakeGitea {
+            topics: [("someone/repo".into(), vec!["contractor-disabled".into()])].into(),
+            ..Default::default()
+        }
+        .serve()
+        .await;
+        let state = State::in_memory(testing::config(&url)).unwrap();
+
+        state
+            .bot()
+            .handle_request(BotRequest {
+                repo: Repository {
+                    owner: "someone".into(),
+                    name: "repo".into(),
+                    metadata: RepositoryMetadata::default(),
+                },
+                issue: 1,
+                requester: None,
+                command: "contractor refresh".into(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(state.queue.depth().pending, 0);
+        assert_eq!(
+            writes(&requests),
+            vec!["POST /api/v1/repos/someone/repo/issues/1/comments"]
+        );
+    }
+
+    #[tokio::test]
+    async fn acknowledges_refreshes_in_the_background() {
+        let (url, requests) = FakeGitea::default().serve().await;
+        let state = State::in_memory(testing::config(&url)).unwrap();
+
+        state
+            .bot()
+            .handle_request(BotRequest {
+                repo: Repository {
+                    owner: "someone".into(),
+                    name: "repo".into(),
+                    metadata: RepositoryMetadata::default(),
+                },
+                issue: 1,
+                requester: None,
+                command: "contractor refresh".into(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(state.queue.depth().pending, 1);
+        assert!(writes(&requests).is_empty());
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while writes(&requests).is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert_eq!(
+            writes(&requests),
+            vec!["POST /api/v1/repos/someone/repo/issues/1/comments"]
+        );
     }
 }
@@ -0,0 +1,390 @@
+use std::{collections::HashMap, pin::Pin, sync::Arc, time::Duration};
+
+use futures::Future;
+use sqlx::PgPool;
+use tokio::{sync::Mutex, time::Instant};
+
+use super::{
+    gitea::Repository,
+    jobs::JobTrigger,
+    queue::{JobQueue, JobRequest},
+    reconciler::{Recheck, ReconcileOptions, Reconciler},
+};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+type DynPendingPushStore = Arc<dyn traits::PendingPushStore + Send + Sync + 'static>;
+
+/// Holds back renovate runs triggered by pushes until a repository has seen
+/// no further pushes for `delay`, so a burst of pushes causes a single run.
+/// Pending runs are kept in the database, so pushes delivered to different
+/// replicas share a single run, submitted by whichever replica takes it first.
+/// Repositories are checked again before their run is submitted, as they may
+/// have been archived or opted out during the delay.
+#[derive(Clone)]
+pub struct PushDebouncer {
+    store: DynPendingPushStore,
+    queue: JobQueue,
+    reconciler: Reconciler,
+    reconcile_options: ReconcileOptions,
+    delay: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PendingPush {
+    pub repository: String,
+    /// The pusher of the latest push
+    pub requester: Option<String>,
+}
+
+impl PushDebouncer {
+    pub fn postgres(
+        db: PgPool,
+        queue: JobQueue,
+        reconciler: Reconciler,
+        reconcile_options: ReconcileOptions,
+        delay: Duration,
+    ) -> Self {
+        Self::new(
+            Arc::new(PostgresPendingPushStore { db }),
+            queue,
+            reconciler,
+            reconcile_options,
+            delay,
+        )
+    }
+
+    pub fn in_memory(
+        queue: JobQueue,
+        reconciler: Reconciler,
+        reconcile_options: ReconcileOptions,
+        delay: Duration,
+    ) -> Self {
+        Self::new(
+            Arc::new(InMemoryPendingPushStore::default()),
+            queue,
+            reconciler,
+            reconcile_options,
+            delay,
+        )
+    }
+
+    fn new(
+        store: DynPendingPushStore,
+        queue: JobQueue,
+        reconciler: Reconciler,
+        reconcile_options: ReconcileOptions,
+        delay: Duration,
+    ) -> Self {
+        Self {
+            store,
+            queue,
+            reconciler,
+            reconcile_options,
+            delay,
+        }
+    }
+
+    /// Schedules renovate for `repo` after the delay, replacing a run still
+    /// waiting for an earlier push
+    pub async fn submit(&self, repo: &Repository, requester: Option<&str>) -> anyhow::Result<()> {
+        self.store
+            .defer(
+                &PendingPush {
+                    repository: repo.to_string(),
+                    requester: requester.map(Into::into),
+                },
+                self.delay,
+            )
+            .await
+    }
+
+    /// Submits runs whose delay passed to the queue, runs until the process
+    /// exits
+    pub async fn run(&self) -> anyhow::Result<()> {
+        loop {
+            tokio::time::sleep(POLL_INTERVAL).await;
+
+            if let Err(e) = self.submit_due().await {
+                tracing::warn!("failed to submit renovate runs after pushes: {}", e);
+            }
+        }
+    }
+
+    async fn submit_due(&self) -> anyhow::Result<()> {
+        for push in self.store.take_due().await? {
+            let Some((owner, name)) = push.repository.split_once('/') else {
+                tracing::warn!("skipping invalid pending push for: {}", push.repository);
+                continue;
+            };
+
+            let repo = Repository {
+                owner: owner.into(),
+                name: name.into(),
+                metadata: Default::default(),
+            };
+
+            let repo = match self
+                .reconciler
+                .recheck(&self.reconcile_options, &repo)
+                .await
+            {
+                Ok(Recheck::Included(repo)) => repo,
+                Ok(Recheck::Excluded(reason)) => {
+                    tracing::info!(
+                        "skipping renovate for: {} after push, it is {} now",
+                        repo,
+                        reason
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    tracing::warn!(
+                        "skipping renovate for: {} after push, failed to check it: {:#}",
+                        repo,
+                        e
+                    );
+                    continue;
+                }
+            };
+
+            tracing::info!("submitting renovate for: {} after push", repo);
+
+            // The result is only reported through the job history
+            let _ = self.queue.submit(JobRequest {
+                repo,
+                trigger: JobTrigger::Push,
+                requester: push.requester,
+                renovate_version: None,
+            });
+        }
+
+        Ok(())
+    }
+}
+
+pub struct PostgresPendingPushStore {
+    db: PgPool,
+}
+
+impl traits::PendingPushStore for PostgresPendingPushStore {
+    fn defer<'a>(
+        &'a self,
+        push: &'a PendingPush,
+        delay: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            sqlx::query(
+                r#"
+                INSERT INTO pending_pushes (repository, requester, due_at)
+                VALUES ($1, $2, now() + $3::INT8 * INTERVAL '1 second')
+                ON CONFLICT (repository) DO UPDATE
+                SET requester = excluded.requester, due_at = excluded.due_at
+                "#,
+            )
+            .bind(&push.repository)
+            .bind(&push.requester)
+            .bind(delay.as_secs() as i64)
+            .execute(&self.db)
+            .await?;
+
+            Ok(())
+        })
+    }
+
+    fn take_due<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<PendingPush>>> + Send + 'a>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<_, (String, Option<String>)>(
+                r#"
+                DELETE FROM pending_pushes
+                WHERE due_at <= now()
+                RETURNING repository, requester
+                "#,
+            )
+            .fetch_all(&self.db)
+            .await?;
+
+            Ok(rows
+                .into_iter()
+                .map(|(repository, requester)| PendingPush {
+                    repository,
+                    requester,
+                })
+                .collect())
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryPendingPushStore {
+    pending: Mutex<HashMap<String, (PendingPush, Instant)>>,
+}
+
+impl traits::PendingPushStore for InMemoryPendingPushStore {
+    fn defer<'a>(
+        &'a self,
+        push: &'a PendingPush,
+        delay: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.pending.lock().await.insert(
+                push.repository.clone(),
+                (push.clone(), Instant::now() + delay),
+            );
+
+            Ok(())
+        })
+    }
+
+    fn take_due<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<PendingPush>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut pending = self.pending.lock().await;
+            let now = Instant::now();
+
+            let due = pending
+                .iter()
+                .filter(|(_, (_, due_at))| *due_at <= now)
+                .map(|(repository, _)| repository.clone())
+                .collect::<Vec<_>>();
+
+            Ok(due
+                .into_iter()
+                .filter_map(|repository| pending.remove(&repository))
+                .map(|(push, _)| push)
+                .collect())
+        })
+    }
+}
+
+pub mod traits {
+    use std::{pin::Pin, time::Duration};
+
+    use futures::Future;
+
+    use super::PendingPush;
+
+    pub trait PendingPushStore {
+        /// Makes `push` due after `delay`, replacing a pending push for the
+        /// same repository
+        fn defer<'a>(
+            &'a self,
+            push: &'a PendingPush,
+            delay: Duration,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+        /// Removes and returns the pending pushes which are due, each is only
+        /// ever returned once
+        fn take_due<'a>(
+            &'a self,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<PendingPush>>> + Send + 'a>>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traits::PendingPushStore, *};
+    use crate::{
+        services::gitea::testing::{self, FakeGitea},
+        State,
+    };
+
+    fn push(repository: &str, requester: &str) -> PendingPush {
+        PendingPush {
+            repository: repository.into(),
+            requester: Some(requester.into()),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_memory_store_only_returns_due_pushes_once() {
+        let store = InMemoryPendingPushStore::default();
+        let delay = Duration::from_secs(60);
+
+        store.defer(&push("foo/a", "alice"), delay).await.unwrap();
+        assert!(store.take_due().await.unwrap().is_empty());
+
+        tokio::time::advance(delay).await;
+
+        assert_eq!(
+            store.take_due().await.unwrap(),
+            vec![push("foo/a", "alice")]
+        );
+        assert!(store.take_due().await.unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_memory_store_collapses_a_burst_of_pushes() {
+        let store = InMemoryPendingPushStore::default();
+        let delay = Duration::from_secs(60);
+
+        store.defer(&push("foo/a", "alice"), delay).await.unwrap();
+        tokio::time::advance(delay / 2).await;
+        store.defer(&push("foo/a", "bob"), delay).await.unwrap();
+        store.defer(&push("foo/b", "carol"), delay).await.unwrap();
+
+        // The first push would have been due by now, the second one replaced it
+        tokio::time::advance(delay / 2).await;
+        assert!(store.take_due().await.unwrap().is_empty());
+
+        tokio::time::advance(delay / 2).await;
+
+        let mut due = store.take_due().await.unwrap();
+        due.sort_by(|a, b| a.repository.cmp(&b.repository));
+        assert_eq!(due, vec![push("foo/a", "bob"), push("foo/b", "carol")]);
+    }
+
+    #[tokio::test]
+    async fn only_submits_repositories_which_are_still_included() {
+        let repo = |name: &str, extra: serde_json::Value| {
+            let mut repo = serde_json::json!({
+                "full_name": format!("someone/{}", name),
+                "default_branch": "main",
+                "topics": [],
+            });
+            repo.as_object_mut()
+                .unwrap()
+                .extend(extra.as_object().unwrap().clone());
+            repo
+        };
+
+        let (url, _) = FakeGitea {
+            repos: vec![
+                repo("included", serde_json::json!({})),
+                repo("archived", serde_json::json!({ "archived": true })),
+                repo("mirror", serde_json::json!({ "mirror": true })),
+                repo(
+                    "disabled",
+                    serde_json::json!({ "topics": ["contractor-disabled"] }),
+                ),
+            ],
+            ..Default::default()
+        }
+        .serve()
+        .await;
+
+        let mut config = testing::config(&url);
+        config.jobs.push_debounce_seconds = 0;
+        let state = State::in_memory(config).unwrap();
+
+        for name in ["included", "archived", "mirror", "disabled", "deleted"] {
+            let repo = Repository {
+                owner: "someone".into(),
+                name: name.into(),
+                metadata: Default::default(),
+            };
+            state
+                .push_debouncer
+                .submit(&repo, Some("alice"))
+                .await
+                .unwrap();
+        }
+
+        state.push_debouncer.submit_due().await.unwrap();
+
+        assert_eq!(state.queue.depth().pending, 1);
+    }
+}
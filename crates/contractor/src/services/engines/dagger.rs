@@ -1,8 +1,39 @@
-use std::{str::FromStr, sync::Arc};
-
-use dagger_sdk::ContainerWithNewFileOptsBuilder;
-use futures::Future;
-use tokio::sync::RwLock;
+use std::{
+    fmt::Display,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+use backon::{ExponentialBuilder, Retryable};
+use dagger_sdk::{
+    core::graphql_client::GraphQLError, errors::DaggerError, ContainerWithNewFileOptsBuilder,
+};
+use futures::{future::BoxFuture, Future};
+use serde::Serialize;
+use uuid::Uuid;
+
+use crate::{
+    config::RenovateRunnerConfig,
+    services::{
+        renovate::{RenovateFailed, RenovateLog, RenovateRun, STDERR_PREFIX},
+        secrets::{dagger_secret_name, RenovateSecrets},
+    },
+};
+
+/// Where the renovate wrapper leaves the exit code of renovate
+const EXIT_CODE_FILE: &str = "/tmp/contractor-exit-code";
+
+/// Runs renovate for the repository in `$0`. Stderr lines are prefixed and
+/// merged into stdout to keep the order of the output, and the wrapper
+/// always exits 0 so the output can be read back when renovate fails.
+fn renovate_script() -> String {
+    format!(
+        r#"{{ {{ renovate "$0"; echo $? > {exit_code_file}; }} 2>&1 1>&3 3>&- | sed -u 's/^/{prefix}/'; }} 3>&1"#,
+        exit_code_file = EXIT_CODE_FILE,
+        prefix = STDERR_PREFIX,
+    )
+}
 
 type DynDagger = Arc<dyn traits::Dagger + Send + Sync + 'static>;
 
@@ -11,20 +42,51 @@ pub struct Dagger {
     dagger: DynDagger,
 }
 
-impl Default for Dagger {
-    fn default() -> Self {
-        Self::new()
+impl Dagger {
+    pub fn new(config: RenovateRunnerConfig) -> Self {
+        Self {
+            dagger: Arc::new(DefaultDagger::new(config)),
+        }
     }
 }
 
+#[cfg(test)]
 impl Dagger {
-    pub fn new() -> Self {
+    /// An engine which never connects
+    pub fn disconnected() -> Self {
+        struct Disconnected;
+
+        impl traits::Dagger for Disconnected {
+            fn execute_renovate<'a>(
+                &'a self,
+                _config: &'a crate::services::renovate::RenovateConfig,
+            ) -> std::pin::Pin<Box<dyn Future<Output = anyhow::Result<RenovateRun>> + Send + 'a>>
+            {
+                Box::pin(async move {
+                    Err(EngineUnavailable {
+                        connection: self.connection(),
+                    }
+                    .into())
+                })
+            }
+
+            fn connection(&self) -> EngineConnection {
+                EngineConnection::Connecting
+            }
+        }
+
         Self {
-            dagger: Arc::new(DefaultDagger::new()),
+            dagger: Arc::new(Disconnected),
         }
     }
 }
 
+impl From<DynDagger> for Dagger {
+    fn from(dagger: DynDagger) -> Self {
+        Self { dagger }
+    }
+}
+
 impl std::ops::Deref for Dagger {
     type Target = DynDagger;
 
@@ -33,39 +95,246 @@ impl std::ops::Deref for Dagger {
     }
 }
 
-struct DefaultDagger {
-    client: Arc<RwLock<Option<dagger_sdk::Query>>>,
+/// State of the connection to the dagger engine
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum EngineConnection {
+    Connecting,
+    Connected,
+    /// The last attempt failed, another one is scheduled
+    Failed {
+        error: String,
+    },
 }
 
-impl DefaultDagger {
-    pub fn new() -> Self {
-        let client = Arc::new(RwLock::new(None));
+/// Returned when renovate is requested while the dagger engine isn't
+/// connected, or the connection is lost during the run. Callers can downcast
+/// to this to tell it apart from a failed run.
+#[derive(Debug)]
+pub struct EngineUnavailable {
+    pub connection: EngineConnection,
+}
 
-        let host =
-            std::env::var("CONTRACTOR_DOCKER_HOST").expect("CONTRACTOR_DOCKER_HOST to be set");
+impl Display for EngineUnavailable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.connection {
+            EngineConnection::Failed { error } => {
+                write!(f, "dagger engine is unavailable: {}", error)
+            }
+            _ => write!(f, "dagger engine is unavailable: still connecting"),
+        }
+    }
+}
 
-        std::env::set_var("DOCKER_HOST", host);
+impl std::error::Error for EngineUnavailable {}
 
-        tokio::spawn({
-            let client = client.clone();
+enum Connection<C> {
+    Connecting,
+    Connected(C),
+    Failed(String),
+}
 
-            async move {
-                let mut client = client.write().await;
+type Connect<C> = Arc<dyn Fn() -> BoxFuture<'static, anyhow::Result<C>> + Send + Sync>;
 
-                match dagger_sdk::connect().await {
-                    Ok(o) => *client = Some(o),
-                    Err(e) => tracing::error!("failed to start dagger engine: {}", e),
-                };
+/// Tracks the connection to the engine, connecting again with backoff after
+/// it was lost. Generic over the client so the transitions can be tested
+/// without an engine.
+struct EngineConnector<C> {
+    connection: Arc<RwLock<Connection<C>>>,
+    connect: Connect<C>,
+}
+
+impl<C: Clone + Send + Sync + 'static> EngineConnector<C> {
+    /// Starts connecting in the background
+    fn start(connect: Connect<C>) -> Self {
+        let connector = Self {
+            connection: Arc::new(RwLock::new(Connection::Connecting)),
+            connect,
+        };
+
+        tokio::spawn(connector.connect());
+
+        connector
+    }
+
+    /// Keeps trying to connect to the engine with backoff until it succeeds
+    fn connect(&self) -> impl Future<Output = ()> + Send + 'static {
+        let connection = self.connection.clone();
+        let connect = self.connect.clone();
+
+        async move {
+            let backoff = ExponentialBuilder::default()
+                .with_max_delay(Duration::from_secs(60))
+                .with_max_times(usize::MAX);
+
+            let result = (|| connect())
+                .retry(&backoff)
+                .notify(|err, dur| {
+                    tracing::warn!(
+                        "failed to start dagger engine: {err:#}, retrying in: {} seconds",
+                        dur.as_secs()
+                    );
+
+                    *connection.write().unwrap() = Connection::Failed(format!("{:#}", err));
+                })
+                .await;
+
+            match result {
+                Ok(client) => {
+                    tracing::info!("connected to dagger engine");
+                    *connection.write().unwrap() = Connection::Connected(client);
+                }
+                Err(e) => {
+                    tracing::error!("gave up starting dagger engine: {:#}", e);
+                    *connection.write().unwrap() = Connection::Failed(format!("{:#}", e));
+                }
             }
-        });
+        }
+    }
+
+    /// Starts connecting again after the connection to the engine was lost,
+    /// unless that already happened. Only a connected engine can be lost, so
+    /// runs failing together reconnect once, and a connection still being
+    /// established isn't restarted. Returns whether it reconnects.
+    fn reconnect(&self, error: &anyhow::Error) -> bool {
+        let mut connection = self.connection.write().unwrap();
+        if !matches!(*connection, Connection::Connected(_)) {
+            return false;
+        }
+
+        tracing::warn!(
+            "lost connection to dagger engine: {:#}, reconnecting",
+            error
+        );
+        *connection = Connection::Connecting;
+
+        tokio::spawn(self.connect());
 
-        Self { client }
+        true
     }
 
-    pub async fn get_client(&self) -> dagger_sdk::Query {
-        let client = self.client.clone().read().await.clone();
+    fn client(&self) -> Result<C, EngineUnavailable> {
+        match &*self.connection.read().unwrap() {
+            Connection::Connected(client) => Ok(client.clone()),
+            Connection::Connecting => Err(EngineUnavailable {
+                connection: EngineConnection::Connecting,
+            }),
+            Connection::Failed(error) => Err(EngineUnavailable {
+                connection: EngineConnection::Failed {
+                    error: error.clone(),
+                },
+            }),
+        }
+    }
 
-        client.unwrap()
+    fn status(&self) -> EngineConnection {
+        match &*self.connection.read().unwrap() {
+            Connection::Connecting => EngineConnection::Connecting,
+            Connection::Connected(_) => EngineConnection::Connected,
+            Connection::Failed(error) => EngineConnection::Failed {
+                error: error.clone(),
+            },
+        }
+    }
+}
+
+struct DefaultDagger {
+    connector: EngineConnector<dagger_sdk::Query>,
+    config: RenovateRunnerConfig,
+    secrets: RenovateSecrets,
+}
+
+impl DefaultDagger {
+    pub fn new(config: RenovateRunnerConfig) -> Self {
+        std::env::set_var("DOCKER_HOST", &config.docker_host);
+
+        let connector = EngineConnector::start(Arc::new(|| {
+            Box::pin(async { Ok(dagger_sdk::connect().await?) })
+        }));
+
+        Self {
+            connector,
+            secrets: RenovateSecrets::new(&config),
+            config,
+        }
+    }
+
+    async fn run_renovate(
+        &self,
+        client: dagger_sdk::Query,
+        config: &crate::services::renovate::RenovateConfig,
+    ) -> anyhow::Result<RenovateRun> {
+        let credentials = self.secrets.resolve(&config.repo).await?;
+
+        // Dagger keys secrets by name, so every run gets its own names to
+        // not pick up the credentials of a concurrent run
+        let run = Uuid::new_v4();
+
+        let github_com_token = client.set_secret(
+            dagger_secret_name("GITHUB_COM_TOKEN", &run),
+            credentials.github_com_token.expose(),
+        );
+
+        let renovate_secrets = client.set_secret(
+            dagger_secret_name("RENOVATE_SECRETS", &run),
+            credentials.secrets.expose(),
+        );
+
+        let renovate_token = client.set_secret(
+            dagger_secret_name("RENOVATE_TOKEN", &run),
+            credentials.token.expose(),
+        );
+
+        let renovate_file = client.http(&self.config.config_url).contents().await?;
+
+        let mut renovate_file_value: serde_json::Value = serde_json::from_str(&renovate_file)?;
+        let obj = renovate_file_value
+            .as_object_mut()
+            .ok_or(anyhow::anyhow!("config is not a valid json object"))?;
+
+        let _ = obj.insert("autodiscover".into(), serde_json::Value::from_str("false")?);
+
+        let renovate_file = serde_json::to_string(&obj)?;
+
+        let container = client
+            .container()
+            .from(&config.image)
+            .with_secret_variable("GITHUB_COM_TOKEN", github_com_token)
+            .with_secret_variable("RENOVATE_SECRETS", renovate_secrets)
+            .with_secret_variable("RENOVATE_TOKEN", renovate_token)
+            .with_env_variable("LOG_LEVEL", "info")
+            .with_env_variable("LOG_FORMAT", "json")
+            .with_env_variable("RENOVATE_CONFIG_FILE", "/opt/renovate/config.json")
+            .with_new_file_opts(
+                "/opt/renovate/config.json",
+                ContainerWithNewFileOptsBuilder::default()
+                    .contents(renovate_file.as_str())
+                    .permissions(0o644isize)
+                    .build()?,
+            )
+            .with_exec(vec!["sh", "-c", &renovate_script(), &config.repo]);
+
+        let output = container.stdout().await?;
+        let exit_code = container.file(EXIT_CODE_FILE).contents().await?;
+        let exit_code = exit_code.trim().parse::<i64>().map_err(|e| {
+            anyhow::anyhow!("renovate exit code: {:?} is invalid: {}", exit_code, e)
+        })?;
+
+        let run = RenovateRun::new(RenovateLog::parse(&output), exit_code);
+
+        tracing::debug!(
+            "renovate on: {} exited with code: {} and {} log entries, summary: {:?}",
+            &config.repo,
+            run.exit_code,
+            run.log.entries.len(),
+            run.summary
+        );
+
+        if run.exit_code != 0 {
+            return Err(RenovateFailed { run }.into());
+        }
+
+        Ok(run)
     }
 }
 
@@ -73,72 +342,43 @@ impl traits::Dagger for DefaultDagger {
     fn execute_renovate<'a>(
         &'a self,
         config: &'a crate::services::renovate::RenovateConfig,
-    ) -> std::pin::Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+    ) -> std::pin::Pin<Box<dyn Future<Output = anyhow::Result<RenovateRun>> + Send + 'a>> {
         Box::pin(async move {
-            let renovate_image = "renovate/renovate:37";
-
-            let client = self.get_client().await;
-
-            let github_com_token = client.set_secret(
-                "GITHUB_COM_TOKEN",
-                std::env::var("CONTRACTOR_GITHUB_COM_TOKEN")
-                    .expect("CONTRACTOR_GITHUB_COM_TOKEN to be set"),
-            );
-
-            let renovate_secrets = client.set_secret(
-                "RENOVATE_SECRETS",
-                std::env::var("CONTRACTOR_RENOVATE_SECRETS")
-                    .expect("CONTRACTOR_RENOVATE_SECRETS to be set"),
-            );
-
-            let renovate_token = client.set_secret(
-                "RENOVATE_TOKEN",
-                std::env::var("CONTRACTOR_RENOVATE_TOKEN")
-                    .expect("CONTRACTOR_RENOVATE_TOKEN to be set"),
-            );
-
-            let renovate_file_url = std::env::var("CONTRACTOR_RENOVATE_CONFIG_URL")
-                .expect("CONTRACTOR_RENOVATE_CONFIG_URL to be set");
-
-            let renovate_file = client.http(renovate_file_url).contents().await?;
-
-            let mut renovate_file_value: serde_json::Value = serde_json::from_str(&renovate_file)?;
-            let obj = renovate_file_value
-                .as_object_mut()
-                .ok_or(anyhow::anyhow!("config is not a valid json object"))?;
-
-            let _ = obj.insert("autodiscover".into(), serde_json::Value::from_str("false")?);
-
-            let renovate_file = serde_json::to_string(&obj)?;
-
-            let output = client
-                .container()
-                .from(renovate_image)
-                .with_secret_variable("GITHUB_COM_TOKEN", github_com_token)
-                .with_secret_variable("RENOVATE_SECRETS", renovate_secrets)
-                .with_secret_variable("RENOVATE_TOKEN", renovate_token)
-                .with_env_variable("LOG_LEVEL", "info")
-                .with_env_variable("RENOVATE_CONFIG_FILE", "/opt/renovate/config.json")
-                .with_new_file_opts(
-                    "/opt/renovate/config.json",
-                    ContainerWithNewFileOptsBuilder::default()
-                        .contents(renovate_file.as_str())
-                        .permissions(0o644isize)
-                        .build()?,
-                )
-                .with_exec(vec![&config.repo])
-                .stdout()
-                .await?;
-
-            tracing::debug!(
-                "renovate on: {} finished with output {}",
-                &config.repo,
-                &output
-            );
-
-            Ok::<(), anyhow::Error>(())
+            let client = self.connector.client()?;
+
+            match self.run_renovate(client, config).await {
+                Err(e) if is_connection_error(&e) => {
+                    self.connector.reconnect(&e);
+
+                    Err(EngineUnavailable {
+                        connection: EngineConnection::Failed {
+                            error: format!("{:#}", e),
+                        },
+                    }
+                    .into())
+                }
+                result => result,
+            }
         })
     }
+
+    fn connection(&self) -> EngineConnection {
+        self.connector.status()
+    }
+}
+
+/// Whether `error` was caused by the connection to the engine rather than by
+/// the run itself. The sdk reports every failure to reach the engine, refused
+/// or reset connections, timeouts and unreadable responses, as an
+/// `HttpError`. Errors the engine reported for the query, like a failing
+/// image pull, are `DomainError`s and fail the run instead.
+fn is_connection_error(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        matches!(
+            e.downcast_ref::<DaggerError>(),
+            Some(DaggerError::Query(GraphQLError::HttpError(_)))
+        )
+    })
 }
 
 pub mod traits {
@@ -146,12 +386,120 @@ pub mod traits {
 
     use futures::Future;
 
-    use crate::services::renovate::RenovateConfig;
+    use crate::services::renovate::{RenovateConfig, RenovateRun};
 
     pub trait Dagger {
         fn execute_renovate<'a>(
             &'a self,
             config: &'a RenovateConfig,
-        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<RenovateRun>> + Send + 'a>>;
+
+        fn connection(&self) -> super::EngineConnection;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use dagger_sdk::errors::DaggerUnpackError;
+
+    use super::*;
+
+    /// A connector whose client counts the attempts it took, the first
+    /// `failures` attempts fail
+    fn connector(failures: usize) -> (EngineConnector<usize>, Arc<AtomicUsize>) {
+        let attempts = Arc::new(AtomicUsize::new(0));
+
+        let connector = EngineConnector::start({
+            let attempts = attempts.clone();
+            Arc::new(move || {
+                let attempt = attempts.fetch_add(1, Ordering::SeqCst) + 1;
+                Box::pin(async move {
+                    if attempt <= failures {
+                        anyhow::bail!("attempt {} failed", attempt);
+                    }
+
+                    Ok(attempt)
+                })
+            })
+        });
+
+        (connector, attempts)
+    }
+
+    /// Lets the spawned connect task run until it sleeps or finishes
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// Waits out the backoff of the spawned connect task
+    async fn wait_for_backoff() {
+        tokio::time::sleep(Duration::from_secs(10 * 60)).await;
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn keeps_connecting_until_the_engine_is_up() {
+        let (connector, attempts) = connector(2);
+        assert!(matches!(connector.status(), EngineConnection::Connecting));
+        assert!(connector.client().is_err());
+
+        settle().await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+        match connector.client() {
+            Err(EngineUnavailable {
+                connection: EngineConnection::Failed { error },
+            }) => assert_eq!(error, "attempt 1 failed"),
+            _ => panic!("expected the first attempt to have failed"),
+        }
+
+        wait_for_backoff().await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert!(matches!(connector.status(), EngineConnection::Connected));
+        assert_eq!(connector.client().unwrap(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn only_reconnects_a_connected_engine() {
+        let (connector, attempts) = connector(1);
+        let lost = anyhow::anyhow!("connection reset");
+
+        settle().await;
+        assert!(matches!(
+            connector.status(),
+            EngineConnection::Failed { .. }
+        ));
+        assert!(!connector.reconnect(&lost));
+
+        wait_for_backoff().await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(matches!(connector.status(), EngineConnection::Connected));
+
+        assert!(connector.reconnect(&lost));
+        assert!(matches!(connector.status(), EngineConnection::Connecting));
+        // Other runs failing on the same lost connection don't reconnect again
+        assert!(!connector.reconnect(&lost));
+
+        settle().await;
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(connector.client().unwrap(), 3);
+    }
+
+    #[test]
+    fn only_http_errors_are_connection_errors() {
+        let lost = anyhow::Error::new(DaggerError::Query(GraphQLError::HttpError(
+            "connection refused".into(),
+        )));
+        assert!(is_connection_error(&lost));
+        assert!(is_connection_error(&lost.context("running renovate")));
+
+        let unpack =
+            anyhow::Error::new(DaggerError::Unpack(DaggerUnpackError::TooManyNestedObjects));
+        assert!(!is_connection_error(&unpack));
+        assert!(!is_connection_error(&anyhow::anyhow!(
+            "renovate exit code is invalid"
+        )));
     }
 }
@@ -0,0 +1,264 @@
+use crate::SharedState;
+
+use super::{
+    debouncer::PushDebouncer,
+    gitea::{GiteaClient, GiteaClientState, Repository},
+    reconciler::{ReconcileOptions, Reconciler, ReconcilerState},
+    renovate::RENOVATE_CONFIG_FILES,
+};
+
+/// Gitea events contractor acts on besides bot comments
+pub enum GiteaEvent {
+    Push(PushEvent),
+    PullRequest(PullRequestEvent),
+    Repository(RepositoryEvent),
+}
+
+pub struct PushEvent {
+    pub repo: Repository,
+    /// The pushed ref, e.g. `refs/heads/main`
+    pub git_ref: String,
+    /// The commit the ref pointed at before the push, `None` for a new ref
+    pub before: Option<String>,
+    pub after: String,
+    /// Files added, modified or removed by the pushed commits
+    pub changed_files: Vec<String>,
+    pub pusher: Option<String>,
+}
+
+pub struct PullRequestEvent {
+    pub repo: Repository,
+    pub action: String,
+    pub number: u64,
+    pub title: String,
+}
+
+pub struct RepositoryEvent {
+    pub repo: Repository,
+    pub action: String,
+}
+
+pub struct EventHandler {
+    gitea_client: GiteaClient,
+    reconciler: Reconciler,
+    reconcile_options: ReconcileOptions,
+    push_debouncer: PushDebouncer,
+}
+
+impl EventHandler {
+    pub fn new(
+        gitea_client: GiteaClient,
+        reconciler: Reconciler,
+        reconcile_options: ReconcileOptions,
+        push_debouncer: PushDebouncer,
+    ) -> Self {
+        Self {
+            gitea_client,
+            reconciler,
+            reconcile_options,
+            push_debouncer,
+        }
+    }
+
+    pub async fn handle(&self, event: GiteaEvent) -> anyhow::Result<()> {
+        match event {
+            GiteaEvent::Push(push) => self.handle_push(push).await,
+            GiteaEvent::PullRequest(pr) => {
+                tracing::info!(
+                    "pull request {} #{} on: {}, title: {}",
+                    pr.action,
+                    pr.number,
+                    pr.repo,
+                    pr.title
+                );
+
+                Ok(())
+            }
+            GiteaEvent::Repository(repository) => self.handle_repository(repository).await,
+        }
+    }
+
+    async fn handle_push(&self, push: PushEvent) -> anyhow::Result<()> {
+        let default_branch = format!("refs/heads/{}", push.repo.metadata.default_branch);
+        if push.git_ref != default_branch {
+            tracing::debug!(
+                "push to {} on: {} isn't on the default branch, skipping",
+                push.git_ref,
+                push.repo
+            );
+            return Ok(());
+        }
+
+        if !self.changes_renovate_config(&push).await? {
+            tracing::debug!(
+                "push to {} on: {} didn't change the renovate config, skipping",
+                push.git_ref,
+                push.repo
+            );
+            return Ok(());
+        }
+
+        if self
+            .reconciler
+            .opted_out(&self.reconcile_options, &push.repo)
+            .await?
+        {
+            tracing::info!(
+                "renovate config changed by push on: {}, but it opted out by topic, skipping",
+                push.repo
+            );
+            return Ok(());
+        }
+
+        tracing::info!(
+            "renovate config changed by push to {} on: {}, scheduling renovate",
+            push.git_ref,
+            push.repo
+        );
+
+        self.push_debouncer
+            .submit(&push.repo, push.pusher.as_deref())
+            .await
+    }
+
+    async fn changes_renovate_config(&self, push: &PushEvent) -> anyhow::Result<bool> {
+        if push.changed_files.iter().any(|f| is_renovate_config(f)) {
+            return Ok(true);
+        }
+
+        if !push.changed_files.iter().any(|f| f == PACKAGE_JSON) {
+            return Ok(false);
+        }
+
+        let before = match &push.before {
+            Some(before) => {
+                self.gitea_client
+                    .get_raw_file(&push.repo, PACKAGE_JSON, Some(before))
+                    .await?
+            }
+            None => None,
+        };
+        let after = self
+            .gitea_client
+            .get_raw_file(&push.repo, PACKAGE_JSON, Some(&push.after))
+            .await?;
+
+        Ok(package_renovate_config(before.as_deref()) != package_renovate_config(after.as_deref()))
+    }
+
+    /// Gitea only sends repository events to organisation and system hooks,
+    /// which operators add by hand, see the README
+    async fn handle_repository(&self, event: RepositoryEvent) -> anyhow::Result<()> {
+        if event.action != "created" {
+            tracing::debug!(
+                "repository {} for: {}, nothing to do",
+                event.action,
+                event.repo
+            );
+            return Ok(());
+        }
+
+        // The same checks as a reconcile, so a created repository doesn't get a
+        // webhook the next reconcile with `--prune` would remove again
+        if !self
+            .reconciler
+            .wants_webhook(&self.reconcile_options, event.repo.clone())
+            .await?
+        {
+            tracing::debug!(
+                "created repository: {} is filtered out or doesn't have renovate enabled, skipping",
+                event.repo
+            );
+            return Ok(());
+        }
+
+        if self.gitea_client.get_webhook(&event.repo).await?.is_some() {
+            tracing::debug!("webhook already found for: {}, skipping", event.repo);
+            return Ok(());
+        }
+
+        tracing::info!("installing webhook for created repository: {}", event.repo);
+        self.gitea_client.add_webhook(&event.repo).await
+    }
+}
+
+pub trait EventHandlerState {
+    fn event_handler(&self) -> EventHandler;
+}
+impl EventHandlerState for SharedState {
+    fn event_handler(&self) -> EventHandler {
+        EventHandler::new(
+            self.gitea_client(),
+            self.reconciler(),
+            self.reconcile.clone(),
+            self.push_debouncer.clone(),
+        )
+    }
+}
+
+const PACKAGE_JSON: &str = "package.json";
+
+/// Whether a push changing `path` changed the renovate config. `package.json`
+/// changes far more often than its `renovate` key, so it is compared through
+/// [`package_renovate_config`] instead.
+fn is_renovate_config(path: &str) -> bool {
+    path != PACKAGE_JSON && RENOVATE_CONFIG_FILES.contains(&path)
+}
+
+/// The `renovate` key of a `package.json`, `None` if the file doesn't exist,
+/// isn't valid json or doesn't have the key
+fn package_renovate_config(package: Option<&str>) -> Option<serde_json::Value> {
+    serde_json::from_str::<serde_json::Value>(package?)
+        .ok()?
+        .get("renovate")
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renovate_config_files_are_detected() {
+        assert!(is_renovate_config("renovate.json"));
+        assert!(is_renovate_config(".github/renovate.json5"));
+        assert!(is_renovate_config(".renovaterc"));
+
+        assert!(!is_renovate_config("package.json"));
+        assert!(!is_renovate_config("sub/renovate.json"));
+        assert!(!is_renovate_config("README.md"));
+    }
+
+    #[test]
+    fn package_renovate_config_is_the_renovate_key() {
+        let package = r#"{"name": "app", "renovate": {"extends": ["config:base"]}}"#;
+
+        assert_eq!(
+            package_renovate_config(Some(package)),
+            Some(serde_json::json!({"extends": ["config:base"]}))
+        );
+        assert_eq!(package_renovate_config(Some(r#"{"name": "app"}"#)), None);
+        assert_eq!(package_renovate_config(Some("not json")), None);
+        assert_eq!(package_renovate_config(None), None);
+    }
+
+    #[test]
+    fn only_renovate_key_changes_count() {
+        let before = r#"{"version": "1.0.0", "renovate": {"extends": ["config:base"]}}"#;
+        let bumped = r#"{"version": "1.1.0", "renovate": {"extends": ["config:base"]}}"#;
+        let changed = r#"{"version": "1.0.0", "renovate": {"extends": ["config:recommended"]}}"#;
+
+        assert_eq!(
+            package_renovate_config(Some(before)),
+            package_renovate_config(Some(bumped))
+        );
+        assert_ne!(
+            package_renovate_config(Some(before)),
+            package_renovate_config(Some(changed))
+        );
+        assert_ne!(
+            package_renovate_config(None),
+            package_renovate_config(Some(before))
+        );
+    }
+}
@@ -1,11 +1,15 @@
-use std::{fmt::Display, ops::Deref, pin::Pin, sync::Arc};
+use std::{fmt::Display, ops::Deref, pin::Pin, sync::Arc, time::Duration};
+
+const PAGE_SIZE: usize = 50;
+const PAGE_CONCURRENCY: usize = 5;
 
 type DynGiteaClient = Arc<dyn traits::GiteaClient + Send + Sync + 'static>;
+#[derive(Clone)]
 pub struct GiteaClient(DynGiteaClient);
 
 impl GiteaClient {
-    pub fn new() -> Self {
-        Self(Arc::new(DefaultGiteaClient::default()))
+    pub fn new(config: &Config, metrics: Metrics) -> Self {
+        Self(Arc::new(DefaultGiteaClient::new(config, metrics)))
     }
 }
 
@@ -17,10 +21,40 @@ impl Deref for GiteaClient {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+/// A repository is identified by its owner and name, the metadata is only
+/// informational and isn't part of equality
+#[derive(Clone, Debug)]
 pub struct Repository {
     pub owner: String,
     pub name: String,
+    pub metadata: RepositoryMetadata,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct RepositoryMetadata {
+    pub archived: bool,
+    pub fork: bool,
+    pub mirror: bool,
+    pub empty: bool,
+    pub private: bool,
+    pub default_branch: String,
+    /// `None` when gitea didn't include the topics in the response
+    pub topics: Option<Vec<String>>,
+}
+
+impl PartialEq for Repository {
+    fn eq(&self, other: &Self) -> bool {
+        self.owner == other.owner && self.name == other.name
+    }
+}
+
+impl Eq for Repository {}
+
+impl std::hash::Hash for Repository {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.owner.hash(state);
+        self.name.hash(state);
+    }
 }
 
 impl Display for Repository {
@@ -29,6 +63,16 @@ impl Display for Repository {
     }
 }
 
+/// Serialized as `owner/name`
+impl Serialize for Repository {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.collect_str(self)
+    }
+}
+
 impl TryFrom<GiteaRepository> for Repository {
     type Error = anyhow::Error;
 
@@ -48,6 +92,15 @@ impl TryFrom<GiteaRepository> for Repository {
         Ok(Repository {
             owner: owner.into(),
             name: name.into(),
+            metadata: RepositoryMetadata {
+                archived: value.archived,
+                fork: value.fork,
+                mirror: value.mirror,
+                empty: value.empty,
+                private: value.private,
+                default_branch: value.default_branch,
+                topics: value.topics,
+            },
         })
     }
 }
@@ -55,6 +108,29 @@ impl TryFrom<GiteaRepository> for Repository {
 #[derive(Clone, Debug, Deserialize)]
 pub struct GiteaRepository {
     full_name: String,
+    #[serde(default)]
+    archived: bool,
+    #[serde(default)]
+    fork: bool,
+    #[serde(default)]
+    mirror: bool,
+    #[serde(default)]
+    empty: bool,
+    #[serde(default)]
+    private: bool,
+    #[serde(default)]
+    default_branch: String,
+    /// Gitea sends `null` for repositories without topics, and leaves the
+    /// field out where it doesn't report topics at all
+    #[serde(default, deserialize_with = "topics_or_empty")]
+    topics: Option<Vec<String>>,
+}
+
+fn topics_or_empty<'de, D>(deserializer: D) -> Result<Option<Vec<String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Option::<Vec<String>>::deserialize(deserializer).map(|topics| Some(topics.unwrap_or_default()))
 }
 
 pub struct DefaultGiteaClient {
@@ -62,23 +138,48 @@ pub struct DefaultGiteaClient {
     token: String,
 
     webhook_url: String,
+    webhook_secret: String,
+
+    metrics: Metrics,
 }
 
-impl Default for DefaultGiteaClient {
-    fn default() -> Self {
+impl DefaultGiteaClient {
+    pub fn new(config: &Config, metrics: Metrics) -> Self {
         Self {
-            url: std::env::var("GITEA_URL")
-                .context("GITEA_URL should be set")
-                .map(|g| g.trim_end_matches('/').to_string())
-                .unwrap(),
-            token: std::env::var("GITEA_TOKEN")
-                .context("GITEA_TOKEN should be set")
-                .unwrap(),
-            webhook_url: std::env::var("CONTRACTOR_URL")
-                .context("CONTRACTOR_URL should be set")
-                .map(|url| format!("{}/webhooks/gitea", url.trim_end_matches('/')))
-                .unwrap(),
+            url: config.gitea.url.trim_end_matches('/').to_string(),
+            token: config.gitea.token.clone(),
+            webhook_url: format!(
+                "{}/webhooks/gitea",
+                config.webhook.url.trim_end_matches('/')
+            ),
+            webhook_secret: config.webhook.secret.clone(),
+            metrics,
+        }
+    }
+
+    /// Records the duration and failures of a gitea api operation
+    async fn observe<T>(
+        &self,
+        operation: &str,
+        fut: impl Future<Output = anyhow::Result<T>>,
+    ) -> anyhow::Result<T> {
+        let timer = self
+            .metrics
+            .gitea_request_duration
+            .with_label_values(&[operation])
+            .start_timer();
+
+        let result = fut.await;
+
+        timer.observe_duration();
+        if result.is_err() {
+            self.metrics
+                .gitea_request_errors
+                .with_label_values(&[operation])
+                .inc();
         }
+
+        result
     }
 }
 
@@ -94,17 +195,18 @@ pub struct GiteaWebhookConfig {
     url: String,
 }
 
+/// Gitea also lists slack, discord, etc. hooks, which are only ever skipped
 #[derive(Clone, Debug, Deserialize, Serialize, PartialEq, Eq)]
 pub enum GiteaWebhookType {
     #[serde(rename = "gitea")]
     Gitea,
-    Other(String),
+    #[serde(other)]
+    Other,
 }
 
 #[derive(Clone, Debug, Serialize)]
 pub struct CreateGiteaWebhook {
     active: bool,
-    authorization_header: Option<String>,
     branch_filter: Option<String>,
     config: CreateGiteaWebhookConfig,
     events: Vec<String>,
@@ -112,142 +214,279 @@ pub struct CreateGiteaWebhook {
     r#type: GiteaWebhookType,
 }
 
+#[derive(Clone, Debug, Deserialize)]
+pub struct GiteaUser {
+    login: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GiteaTopics {
+    topics: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct GiteaPullRequest {
+    pub number: u64,
+    /// `open` or `closed`
+    pub state: String,
+    #[serde(default)]
+    pub merged: bool,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CreateGiteaPullRequest {
+    base: String,
+    head: String,
+    title: String,
+    body: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CreateGiteaFile {
+    /// Base64 encoded
+    content: String,
+    message: String,
+    branch: String,
+    new_branch: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct CreateGiteaIssueComment {
+    body: String,
+}
+
 #[derive(Clone, Debug, Serialize)]
 pub struct CreateGiteaWebhookConfig {
     content_type: String,
     url: String,
+    secret: String,
 }
 
 impl DefaultGiteaClient {
-    async fn fetch_user_repos_page(
+    /// Fetches a single page of a paginated list endpoint, along with the
+    /// pagination links and total count Gitea reports for it
+    async fn fetch_page<T: DeserializeOwned>(
         &self,
+        path: &str,
         page: usize,
-    ) -> anyhow::Result<(Vec<Repository>, Vec<usize>)> {
+    ) -> anyhow::Result<(Vec<T>, PageLinks, Option<usize>)> {
         let client = reqwest::Client::new();
 
-        let url = format!("{}/api/v1/user/repos?page={page}&limit=50", self.url);
+        let url = format!("{}{}", self.url, path);
 
-        tracing::trace!("calling url: {}", &url);
+        tracing::trace!("calling url: {}, page: {}", &url, page);
 
-        let response = client
-            .get(&url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("token {}", self.token))
-            .send()
-            .await?;
+        let response = (|| async {
+            client
+                .get(&url)
+                .query(&[("page", page), ("limit", PAGE_SIZE)])
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?
+        .error_for_status()?;
+
+        let links = match response.headers().get("link") {
+            Some(link_header) => parse_link(link_header.to_str()?)?,
+            None => PageLinks::default(),
+        };
+
+        let total_count = match response.headers().get("x-total-count") {
+            Some(total_count) => Some(total_count.to_str()?.parse::<usize>()?),
+            None => None,
+        };
+
+        let items = response.json::<Vec<T>>().await?;
+
+        Ok((items, links, total_count))
+    }
+
+    /// Fetches every page of a paginated list endpoint. Uses the `last` link to
+    /// fetch the remaining pages concurrently, and falls back to walking the
+    /// `next` links when Gitea doesn't report a last page.
+    async fn fetch_paginated<T: DeserializeOwned + Send>(
+        &self,
+        path: &str,
+    ) -> anyhow::Result<Vec<T>> {
+        let (mut items, links, total_count) = self.fetch_page::<T>(path, 1).await?;
+
+        match links.last {
+            Some(last) => {
+                let pages: Vec<Vec<T>> = futures::stream::iter(2..=last)
+                    .map(|page| async move {
+                        let (items, _, _) = self.fetch_page::<T>(path, page).await?;
+
+                        Ok::<Vec<T>, anyhow::Error>(items)
+                    })
+                    .buffered(PAGE_CONCURRENCY)
+                    .try_collect()
+                    .await?;
+
+                items.extend(pages.into_iter().flatten());
+            }
+            None => {
+                let mut current = 1;
+                let mut next = links.next;
+
+                while let Some(page) = next.filter(|page| *page > current) {
+                    let (page_items, links, _) = self.fetch_page::<T>(path, page).await?;
+
+                    items.extend(page_items);
+                    current = page;
+                    next = links.next;
+                }
+            }
+        }
 
-        let mut pages = Vec::new();
-        if page <= 1 {
-            if let Some(link_header) = response.headers().get("link") {
-                let link_str = link_header.to_str()?;
-                pages = parse_link(page, link_str)?;
+        if let Some(total_count) = total_count {
+            if total_count != items.len() {
+                tracing::warn!(
+                    "{} reported {} items, but {} were fetched",
+                    path,
+                    total_count,
+                    items.len()
+                );
             }
         }
 
-        let repositories = response.json::<Vec<GiteaRepository>>().await?;
+        Ok(items)
+    }
 
-        Ok((
-            repositories
-                .into_iter()
-                .flat_map(Repository::try_from)
-                .collect(),
-            pages,
-        ))
+    /// The url of the endpoint below `repo` made of `segments`, each segment
+    /// is encoded so file paths and branches have to be split on `/` first
+    fn repo_url<'a>(
+        &self,
+        repo: &'a Repository,
+        segments: impl IntoIterator<Item = &'a str>,
+    ) -> String {
+        format!(
+            "{}{}",
+            self.url,
+            api_path(
+                ["repos", repo.owner.as_str(), repo.name.as_str()]
+                    .into_iter()
+                    .chain(segments)
+            )
+        )
     }
 
-    pub async fn fetch_user_repos(&self) -> anyhow::Result<Vec<Repository>> {
-        let (repos, pages) = self.fetch_user_repos_page(1).await?;
+    /// Fetches the repositories of `user`, or those of the token owner when
+    /// `user` is `None`
+    pub async fn fetch_user_repos(&self, user: Option<&str>) -> anyhow::Result<Vec<Repository>> {
+        let path = match user {
+            Some(user) => api_path(["users", user, "repos"]),
+            None => api_path(["user", "repos"]),
+        };
 
-        let tasks = pages
+        let repositories = self.fetch_paginated::<GiteaRepository>(&path).await?;
+
+        Ok(repositories
             .into_iter()
-            .map(|page| async move {
-                let (new_repos, _) = self.fetch_user_repos_page(page).await?;
+            .flat_map(Repository::try_from)
+            .collect())
+    }
 
-                Ok::<Vec<Repository>, anyhow::Error>(new_repos)
-            })
-            .collect::<FuturesUnordered<_>>();
+    pub async fn fetch_org_repos(&self, org: &str) -> anyhow::Result<Vec<Repository>> {
+        let path = api_path(["orgs", org, "repos"]);
 
-        let res: Result<Vec<Vec<Repository>>, anyhow::Error> = tasks.try_collect().await;
-        let res = res?.into_iter().flatten();
+        let repositories = self.fetch_paginated::<GiteaRepository>(&path).await?;
 
-        Ok(repos.into_iter().chain(res).collect())
+        Ok(repositories
+            .into_iter()
+            .flat_map(Repository::try_from)
+            .collect())
     }
 
-    async fn fetch_org_repos_page(
-        &self,
-        org: &str,
-        page: usize,
-    ) -> anyhow::Result<(Vec<Repository>, Vec<usize>)> {
+    async fn fetch_repository(&self, repo: &Repository) -> anyhow::Result<Option<Repository>> {
         let client = reqwest::Client::new();
 
-        let url = format!(
-            "{}/api/v1/orgs/{}/repos?page={page}&limit=50",
-            self.url, org
-        );
+        let url = self.repo_url(repo, []);
 
         tracing::trace!("calling url: {}", &url);
 
-        let response = client
-            .get(&url)
-            .header("Content-Type", "application/json")
-            .header("Authorization", format!("token {}", self.token))
-            .send()
-            .await?;
+        let response = (|| async {
+            client
+                .get(&url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
 
-        let mut pages = Vec::new();
-        if page <= 1 {
-            if let Some(link_header) = response.headers().get("link") {
-                let link_str = link_header.to_str()?;
-                pages = parse_link(page, link_str)?;
-            }
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
         }
 
-        let repositories = response.json::<Vec<GiteaRepository>>().await?;
+        let repository = response
+            .error_for_status()?
+            .json::<GiteaRepository>()
+            .await?;
 
-        Ok((
-            repositories
-                .into_iter()
-                .flat_map(Repository::try_from)
-                .collect(),
-            pages,
-        ))
+        Ok(Some(repository.try_into()?))
     }
 
-    pub async fn fetch_org_repos(&self, org: &str) -> anyhow::Result<Vec<Repository>> {
-        let (repos, pages) = self.fetch_org_repos_page(org, 1).await?;
-
-        let tasks = pages
-            .into_iter()
-            .map(|page| async move {
-                let (new_repos, _) = self.fetch_org_repos_page(org, page).await?;
-
-                Ok::<Vec<Repository>, anyhow::Error>(new_repos)
-            })
-            .collect::<FuturesUnordered<_>>();
+    /// Finds the first renovate config file in `repo`, checking the same
+    /// files as renovate in the same order
+    async fn fetch_renovate(&self, repo: &Repository) -> anyhow::Result<Option<String>> {
+        for file in RENOVATE_CONFIG_FILES {
+            let Some(content) = self.fetch_raw_file(repo, file, None).await? else {
+                continue;
+            };
+
+            if *file == "package.json" {
+                let package = match serde_json::from_str::<serde_json::Value>(&content) {
+                    Ok(package) => package,
+                    Err(e) => {
+                        tracing::debug!("failed to parse package.json for: {}, error: {}", repo, e);
+                        continue;
+                    }
+                };
+
+                if package.get("renovate").is_none() {
+                    continue;
+                }
+            }
 
-        let res: Result<Vec<Vec<Repository>>, anyhow::Error> = tasks.try_collect().await;
-        let res = res?.into_iter().flatten();
+            return Ok(Some(file.to_string()));
+        }
 
-        Ok(repos.into_iter().chain(res).collect())
+        Ok(None)
     }
 
-    async fn fetch_renovate(&self, repo: &Repository) -> anyhow::Result<Option<()>> {
+    /// Fetches the content of `path` at `git_ref` of `repo`, on the default
+    /// branch when `git_ref` is `None`. `None` if the file doesn't exist
+    async fn fetch_raw_file(
+        &self,
+        repo: &Repository,
+        path: &str,
+        git_ref: Option<&str>,
+    ) -> anyhow::Result<Option<String>> {
         let client = reqwest::Client::new();
 
-        let url = format!(
-            "{}/api/v1/repos/{}/{}/contents/renovate.json",
-            self.url, &repo.owner, &repo.name
-        );
+        let url = self.repo_url(repo, ["raw"].into_iter().chain(path.split('/')));
 
         tracing::trace!("calling url: {}", &url);
 
         let response = (|| async {
-            client
+            let mut request = client
                 .get(&url)
-                .header("Content-Type", "application/json")
-                .header("Authorization", format!("token {}", self.token))
-                .send()
-                .await
+                .header("Authorization", format!("token {}", self.token));
+            if let Some(git_ref) = git_ref {
+                request = request.query(&[("ref", git_ref)]);
+            }
+
+            request.send().await
         })
         .retry(&ExponentialBuilder::default())
         .notify(|err, dur| {
@@ -256,12 +495,13 @@ impl DefaultGiteaClient {
         .await?;
 
         match response.error_for_status() {
-            Ok(_) => Ok(Some(())),
+            Ok(response) => Ok(Some(response.text().await?)),
             Err(e) => match e.status() {
                 Some(StatusCode::NOT_FOUND) => Ok(None),
                 Some(status) => {
                     tracing::warn!(
-                        "failed to call fetch renovate for: {}, with error: {}",
+                        "failed to fetch {} for: {}, with error: {}",
+                        path,
                         &repo,
                         status
                     );
@@ -274,13 +514,58 @@ impl DefaultGiteaClient {
         }
     }
 
-    async fn get_webhook(&self, repo: &Repository) -> anyhow::Result<Option<GiteaWebhook>> {
+    async fn fetch_current_user(&self) -> anyhow::Result<String> {
         let client = reqwest::Client::new();
 
-        let url = format!(
-            "{}/api/v1/repos/{}/{}/hooks",
-            self.url, &repo.owner, &repo.name
-        );
+        let url = format!("{}{}", self.url, api_path(["user"]));
+
+        tracing::trace!("calling url: {}", &url);
+
+        let user = client
+            .get(&url)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("token {}", self.token))
+            .timeout(Duration::from_secs(5))
+            .send()
+            .await?
+            .error_for_status()?
+            .json::<GiteaUser>()
+            .await?;
+
+        Ok(user.login)
+    }
+
+    async fn fetch_topics(&self, repo: &Repository) -> anyhow::Result<Vec<String>> {
+        let client = reqwest::Client::new();
+
+        let url = self.repo_url(repo, ["topics"]);
+
+        tracing::trace!("calling url: {}", &url);
+
+        let response = (|| async {
+            client
+                .get(&url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?
+        .error_for_status()?;
+
+        let topics = response.json::<GiteaTopics>().await?;
+
+        Ok(topics.topics)
+    }
+
+    async fn fetch_webhook(&self, repo: &Repository) -> anyhow::Result<Option<GiteaWebhook>> {
+        let client = reqwest::Client::new();
+
+        let url = self.repo_url(repo, ["hooks"]);
 
         tracing::trace!("calling url: {}", &url);
 
@@ -303,27 +588,21 @@ impl DefaultGiteaClient {
         let valid_webhooks = webhooks
             .into_iter()
             .filter(|w| w.r#type == GiteaWebhookType::Gitea)
-            .filter(|w| w.config.url.contains("contractor"))
+            .filter(|w| w.config.url == self.contractor_webhook_url())
             .collect::<Vec<_>>();
 
         Ok(valid_webhooks.first().map(|f| f.to_owned()))
     }
 
-    async fn add_webhook(&self, repo: &Repository) -> anyhow::Result<()> {
+    async fn post_webhook(&self, repo: &Repository) -> anyhow::Result<()> {
         let client = reqwest::Client::new();
 
-        let url = format!(
-            "{}/api/v1/repos/{}/{}/hooks",
-            self.url, &repo.owner, &repo.name
-        );
+        let url = self.repo_url(repo, ["hooks"]);
 
         let val = self.create_webhook();
 
-        tracing::trace!(
-            "calling url: {} with body {}",
-            &url,
-            serde_json::to_string(&val)?
-        );
+        // The body carries the webhook secret, so only the url is traced
+        tracing::trace!("calling url: {}", &url);
 
         let response = (|| async {
             client
@@ -352,35 +631,40 @@ impl DefaultGiteaClient {
         Ok(())
     }
 
+    /// The url of the webhook contractor installs, hooks are only treated as
+    /// contractor's when their url matches it exactly
+    fn contractor_webhook_url(&self) -> String {
+        format!("{}?type=contractor", self.webhook_url)
+    }
+
     fn create_webhook(&self) -> CreateGiteaWebhook {
         CreateGiteaWebhook {
             active: true,
-            authorization_header: Some("something".into()),
             branch_filter: Some("*".into()),
             config: CreateGiteaWebhookConfig {
                 content_type: "json".into(),
-                url: format!("{}?type=contractor", self.webhook_url),
+                url: self.contractor_webhook_url(),
+                secret: self.webhook_secret.clone(),
             },
-            events: vec!["pull_request_comment".into(), "issue_comment".into()],
+            events: vec![
+                "pull_request_comment".into(),
+                "issue_comment".into(),
+                "push".into(),
+                "pull_request".into(),
+            ],
             r#type: GiteaWebhookType::Gitea,
         }
     }
 
-    async fn update_webhook(&self, repo: &Repository, webhook: GiteaWebhook) -> anyhow::Result<()> {
+    async fn patch_webhook(&self, repo: &Repository, webhook: &GiteaWebhook) -> anyhow::Result<()> {
         let client = reqwest::Client::new();
 
-        let url = format!(
-            "{}/api/v1/repos/{}/{}/hooks/{}",
-            self.url, &repo.owner, &repo.name, &webhook.id,
-        );
+        let url = self.repo_url(repo, ["hooks", &webhook.id.to_string()]);
 
         let val = self.create_webhook();
 
-        tracing::trace!(
-            "calling url: {} with body {}",
-            &url,
-            serde_json::to_string(&val)?
-        );
+        // The body carries the webhook secret, so only the url is traced
+        tracing::trace!("calling url: {}", &url);
 
         let response = (|| async {
             client
@@ -408,101 +692,902 @@ impl DefaultGiteaClient {
 
         Ok(())
     }
-}
-
-impl traits::GiteaClient for DefaultGiteaClient {
-    fn get_user_repositories<'a>(
-        &'a self,
-        user: &str,
-    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>
-    {
-        tracing::debug!("fetching gitea repositories for user: {user}");
-
-        Box::pin(async { self.fetch_user_repos().await })
-    }
-
-    fn get_org_repositories<'a>(
-        &'a self,
-        org: &'a str,
-    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>
-    {
-        tracing::debug!("fetching gitea repositories for org: {org}");
 
-        Box::pin(async move { self.fetch_org_repos(org).await })
-    }
+    async fn remove_webhook(
+        &self,
+        repo: &Repository,
+        webhook: &GiteaWebhook,
+    ) -> anyhow::Result<()> {
+        let client = reqwest::Client::new();
 
-    fn renovate_enabled<'a>(
-        &'a self,
-        repo: &'a Repository,
-    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<bool>> + Send + 'a>> {
-        tracing::trace!("checking whether renovate is enabled for: {:?}", repo);
+        let url = self.repo_url(repo, ["hooks", &webhook.id.to_string()]);
 
-        Box::pin(async { self.fetch_renovate(repo).await.map(|s| s.is_some()) })
-    }
+        tracing::trace!("calling url: {}", &url);
 
-    fn ensure_webhook<'a>(
-        &'a self,
-        repo: &'a Repository,
-        force_refresh: bool,
-    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
-        tracing::trace!("ensuring webhook exists for repo: {}", repo);
+        let response = (|| async {
+            client
+                .delete(&url)
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
 
-        Box::pin(async move {
-            match (self.get_webhook(repo).await?, force_refresh) {
-                (Some(_), false) => {
-                    tracing::trace!("webhook already found for {} skipping...", repo);
-                }
-                (Some(webhook), true) => {
-                    tracing::trace!("webhook already found for {} refreshing it", repo);
-                    self.update_webhook(repo, webhook).await?;
-                }
-                (None, _) => {
-                    tracing::trace!("webhook was not found for {} adding", repo);
-                    self.add_webhook(repo).await?;
-                }
+        if let Err(e) = response.error_for_status_ref() {
+            if let Ok(ok) = response.text().await {
+                anyhow::bail!("failed to delete webhook: {}, body: {}", e, ok);
             }
 
-            Ok(())
-        })
+            anyhow::bail!("failed to delete webhook: {}", e)
+        }
+
+        Ok(())
     }
-}
 
-// <https://git.front.kjuulh.io/api/v1/user/repos?page=2>; rel="next",<https://git.front.kjuulh.io/api/v1/user/repos?page=9>; rel="last"
-fn parse_link(page: usize, link_str: &str) -> anyhow::Result<Vec<usize>> {
-    let link_sections = link_str.split(',');
+    async fn add_issue_comment(
+        &self,
+        repo: &Repository,
+        issue: u64,
+        body: &str,
+    ) -> anyhow::Result<()> {
+        let client = reqwest::Client::new();
 
-    for link_section in link_sections {
-        if let Some((link, rel)) = link_section.rsplit_once("; ") {
-            if rel == r#"rel="last""# {
-                let actual_link = &link[1..link.len() - 1];
+        let url = self.repo_url(repo, ["issues", &issue.to_string(), "comments"]);
 
-                let url = Url::parse(actual_link)?;
+        let val = CreateGiteaIssueComment { body: body.into() };
 
-                if let Some(page_num) = url
-                    .query_pairs()
-                    .into_iter()
-                    .find(|(name, _)| name == "page")
-                    .map(|(_, value)| value)
-                {
-                    let page_num: usize = page_num.parse()?;
+        tracing::trace!("calling url: {}", &url);
 
-                    let page_numbers = (page + 1..page_num).collect::<Vec<usize>>();
+        let response = (|| async {
+            client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .json(&val)
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
 
-                    return Ok(page_numbers);
-                }
+        if let Err(e) = response.error_for_status_ref() {
+            if let Ok(ok) = response.text().await {
+                anyhow::bail!("failed to create issue comment: {}, body: {}", e, ok);
             }
+
+            anyhow::bail!("failed to create issue comment: {}", e)
         }
+
+        Ok(())
     }
 
-    Ok(Vec::default())
-}
+    async fn fetch_pull_request(
+        &self,
+        repo: &Repository,
+        head: &str,
+    ) -> anyhow::Result<Option<GiteaPullRequest>> {
+        let client = reqwest::Client::new();
 
-mod extensions;
-pub mod traits;
+        let url = self.repo_url(
+            repo,
+            ["pulls", repo.metadata.default_branch.as_str()]
+                .into_iter()
+                .chain(head.split('/')),
+        );
 
-use anyhow::Context;
-use backon::{ExponentialBuilder, Retryable};
-pub use extensions::*;
-use futures::{stream::FuturesUnordered, TryStreamExt};
-use reqwest::{StatusCode, Url};
-use serde::{Deserialize, Serialize};
+        tracing::trace!("calling url: {}", &url);
+
+        let response = (|| async {
+            client
+                .get(&url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        let pull_request = response
+            .error_for_status()?
+            .json::<GiteaPullRequest>()
+            .await?;
+
+        Ok(Some(pull_request))
+    }
+
+    async fn fetch_branch_exists(&self, repo: &Repository, branch: &str) -> anyhow::Result<bool> {
+        let client = reqwest::Client::new();
+
+        let url = self.repo_url(repo, ["branches"].into_iter().chain(branch.split('/')));
+
+        tracing::trace!("calling url: {}", &url);
+
+        let response = (|| async {
+            client
+                .get(&url)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(false);
+        }
+
+        response.error_for_status()?;
+
+        Ok(true)
+    }
+
+    async fn post_file(
+        &self,
+        repo: &Repository,
+        new_branch: &str,
+        path: &str,
+        content: &str,
+        message: &str,
+    ) -> anyhow::Result<()> {
+        let client = reqwest::Client::new();
+
+        let url = self.repo_url(repo, ["contents"].into_iter().chain(path.split('/')));
+
+        let val = CreateGiteaFile {
+            content: base64::engine::general_purpose::STANDARD.encode(content),
+            message: message.into(),
+            branch: repo.metadata.default_branch.clone(),
+            new_branch: new_branch.into(),
+        };
+
+        tracing::trace!("calling url: {}", &url);
+
+        let response = (|| async {
+            client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .json(&val)
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
+
+        if let Err(e) = response.error_for_status_ref() {
+            if let Ok(ok) = response.text().await {
+                anyhow::bail!("failed to create file: {}, body: {}", e, ok);
+            }
+
+            anyhow::bail!("failed to create file: {}", e)
+        }
+
+        Ok(())
+    }
+
+    async fn post_pull_request(
+        &self,
+        repo: &Repository,
+        head: &str,
+        title: &str,
+        body: &str,
+    ) -> anyhow::Result<GiteaPullRequest> {
+        let client = reqwest::Client::new();
+
+        let url = self.repo_url(repo, ["pulls"]);
+
+        let val = CreateGiteaPullRequest {
+            base: repo.metadata.default_branch.clone(),
+            head: head.into(),
+            title: title.into(),
+            body: body.into(),
+        };
+
+        tracing::trace!("calling url: {}", &url);
+
+        let response = (|| async {
+            client
+                .post(&url)
+                .header("Content-Type", "application/json")
+                .header("Accept", "application/json")
+                .header("Authorization", format!("token {}", self.token))
+                .json(&val)
+                .send()
+                .await
+        })
+        .retry(&ExponentialBuilder::default())
+        .notify(|err, dur| {
+            tracing::debug!("retrying job: {err}, in: {} seconds", dur.as_secs());
+        })
+        .await?;
+
+        if let Err(e) = response.error_for_status_ref() {
+            if let Ok(ok) = response.text().await {
+                anyhow::bail!("failed to create pull request: {}, body: {}", e, ok);
+            }
+
+            anyhow::bail!("failed to create pull request: {}", e)
+        }
+
+        Ok(response.json::<GiteaPullRequest>().await?)
+    }
+}
+
+impl traits::GiteaClient for DefaultGiteaClient {
+    fn get_current_user<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<String>> + Send + 'a>> {
+        Box::pin(async move {
+            self.observe("get_current_user", self.fetch_current_user())
+                .await
+        })
+    }
+
+    fn get_user_repositories<'a>(
+        &'a self,
+        user: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>
+    {
+        tracing::debug!("fetching gitea repositories for user: {user}");
+
+        Box::pin(async move {
+            self.observe("get_user_repositories", self.fetch_user_repos(Some(user)))
+                .await
+        })
+    }
+
+    fn get_own_repositories<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>
+    {
+        tracing::debug!("fetching gitea repositories for token owner");
+
+        Box::pin(async move {
+            self.observe("get_own_repositories", self.fetch_user_repos(None))
+                .await
+        })
+    }
+
+    fn get_org_repositories<'a>(
+        &'a self,
+        org: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>
+    {
+        tracing::debug!("fetching gitea repositories for org: {org}");
+
+        Box::pin(async move {
+            self.observe("get_org_repositories", self.fetch_org_repos(org))
+                .await
+        })
+    }
+
+    fn get_repository<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<
+        Box<dyn futures::prelude::Future<Output = anyhow::Result<Option<Repository>>> + Send + 'a>,
+    > {
+        tracing::trace!("fetching repository: {}", repo);
+
+        Box::pin(async move {
+            self.observe("get_repository", self.fetch_repository(repo))
+                .await
+        })
+    }
+
+    fn renovate_enabled<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Option<String>>> + Send + 'a>>
+    {
+        tracing::trace!("checking whether renovate is enabled for: {:?}", repo);
+
+        Box::pin(async move {
+            self.observe("renovate_enabled", self.fetch_renovate(repo))
+                .await
+        })
+    }
+
+    fn get_raw_file<'a>(
+        &'a self,
+        repo: &'a Repository,
+        path: &'a str,
+        git_ref: Option<&'a str>,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Option<String>>> + Send + 'a>>
+    {
+        Box::pin(async move {
+            self.observe("get_raw_file", self.fetch_raw_file(repo, path, git_ref))
+                .await
+        })
+    }
+
+    fn get_topics<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<Vec<String>>> + Send + 'a>>
+    {
+        tracing::trace!("fetching topics for: {}", repo);
+
+        Box::pin(async move { self.observe("get_topics", self.fetch_topics(repo)).await })
+    }
+
+    fn add_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        tracing::trace!("adding webhook for repo: {}", repo);
+
+        Box::pin(async move { self.observe("add_webhook", self.post_webhook(repo)).await })
+    }
+
+    fn update_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+        webhook: &'a GiteaWebhook,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        tracing::trace!("updating webhook: {} for repo: {}", webhook.id, repo);
+
+        Box::pin(async move {
+            self.observe("update_webhook", self.patch_webhook(repo, webhook))
+                .await
+        })
+    }
+
+    fn get_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<
+        Box<
+            dyn futures::prelude::Future<Output = anyhow::Result<Option<GiteaWebhook>>> + Send + 'a,
+        >,
+    > {
+        tracing::trace!("fetching webhook for repo: {}", repo);
+
+        Box::pin(async move { self.observe("get_webhook", self.fetch_webhook(repo)).await })
+    }
+
+    fn delete_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+        webhook: &'a GiteaWebhook,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        tracing::trace!("deleting webhook: {} for repo: {}", webhook.id, repo);
+
+        Box::pin(async move {
+            self.observe("delete_webhook", self.remove_webhook(repo, webhook))
+                .await
+        })
+    }
+
+    fn create_issue_comment<'a>(
+        &'a self,
+        repo: &'a Repository,
+        issue: u64,
+        body: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        tracing::trace!("creating comment on: {}#{}", repo, issue);
+
+        Box::pin(async move {
+            self.observe(
+                "create_issue_comment",
+                self.add_issue_comment(repo, issue, body),
+            )
+            .await
+        })
+    }
+
+    fn get_pull_request<'a>(
+        &'a self,
+        repo: &'a Repository,
+        head: &'a str,
+    ) -> Pin<
+        Box<
+            dyn futures::prelude::Future<Output = anyhow::Result<Option<GiteaPullRequest>>>
+                + Send
+                + 'a,
+        >,
+    > {
+        Box::pin(async move {
+            self.observe("get_pull_request", self.fetch_pull_request(repo, head))
+                .await
+        })
+    }
+
+    fn branch_exists<'a>(
+        &'a self,
+        repo: &'a Repository,
+        branch: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<bool>> + Send + 'a>> {
+        Box::pin(async move {
+            self.observe("branch_exists", self.fetch_branch_exists(repo, branch))
+                .await
+        })
+    }
+
+    fn create_file<'a>(
+        &'a self,
+        repo: &'a Repository,
+        new_branch: &'a str,
+        path: &'a str,
+        content: &'a str,
+        message: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        tracing::trace!(
+            "creating file: {} on: {}, branch: {}",
+            path,
+            repo,
+            new_branch
+        );
+
+        Box::pin(async move {
+            self.observe(
+                "create_file",
+                self.post_file(repo, new_branch, path, content, message),
+            )
+            .await
+        })
+    }
+
+    fn create_pull_request<'a>(
+        &'a self,
+        repo: &'a Repository,
+        head: &'a str,
+        title: &'a str,
+        body: &'a str,
+    ) -> Pin<Box<dyn futures::prelude::Future<Output = anyhow::Result<GiteaPullRequest>> + Send + 'a>>
+    {
+        tracing::trace!("creating pull request on: {}, from: {}", repo, head);
+
+        Box::pin(async move {
+            self.observe(
+                "create_pull_request",
+                self.post_pull_request(repo, head, title, body),
+            )
+            .await
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct PageLinks {
+    next: Option<usize>,
+    last: Option<usize>,
+}
+
+/// Builds the path of a gitea api endpoint, percent-encoding each segment so
+/// names can't change which endpoint is called
+fn api_path<'a>(segments: impl IntoIterator<Item = &'a str>) -> String {
+    let mut url = Url::parse("http://gitea").expect("base url is valid");
+    url.path_segments_mut()
+        .expect("base url can have a path")
+        .clear()
+        .extend(["api", "v1"])
+        .extend(segments);
+
+    url.path().to_string()
+}
+
+// <https://git.front.kjuulh.io/api/v1/user/repos?page=2>; rel="next",<https://git.front.kjuulh.io/api/v1/user/repos?page=9>; rel="last"
+fn parse_link(link_str: &str) -> anyhow::Result<PageLinks> {
+    let mut links = PageLinks::default();
+
+    for link_section in link_str.split(',') {
+        let mut params = link_section.split(';').map(str::trim);
+
+        let Some(link) = params
+            .next()
+            .and_then(|l| l.strip_prefix('<'))
+            .and_then(|l| l.strip_suffix('>'))
+        else {
+            continue;
+        };
+
+        let rels = params
+            .filter_map(|p| p.strip_prefix("rel="))
+            .flat_map(|rel| rel.trim_matches('"').split_whitespace())
+            .collect::<Vec<_>>();
+
+        if !rels.contains(&"next") && !rels.contains(&"last") {
+            continue;
+        }
+
+        let url = Url::parse(link)?;
+        let Some(page_num) = url
+            .query_pairs()
+            .find(|(name, _)| name == "page")
+            .map(|(_, value)| value.parse::<usize>())
+            .transpose()?
+        else {
+            continue;
+        };
+
+        if rels.contains(&"next") {
+            links.next = Some(page_num);
+        }
+        if rels.contains(&"last") {
+            links.last = Some(page_num);
+        }
+    }
+
+    Ok(links)
+}
+
+mod extensions;
+#[cfg(test)]
+pub mod testing;
+pub mod traits;
+
+use backon::{ExponentialBuilder, Retryable};
+use base64::Engine;
+pub use extensions::*;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{StatusCode, Url};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::renovate::RENOVATE_CONFIG_FILES;
+use crate::config::Config;
+use futures::Future;
+
+use super::metrics::Metrics;
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, sync::Mutex};
+
+    use axum::{extract::Query, http::Uri, Json, Router};
+
+    use super::{testing::fake_gitea, traits::GiteaClient as _, *};
+
+    fn client(url: &str) -> DefaultGiteaClient {
+        DefaultGiteaClient::new(&testing::config(url), Metrics::new().unwrap())
+    }
+
+    /// A fake gitea answering every request with a single repository, and
+    /// recording the requested paths
+    async fn recording_gitea() -> (DefaultGiteaClient, Arc<Mutex<Vec<String>>>) {
+        let paths = Arc::new(Mutex::new(Vec::new()));
+
+        let recorded = paths.clone();
+        let router = Router::new().fallback(move |uri: Uri| {
+            let recorded = recorded.clone();
+            async move {
+                recorded.lock().unwrap().push(uri.path().to_string());
+
+                Json(serde_json::json!([{ "full_name": "someone/repo" }]))
+            }
+        });
+
+        (client(&fake_gitea(router).await), paths)
+    }
+
+    #[tokio::test]
+    async fn lists_the_requested_users_repositories() {
+        let (client, paths) = recording_gitea().await;
+
+        let repos = client.get_user_repositories("someone").await.unwrap();
+
+        assert_eq!(repos.len(), 1);
+        assert_eq!(repos[0].to_string(), "someone/repo");
+        assert_eq!(*paths.lock().unwrap(), vec!["/api/v1/users/someone/repos"]);
+    }
+
+    #[tokio::test]
+    async fn lists_the_token_owners_repositories() {
+        let (client, paths) = recording_gitea().await;
+
+        client.get_own_repositories().await.unwrap();
+
+        assert_eq!(*paths.lock().unwrap(), vec!["/api/v1/user/repos"]);
+    }
+
+    #[tokio::test]
+    async fn encodes_user_and_org_names() {
+        let (client, paths) = recording_gitea().await;
+
+        client.get_user_repositories("../admin").await.unwrap();
+        client.get_user_repositories("a b?c#d").await.unwrap();
+        client.get_org_repositories("org/../x").await.unwrap();
+
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec![
+                "/api/v1/users/..%2Fadmin/repos",
+                "/api/v1/users/a%20b%3Fc%23d/repos",
+                "/api/v1/orgs/org%2F..%2Fx/repos",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn encodes_repository_paths() {
+        let (client, paths) = recording_gitea().await;
+
+        let repo = Repository {
+            owner: "some one".into(),
+            name: "re?po".into(),
+            metadata: RepositoryMetadata {
+                default_branch: "main".into(),
+                ..Default::default()
+            },
+        };
+
+        client
+            .get_raw_file(&repo, ".gitea/renovate#1.json", None)
+            .await
+            .unwrap();
+        client
+            .branch_exists(&repo, "renovate/config ure")
+            .await
+            .unwrap();
+        let _ = client.get_pull_request(&repo, "renovate/configure").await;
+        let _ = client.get_topics(&repo).await;
+
+        assert_eq!(
+            *paths.lock().unwrap(),
+            vec![
+                "/api/v1/repos/some%20one/re%3Fpo/raw/.gitea/renovate%231.json",
+                "/api/v1/repos/some%20one/re%3Fpo/branches/renovate/config%20ure",
+                "/api/v1/repos/some%20one/re%3Fpo/pulls/main/renovate/configure",
+                "/api/v1/repos/some%20one/re%3Fpo/topics",
+            ]
+        );
+    }
+
+    #[test]
+    fn only_leaves_out_topics_gitea_didnt_report() {
+        let topics = |json: serde_json::Value| {
+            serde_json::from_value::<GiteaRepository>(json)
+                .unwrap()
+                .topics
+        };
+
+        assert_eq!(
+            topics(serde_json::json!({ "full_name": "someone/repo" })),
+            None
+        );
+        assert_eq!(
+            topics(serde_json::json!({ "full_name": "someone/repo", "topics": null })),
+            Some(vec![])
+        );
+        assert_eq!(
+            topics(serde_json::json!({ "full_name": "someone/repo", "topics": ["a"] })),
+            Some(vec!["a".to_string()])
+        );
+    }
+
+    #[test]
+    fn parses_next_and_last_links() {
+        let links = parse_link(
+            r#"<https://git.example.com/api/v1/user/repos?page=2&limit=50>; rel="next",<https://git.example.com/api/v1/user/repos?page=9&limit=50>; rel="last""#,
+        )
+        .unwrap();
+
+        assert_eq!(links.next, Some(2));
+        assert_eq!(links.last, Some(9));
+    }
+
+    #[test]
+    fn parses_a_link_without_last() {
+        let links = parse_link(r#"<https://git.example.com/api/v1/user/repos?page=3>; rel="next""#)
+            .unwrap();
+
+        assert_eq!(links.next, Some(3));
+        assert_eq!(links.last, None);
+    }
+
+    #[test]
+    fn ignores_other_relations() {
+        let links = parse_link(
+            r#"<https://git.example.com/api/v1/user/repos?page=1>; rel="first", <https://git.example.com/api/v1/user/repos?page=4>; rel="prev""#,
+        )
+        .unwrap();
+
+        assert_eq!(links, PageLinks::default());
+    }
+
+    #[test]
+    fn parses_a_link_with_several_relations() {
+        let links =
+            parse_link(r#"<https://git.example.com/api/v1/user/repos?page=2>; rel="next last""#)
+                .unwrap();
+
+        assert_eq!(links.next, Some(2));
+        assert_eq!(links.last, Some(2));
+    }
+
+    #[test]
+    fn skips_malformed_sections() {
+        let links = parse_link(
+            r#"garbage, https://git.example.com/?page=5; rel="next", <https://git.example.com/api/v1/user/repos>; rel="next", <https://git.example.com/api/v1/user/repos?page=7>; rel="last""#,
+        )
+        .unwrap();
+
+        assert_eq!(links.next, None);
+        assert_eq!(links.last, Some(7));
+
+        assert_eq!(parse_link("").unwrap(), PageLinks::default());
+    }
+
+    #[test]
+    fn fails_on_invalid_urls_and_pages() {
+        assert!(parse_link(r#"<not a url>; rel="next""#).is_err());
+        assert!(parse_link(r#"<https://git.example.com/?page=two>; rel="last""#).is_err());
+    }
+
+    /// A fake gitea serving `total` repositories over pages of [`PAGE_SIZE`],
+    /// with only `next` links when `with_last` is false
+    async fn paginated_gitea(
+        total: usize,
+        with_last: bool,
+    ) -> (DefaultGiteaClient, Arc<Mutex<Vec<usize>>>) {
+        let pages = Arc::new(Mutex::new(Vec::new()));
+
+        let requested = pages.clone();
+        let router = Router::new().fallback(
+            move |uri: Uri, Query(query): Query<HashMap<String, String>>| {
+                let requested = requested.clone();
+                async move {
+                    let page = query["page"].parse::<usize>().unwrap();
+                    requested.lock().unwrap().push(page);
+
+                    let last = total.div_ceil(PAGE_SIZE).max(1);
+                    let link = |page: usize, rel: &str| {
+                        format!(
+                            "<http://gitea{}?page={}>; rel=\"{}\"",
+                            uri.path(),
+                            page,
+                            rel
+                        )
+                    };
+                    let mut links = Vec::new();
+                    if page < last {
+                        links.push(link(page + 1, "next"));
+                    }
+                    if with_last {
+                        links.push(link(last, "last"));
+                    }
+
+                    let repos = ((page - 1) * PAGE_SIZE..(page * PAGE_SIZE).min(total))
+                        .map(|i| serde_json::json!({ "full_name": format!("someone/repo-{}", i) }))
+                        .collect::<Vec<_>>();
+
+                    (
+                        [
+                            ("link", links.join(",")),
+                            ("x-total-count", total.to_string()),
+                        ],
+                        Json(repos),
+                    )
+                }
+            },
+        );
+
+        (client(&fake_gitea(router).await), pages)
+    }
+
+    #[tokio::test]
+    async fn fetches_every_page_up_to_and_including_the_last() {
+        let (client, pages) = paginated_gitea(PAGE_SIZE * 2 + 1, true).await;
+
+        let repos = client.get_own_repositories().await.unwrap();
+
+        assert_eq!(repos.len(), PAGE_SIZE * 2 + 1);
+        assert_eq!(
+            repos.last().unwrap().name,
+            format!("repo-{}", PAGE_SIZE * 2)
+        );
+        assert_eq!(*pages.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    #[tokio::test]
+    async fn follows_next_links_without_a_last_link() {
+        let (client, pages) = paginated_gitea(PAGE_SIZE * 2 + 1, false).await;
+
+        let repos = client.get_own_repositories().await.unwrap();
+
+        assert_eq!(repos.len(), PAGE_SIZE * 2 + 1);
+        assert_eq!(*pages.lock().unwrap(), vec![1, 2, 3]);
+    }
+
+    /// The last link points at the first page
+    #[tokio::test]
+    async fn fetches_a_single_page_once() {
+        let (client, pages) = paginated_gitea(3, true).await;
+
+        let repos = client.get_own_repositories().await.unwrap();
+
+        assert_eq!(repos.len(), 3);
+        assert_eq!(*pages.lock().unwrap(), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn only_matches_contractors_own_webhook() {
+        let hooks = |urls: &[&str]| {
+            urls.iter()
+                .enumerate()
+                .map(|(id, url)| serde_json::json!({ "id": id, "type": "gitea", "config": { "url": url } }))
+                .collect::<Vec<_>>()
+        };
+        let own = "https://contractor.example.com/webhooks/gitea?type=contractor";
+
+        for (urls, expected) in [
+            (vec![own], Some(0)),
+            (vec!["https://ci.example.com/hook", own], Some(1)),
+            (
+                vec![
+                    "https://contractor-staging.example.com/webhooks/gitea?type=contractor",
+                    "https://evil.example.com/?contractor",
+                    "https://contractor.example.com/webhooks/gitea",
+                ],
+                None,
+            ),
+            (vec![], None),
+        ] {
+            let body = hooks(&urls);
+            let router = Router::new().fallback(move || {
+                let body = body.clone();
+                async move { Json(body) }
+            });
+            let client = client(&fake_gitea(router).await);
+
+            let repo = Repository {
+                owner: "someone".into(),
+                name: "repo".into(),
+                metadata: RepositoryMetadata::default(),
+            };
+            let webhook = client.get_webhook(&repo).await.unwrap();
+
+            assert_eq!(webhook.map(|w| w.id), expected, "{:?}", urls);
+        }
+    }
+
+    #[tokio::test]
+    async fn skips_other_kinds_of_webhooks() {
+        let own = "https://contractor.example.com/webhooks/gitea?type=contractor";
+        let body = serde_json::json!([
+            { "id": 1, "type": "slack", "config": { "url": own } },
+            { "id": 2, "type": "discord", "config": { "url": "https://discord.example.com" } },
+            { "id": 3, "type": "gitea", "config": { "url": "https://ci.example.com/hook" } },
+            { "id": 4, "type": "gitea", "config": { "url": own } },
+            { "id": 5, "type": "msteams", "config": { "url": own } },
+        ]);
+        let router = Router::new().fallback(move || {
+            let body = body.clone();
+            async move { Json(body) }
+        });
+        let client = client(&fake_gitea(router).await);
+
+        let repo = Repository {
+            owner: "someone".into(),
+            name: "repo".into(),
+            metadata: RepositoryMetadata::default(),
+        };
+        let webhook = client.get_webhook(&repo).await.unwrap();
+
+        assert_eq!(webhook.map(|w| w.id), Some(4));
+    }
+}
@@ -3,9 +3,11 @@ use crate::SharedState;
 use super::GiteaClient;
 
 pub trait GiteaClientState {
+    fn gitea_client(&self) -> GiteaClient;
+}
+
+impl GiteaClientState for SharedState {
     fn gitea_client(&self) -> GiteaClient {
-        GiteaClient::new()
+        GiteaClient::new(&self.config, self.metrics.clone())
     }
 }
-
-impl GiteaClientState for SharedState {}
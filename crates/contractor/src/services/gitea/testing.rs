@@ -0,0 +1,145 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{Arc, Mutex},
+};
+
+use axum::{
+    http::{Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+    Json, Router,
+};
+
+use crate::config::Config;
+
+/// Serves `router` as a fake gitea, returns its url
+pub async fn fake_gitea(router: Router) -> String {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+
+    tokio::spawn(async move { axum::serve(listener, router).await.unwrap() });
+
+    format!("http://{}", addr)
+}
+
+/// The config of a contractor using the gitea at `url`
+pub fn config(url: &str) -> Config {
+    let mut config = Config::default();
+    config.gitea.url = url.into();
+    config.webhook.url = "https://contractor.example.com".into();
+
+    config
+}
+
+/// A fake gitea serving fixed repositories, files, topics, webhooks and pull
+/// requests. Repositories are keyed by `owner/name`, files and pull requests
+/// by `owner/name/path` and `owner/name/head`. Every request is recorded as
+/// `METHOD path`, requests in `failing` are answered with a 500 and other
+/// writes are accepted without changing anything.
+#[derive(Clone, Default)]
+pub struct FakeGitea {
+    pub repos: Vec<serde_json::Value>,
+    pub files: HashMap<String, String>,
+    pub topics: HashMap<String, Vec<String>>,
+    pub hooks: HashMap<String, Vec<serde_json::Value>>,
+    pub pulls: HashMap<String, serde_json::Value>,
+    pub failing: HashSet<String>,
+}
+
+pub type Requests = Arc<Mutex<Vec<String>>>;
+
+impl FakeGitea {
+    /// Serves the fake, returns its url and the requests it received
+    pub async fn serve(self) -> (String, Requests) {
+        let requests = Requests::default();
+
+        let recorded = requests.clone();
+        let router = Router::new().fallback(move |method: Method, uri: Uri| {
+            let gitea = self.clone();
+            let recorded = recorded.clone();
+            async move {
+                let request = format!("{} {}", method, uri.path());
+                recorded.lock().unwrap().push(request.clone());
+
+                if gitea.failing.contains(&request) {
+                    return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+                }
+
+                gitea.respond(&method, uri.path())
+            }
+        });
+
+        (fake_gitea(router).await, requests)
+    }
+
+    fn respond(&self, method: &Method, path: &str) -> Response {
+        let segments = path
+            .trim_start_matches("/api/v1/")
+            .split('/')
+            .collect::<Vec<_>>();
+
+        let found = |value: Option<&serde_json::Value>| match value {
+            Some(value) => Json(value.clone()).into_response(),
+            None => StatusCode::NOT_FOUND.into_response(),
+        };
+
+        match (method.as_str(), segments.as_slice()) {
+            ("GET", ["user"]) => Json(serde_json::json!({ "login": "contractor" })).into_response(),
+            ("GET", ["user", "repos"] | ["users", _, "repos"] | ["orgs", _, "repos"]) => {
+                Json(&self.repos).into_response()
+            }
+            ("GET", ["repos", owner, name]) => found(
+                self.repos
+                    .iter()
+                    .find(|r| r["full_name"] == format!("{}/{}", owner, name)),
+            ),
+            ("GET", ["repos", owner, name, "raw", file @ ..]) => {
+                match self.files.get(&format!("{}/{}/{}", owner, name, file.join("/"))) {
+                    Some(content) => content.clone().into_response(),
+                    None => StatusCode::NOT_FOUND.into_response(),
+                }
+            }
+            ("GET", ["repos", owner, name, "topics"]) => Json(serde_json::json!({
+                "topics": self.topics.get(&format!("{}/{}", owner, name)).cloned().unwrap_or_default()
+            }))
+            .into_response(),
+            ("GET", ["repos", owner, name, "hooks"]) => Json(
+                self.hooks
+                    .get(&format!("{}/{}", owner, name))
+                    .cloned()
+                    .unwrap_or_default(),
+            )
+            .into_response(),
+            ("GET", ["repos", owner, name, "pulls", _base, head @ ..]) => {
+                found(self.pulls.get(&format!("{}/{}/{}", owner, name, head.join("/"))))
+            }
+            ("POST", ["repos", _, _, "pulls"]) => Json(serde_json::json!({
+                "number": 1,
+                "state": "open",
+                "merged": false,
+            }))
+            .into_response(),
+            ("GET", _) => StatusCode::NOT_FOUND.into_response(),
+            _ => Json(serde_json::json!({})).into_response(),
+        }
+    }
+}
+
+/// The webhook contractor installs when configured by [`config`]
+pub fn contractor_hook(id: usize) -> serde_json::Value {
+    serde_json::json!({
+        "id": id,
+        "type": "gitea",
+        "config": { "url": "https://contractor.example.com/webhooks/gitea?type=contractor" },
+    })
+}
+
+/// The requests which change something in gitea
+pub fn writes(requests: &Requests) -> Vec<String> {
+    requests
+        .lock()
+        .unwrap()
+        .iter()
+        .filter(|r| !r.starts_with("GET "))
+        .cloned()
+        .collect()
+}
@@ -2,12 +2,23 @@ use std::pin::Pin;
 
 use futures::Future;
 
-use super::Repository;
+use super::{GiteaPullRequest, GiteaWebhook, Repository};
 
 pub trait GiteaClient {
+    /// Login of the owner of the configured token, fails fast without retrying
+    /// so it can be used to check connectivity
+    fn get_current_user<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<String>> + Send + 'a>>;
+
     fn get_user_repositories<'a>(
         &'a self,
-        user: &str,
+        user: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>;
+
+    /// Repositories of the owner of the configured token
+    fn get_own_repositories<'a>(
+        &'a self,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>;
 
     fn get_org_repositories<'a>(
@@ -15,14 +26,95 @@ pub trait GiteaClient {
         org: &'a str,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Repository>>> + Send + 'a>>;
 
+    /// `repo` as gitea has it now, with its current metadata. `None` if it
+    /// doesn't exist anymore
+    fn get_repository<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Repository>>> + Send + 'a>>;
+
+    /// The renovate config file found in `repo`, `None` when renovate isn't
+    /// enabled
     fn renovate_enabled<'a>(
         &'a self,
         repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<String>>> + Send + 'a>>;
+
+    /// The content of `path` at `git_ref` of `repo`, on the default branch
+    /// when `git_ref` is `None`. `None` if it doesn't exist
+    fn get_raw_file<'a>(
+        &'a self,
+        repo: &'a Repository,
+        path: &'a str,
+        git_ref: Option<&'a str>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<String>>> + Send + 'a>>;
+
+    fn get_topics<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<String>>> + Send + 'a>>;
+
+    fn add_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn update_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+        webhook: &'a GiteaWebhook,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    /// The contractor webhook installed on `repo`, if any
+    fn get_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<GiteaWebhook>>> + Send + 'a>>;
+
+    fn delete_webhook<'a>(
+        &'a self,
+        repo: &'a Repository,
+        webhook: &'a GiteaWebhook,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    fn create_issue_comment<'a>(
+        &'a self,
+        repo: &'a Repository,
+        issue: u64,
+        body: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    /// The latest pull request from `head` into the default branch of `repo`,
+    /// in any state
+    fn get_pull_request<'a>(
+        &'a self,
+        repo: &'a Repository,
+        head: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<GiteaPullRequest>>> + Send + 'a>>;
+
+    fn branch_exists<'a>(
+        &'a self,
+        repo: &'a Repository,
+        branch: &'a str,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>>;
 
-    fn ensure_webhook<'a>(
+    /// Commits a new file at `path` to `new_branch`, which is created from the
+    /// default branch of `repo`
+    fn create_file<'a>(
         &'a self,
         repo: &'a Repository,
-        force_refresh: bool,
+        new_branch: &'a str,
+        path: &'a str,
+        content: &'a str,
+        message: &'a str,
     ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+    /// Opens a pull request from `head` into the default branch of `repo`
+    fn create_pull_request<'a>(
+        &'a self,
+        repo: &'a Repository,
+        head: &'a str,
+        title: &'a str,
+        body: &'a str,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<GiteaPullRequest>> + Send + 'a>>;
 }
@@ -0,0 +1,258 @@
+use std::collections::HashMap;
+
+use serde::Deserialize;
+
+use crate::config::RenovateRunnerConfig;
+
+use super::gitea::{GiteaClient, Repository};
+
+/// Repositories can choose a version of their renovate image in this file.
+/// It isn't part of the renovate config as renovate rejects unknown keys.
+pub const REPOSITORY_CONFIG_FILE: &str = ".contractor.json";
+
+/// The contents of [`REPOSITORY_CONFIG_FILE`]
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+struct RepositoryConfig {
+    renovate: RepositoryRenovateConfig,
+}
+
+/// Repositories can only choose another tag or digest of the configured image,
+/// as the image runs with the renovate credentials
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct RepositoryRenovateConfig {
+    version: Option<String>,
+    digest: Option<String>,
+}
+
+impl RepositoryRenovateConfig {
+    /// `image` with the chosen tag or digest, `None` if neither was chosen
+    fn apply_to(self, image: &str) -> anyhow::Result<Option<String>> {
+        match (self.version, self.digest) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("only one of renovate.version and renovate.digest can be set")
+            }
+            (Some(version), None) => {
+                validate_tag(&version)?;
+                Ok(Some(image_with_tag(image, &version)))
+            }
+            (None, Some(digest)) => {
+                validate_digest(&digest)?;
+                Ok(Some(image_with_digest(image, &digest)))
+            }
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Picks the renovate image of a run. The image of the repository's owner is
+/// used, falling back to the global image. The repository's `.contractor.json`
+/// can pin another tag or digest of it, and a version requested for the run
+/// replaces the tag once more.
+#[derive(Clone)]
+pub struct RenovateImages {
+    gitea_client: GiteaClient,
+    image: String,
+    images: HashMap<String, String>,
+}
+
+impl RenovateImages {
+    pub fn new(gitea_client: GiteaClient, config: &RenovateRunnerConfig) -> Self {
+        Self {
+            gitea_client,
+            image: config.image.clone(),
+            images: config.images.clone(),
+        }
+    }
+
+    /// The image to run renovate with for `repo`, `version` replaces the tag of
+    /// the image otherwise chosen
+    pub async fn resolve(
+        &self,
+        repo: &Repository,
+        version: Option<&str>,
+    ) -> anyhow::Result<String> {
+        let image = self.images.get(&repo.owner).unwrap_or(&self.image);
+        let image = match self.repository_image(repo, image).await? {
+            Some(image) => image,
+            None => image.clone(),
+        };
+
+        match version {
+            Some(version) => {
+                validate_tag(version)?;
+                Ok(image_with_tag(&image, version))
+            }
+            None => Ok(image),
+        }
+    }
+
+    /// `image` with the tag or digest `repo` chose, `None` if it didn't choose
+    async fn repository_image(
+        &self,
+        repo: &Repository,
+        image: &str,
+    ) -> anyhow::Result<Option<String>> {
+        let Some(content) = self
+            .gitea_client
+            .get_raw_file(repo, REPOSITORY_CONFIG_FILE, None)
+            .await?
+        else {
+            return Ok(None);
+        };
+
+        serde_json::from_str::<RepositoryConfig>(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(|config| config.renovate.apply_to(image))
+            .map_err(|e| {
+                anyhow::anyhow!("{} of {} is invalid: {}", REPOSITORY_CONFIG_FILE, repo, e)
+            })
+    }
+}
+
+/// Checks `image` looks like an image reference, e.g. `renovate/renovate:37`
+/// or `renovate/renovate@sha256:<digest>`
+pub fn validate_image(image: &str) -> anyhow::Result<()> {
+    if image.is_empty() || image.contains(char::is_whitespace) {
+        anyhow::bail!("image: {:?} is not a valid image reference", image);
+    }
+
+    if let Some((name, digest)) = image.split_once('@') {
+        if name.is_empty() || validate_digest(digest).is_err() {
+            anyhow::bail!(
+                "image: {} should be pinned as name@sha256:<64 hex characters>",
+                image
+            );
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks `digest` is a `sha256:<64 hex characters>` image digest
+pub fn validate_digest(digest: &str) -> anyhow::Result<()> {
+    let hex = digest.strip_prefix("sha256:").unwrap_or_default();
+
+    if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        anyhow::bail!("{:?} should be sha256:<64 hex characters>", digest);
+    }
+
+    Ok(())
+}
+
+/// Checks `tag` is a valid image tag
+pub fn validate_tag(tag: &str) -> anyhow::Result<()> {
+    let valid = !tag.is_empty()
+        && tag.len() <= 128
+        && !tag.starts_with(['.', '-'])
+        && tag
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-'));
+
+    if !valid {
+        anyhow::bail!("{:?} is not a valid renovate version", tag);
+    }
+
+    Ok(())
+}
+
+/// `image` with its tag and digest replaced by `tag`
+pub fn image_with_tag(image: &str, tag: &str) -> String {
+    format!("{}:{}", image_name(image), tag)
+}
+
+/// `image` with its tag and digest replaced by `digest`
+pub fn image_with_digest(image: &str, digest: &str) -> String {
+    format!("{}@{}", image_name(image), digest)
+}
+
+/// `image` without its tag and digest
+fn image_name(image: &str) -> &str {
+    let name = image.split_once('@').map(|(name, _)| name).unwrap_or(image);
+
+    // Only a colon in the last path segment starts the tag, an earlier one
+    // belongs to the registry port
+    let last_segment = name.rfind('/').map(|i| i + 1).unwrap_or_default();
+    match name[last_segment..].rfind(':') {
+        Some(i) => &name[..last_segment + i],
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn tags_and_digests_replace_each_other() {
+        assert_eq!(
+            image_with_tag("renovate/renovate:37", "38"),
+            "renovate/renovate:38"
+        );
+        assert_eq!(
+            image_with_tag(&format!("renovate/renovate@{}", DIGEST), "38"),
+            "renovate/renovate:38"
+        );
+        assert_eq!(
+            image_with_tag("registry.local:5000/renovate", "38"),
+            "registry.local:5000/renovate:38"
+        );
+        assert_eq!(
+            image_with_digest("registry.local:5000/renovate:37", DIGEST),
+            format!("registry.local:5000/renovate@{}", DIGEST)
+        );
+    }
+
+    #[test]
+    fn repository_config_only_accepts_a_version_or_digest() {
+        let parse = |config: &str| serde_json::from_str::<RepositoryConfig>(config).unwrap();
+
+        assert_eq!(
+            parse(r#"{ "renovate": { "version": "38" } }"#)
+                .renovate
+                .apply_to("renovate/renovate:37")
+                .unwrap()
+                .as_deref(),
+            Some("renovate/renovate:38")
+        );
+        assert_eq!(
+            parse(&format!(
+                r#"{{ "renovate": {{ "digest": "{}" }} }}"#,
+                DIGEST
+            ))
+            .renovate
+            .apply_to("renovate/renovate:37")
+            .unwrap(),
+            Some(format!("renovate/renovate@{}", DIGEST))
+        );
+        assert_eq!(
+            parse("{}")
+                .renovate
+                .apply_to("renovate/renovate:37")
+                .unwrap(),
+            None
+        );
+        assert!(parse(&format!(
+            r#"{{ "renovate": {{ "version": "38", "digest": "{}" }} }}"#,
+            DIGEST
+        ))
+        .renovate
+        .apply_to("renovate/renovate:37")
+        .is_err());
+
+        assert!(serde_json::from_str::<RepositoryConfig>(
+            r#"{ "renovate": { "image": "evil/renovate:latest" } }"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn digests_are_validated() {
+        assert!(validate_digest(DIGEST).is_ok());
+        assert!(validate_digest("sha256:abc").is_err());
+        assert!(validate_digest(&DIGEST.replace("sha256", "md5")).is_err());
+    }
+}
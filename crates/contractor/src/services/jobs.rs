@@ -0,0 +1,851 @@
+use std::{collections::HashMap, fmt::Display, pin::Pin, str::FromStr, sync::Arc};
+
+use futures::Future;
+use itertools::Itertools;
+use serde::Serialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use super::{
+    gitea::Repository,
+    renovate::{LogEntry, RenovateFailed, RenovateRun, RenovateRunSummary},
+};
+
+/// Output is truncated to the tail of the log, as that is where renovate
+/// reports its result.
+const MAX_OUTPUT_BYTES: usize = 64 * 1024;
+
+type DynJobStore = Arc<dyn traits::JobStore + Send + Sync + 'static>;
+
+#[derive(Clone)]
+pub struct JobStore(DynJobStore);
+
+impl JobStore {
+    pub fn postgres(db: PgPool) -> Self {
+        Self(Arc::new(PostgresJobStore { db }))
+    }
+
+    pub fn in_memory() -> Self {
+        Self(Arc::new(InMemoryJobStore::default()))
+    }
+
+    /// Records `run` as a job along with its logs, recording failures are
+    /// logged but never stop the run itself.
+    pub async fn track<F>(&self, job: NewJob, run: F) -> anyhow::Result<RenovateRun>
+    where
+        F: Future<Output = anyhow::Result<RenovateRun>>,
+    {
+        let id = match self.start_job(&job).await {
+            Ok(id) => Some(id),
+            Err(e) => {
+                tracing::warn!("failed to record job start for: {}, error: {}", job.repo, e);
+                None
+            }
+        };
+
+        let result = run.await;
+
+        if let Some(id) = id {
+            // Failed renovate runs still carry their logs
+            let (status, run, output) = match &result {
+                Ok(run) => (JobStatus::Succeeded, Some(run), run.log.to_text()),
+                Err(e) => match e.downcast_ref::<RenovateFailed>() {
+                    Some(failed) => (
+                        JobStatus::Failed,
+                        Some(&failed.run),
+                        format!("{}\n{}", failed.run.log.to_text(), e),
+                    ),
+                    None => (JobStatus::Failed, None, e.to_string()),
+                },
+            };
+            let summary = run.map(|r| &r.summary);
+            let exit_code = run.map(|r| r.exit_code);
+
+            if let Some(run) = run {
+                if let Err(e) = self.insert_logs(id, &run.log.entries).await {
+                    tracing::warn!("failed to record job logs for: {}, error: {}", job.repo, e);
+                }
+            }
+
+            if let Err(e) = self
+                .finish_job(id, status, truncate_output(&output), summary, exit_code)
+                .await
+            {
+                tracing::warn!(
+                    "failed to record job result for: {}, error: {}",
+                    job.repo,
+                    e
+                );
+            }
+        }
+
+        result
+    }
+}
+
+impl std::ops::Deref for JobStore {
+    type Target = DynJobStore;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobTrigger {
+    BotComment,
+    Schedule,
+    /// `reconcile --run`
+    Cli,
+    Push,
+}
+
+impl Display for JobTrigger {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JobTrigger::BotComment => "bot_comment",
+            JobTrigger::Schedule => "schedule",
+            JobTrigger::Cli => "cli",
+            JobTrigger::Push => "push",
+        })
+    }
+}
+
+impl FromStr for JobTrigger {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bot_comment" => Ok(JobTrigger::BotComment),
+            "schedule" => Ok(JobTrigger::Schedule),
+            "cli" => Ok(JobTrigger::Cli),
+            "push" => Ok(JobTrigger::Push),
+            _ => anyhow::bail!("{} is not a valid job trigger", s),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl Display for JobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            JobStatus::Running => "running",
+            JobStatus::Succeeded => "succeeded",
+            JobStatus::Failed => "failed",
+        })
+    }
+}
+
+impl FromStr for JobStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "running" => Ok(JobStatus::Running),
+            "succeeded" => Ok(JobStatus::Succeeded),
+            "failed" => Ok(JobStatus::Failed),
+            _ => anyhow::bail!("{} is not a valid job status", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct NewJob {
+    pub repo: Repository,
+    pub trigger: JobTrigger,
+    pub requester: Option<String>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Job {
+    pub id: Uuid,
+    pub repository: String,
+    pub trigger: JobTrigger,
+    pub requester: Option<String>,
+    pub status: JobStatus,
+    pub output: Option<String>,
+    pub summary: Option<RenovateRunSummary>,
+    /// `None` when renovate didn't get to run
+    pub exit_code: Option<i64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub started_at: OffsetDateTime,
+    #[serde(with = "time::serde::rfc3339::option")]
+    pub finished_at: Option<OffsetDateTime>,
+}
+
+#[derive(sqlx::FromRow)]
+struct JobRow {
+    id: Uuid,
+    repository: String,
+    triggered_by: String,
+    requester: Option<String>,
+    status: String,
+    output: Option<String>,
+    summary: Option<String>,
+    exit_code: Option<i64>,
+    started_at: OffsetDateTime,
+    finished_at: Option<OffsetDateTime>,
+}
+
+impl TryFrom<JobRow> for Job {
+    type Error = anyhow::Error;
+
+    fn try_from(value: JobRow) -> Result<Self, Self::Error> {
+        Ok(Job {
+            id: value.id,
+            repository: value.repository,
+            trigger: value.triggered_by.parse()?,
+            requester: value.requester,
+            status: value.status.parse()?,
+            output: value.output,
+            summary: value
+                .summary
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()?,
+            exit_code: value.exit_code,
+            started_at: value.started_at,
+            finished_at: value.finished_at,
+        })
+    }
+}
+
+#[derive(sqlx::FromRow)]
+struct LogRow {
+    stream: String,
+    level: Option<String>,
+    time: Option<String>,
+    message: String,
+    fields: Option<String>,
+}
+
+impl TryFrom<LogRow> for LogEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(value: LogRow) -> Result<Self, Self::Error> {
+        Ok(LogEntry {
+            stream: value.stream.parse()?,
+            level: value.level,
+            time: value.time,
+            message: value.message,
+            fields: value
+                .fields
+                .as_deref()
+                .map(serde_json::from_str)
+                .transpose()?,
+        })
+    }
+}
+
+fn truncate_output(output: &str) -> &str {
+    if output.len() <= MAX_OUTPUT_BYTES {
+        return output;
+    }
+
+    let mut start = output.len() - MAX_OUTPUT_BYTES;
+    while !output.is_char_boundary(start) {
+        start += 1;
+    }
+
+    &output[start..]
+}
+
+pub struct PostgresJobStore {
+    db: PgPool,
+}
+
+impl traits::JobStore for PostgresJobStore {
+    fn start_job<'a>(
+        &'a self,
+        job: &'a NewJob,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Uuid>> + Send + 'a>> {
+        Box::pin(async move {
+            let id = Uuid::new_v4();
+
+            sqlx::query(
+                r#"
+                INSERT INTO jobs (id, repository, triggered_by, requester, status)
+                VALUES ($1, $2, $3, $4, $5)
+                "#,
+            )
+            .bind(id)
+            .bind(job.repo.to_string())
+            .bind(job.trigger.to_string())
+            .bind(&job.requester)
+            .bind(JobStatus::Running.to_string())
+            .execute(&self.db)
+            .await?;
+
+            Ok(id)
+        })
+    }
+
+    fn finish_job<'a>(
+        &'a self,
+        id: Uuid,
+        status: JobStatus,
+        output: &'a str,
+        summary: Option<&'a RenovateRunSummary>,
+        exit_code: Option<i64>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let summary = summary.map(serde_json::to_string).transpose()?;
+
+            sqlx::query(
+                r#"
+                UPDATE jobs
+                SET status = $2, output = $3, summary = $4::JSONB, exit_code = $5, finished_at = now()
+                WHERE id = $1
+                "#,
+            )
+            .bind(id)
+            .bind(status.to_string())
+            .bind(output)
+            .bind(summary)
+            .bind(exit_code)
+            .execute(&self.db)
+            .await?;
+
+            Ok(())
+        })
+    }
+
+    fn get_job<'a>(
+        &'a self,
+        id: Uuid,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Job>>> + Send + 'a>> {
+        Box::pin(async move {
+            let row = sqlx::query_as::<_, JobRow>(
+                r#"
+                SELECT id, repository, triggered_by, requester, status, output, summary::TEXT AS summary, exit_code, started_at, finished_at
+                FROM jobs
+                WHERE id = $1
+                "#,
+            )
+            .bind(id)
+            .fetch_optional(&self.db)
+            .await?;
+
+            row.map(Job::try_from).transpose()
+        })
+    }
+
+    fn list_jobs<'a>(
+        &'a self,
+        repository: Option<&'a str>,
+        limit: i64,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Job>>> + Send + 'a>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<_, JobRow>(
+                r#"
+                SELECT id, repository, triggered_by, requester, status, output, summary::TEXT AS summary, exit_code, started_at, finished_at
+                FROM jobs
+                WHERE $1::TEXT IS NULL OR repository = $1
+                ORDER BY started_at DESC
+                LIMIT $2
+                "#,
+            )
+            .bind(repository)
+            .bind(limit)
+            .fetch_all(&self.db)
+            .await?;
+
+            rows.into_iter().map(Job::try_from).collect()
+        })
+    }
+
+    fn insert_logs<'a>(
+        &'a self,
+        id: Uuid,
+        entries: &'a [LogEntry],
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut seqs = Vec::with_capacity(entries.len());
+            let mut streams = Vec::with_capacity(entries.len());
+            let mut levels = Vec::with_capacity(entries.len());
+            let mut times = Vec::with_capacity(entries.len());
+            let mut messages = Vec::with_capacity(entries.len());
+            let mut fields = Vec::with_capacity(entries.len());
+
+            for (seq, entry) in entries.iter().enumerate() {
+                seqs.push(seq as i64);
+                streams.push(entry.stream.to_string());
+                levels.push(entry.level.clone());
+                times.push(entry.time.clone());
+                messages.push(entry.message.clone());
+                fields.push(entry.fields.as_ref().map(|f| f.to_string()));
+            }
+
+            sqlx::query(
+                r#"
+                INSERT INTO job_logs (job_id, seq, stream, level, time, message, fields)
+                SELECT $1, seq, stream, level, time, message, fields::JSONB
+                FROM UNNEST($2::INT8[], $3::TEXT[], $4::TEXT[], $5::TEXT[], $6::TEXT[], $7::TEXT[])
+                    AS l (seq, stream, level, time, message, fields)
+                "#,
+            )
+            .bind(id)
+            .bind(seqs)
+            .bind(streams)
+            .bind(levels)
+            .bind(times)
+            .bind(messages)
+            .bind(fields)
+            .execute(&self.db)
+            .await?;
+
+            Ok(())
+        })
+    }
+
+    fn get_logs<'a>(
+        &'a self,
+        id: Uuid,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<LogEntry>>> + Send + 'a>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<_, LogRow>(
+                r#"
+                SELECT stream, level, time, message, fields::TEXT AS fields
+                FROM job_logs
+                WHERE job_id = $1
+                ORDER BY seq
+                "#,
+            )
+            .bind(id)
+            .fetch_all(&self.db)
+            .await?;
+
+            rows.into_iter().map(LogEntry::try_from).collect()
+        })
+    }
+
+    fn delete_logs_before<'a>(
+        &'a self,
+        before: OffsetDateTime,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<u64>> + Send + 'a>> {
+        Box::pin(async move {
+            let result = sqlx::query(
+                r#"
+                DELETE FROM job_logs
+                WHERE job_id IN (SELECT id FROM jobs WHERE started_at < $1)
+                "#,
+            )
+            .bind(before)
+            .execute(&self.db)
+            .await?;
+
+            sqlx::query(
+                r#"
+                UPDATE jobs
+                SET output = NULL
+                WHERE started_at < $1 AND output IS NOT NULL
+                "#,
+            )
+            .bind(before)
+            .execute(&self.db)
+            .await?;
+
+            Ok(result.rows_affected())
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryJobStore {
+    jobs: Mutex<HashMap<Uuid, Job>>,
+    logs: Mutex<HashMap<Uuid, Vec<LogEntry>>>,
+}
+
+impl traits::JobStore for InMemoryJobStore {
+    fn start_job<'a>(
+        &'a self,
+        job: &'a NewJob,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Uuid>> + Send + 'a>> {
+        Box::pin(async move {
+            let id = Uuid::new_v4();
+
+            self.jobs.lock().await.insert(
+                id,
+                Job {
+                    id,
+                    repository: job.repo.to_string(),
+                    trigger: job.trigger,
+                    requester: job.requester.clone(),
+                    status: JobStatus::Running,
+                    output: None,
+                    summary: None,
+                    exit_code: None,
+                    started_at: OffsetDateTime::now_utc(),
+                    finished_at: None,
+                },
+            );
+
+            Ok(id)
+        })
+    }
+
+    fn finish_job<'a>(
+        &'a self,
+        id: Uuid,
+        status: JobStatus,
+        output: &'a str,
+        summary: Option<&'a RenovateRunSummary>,
+        exit_code: Option<i64>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut jobs = self.jobs.lock().await;
+            let job = jobs
+                .get_mut(&id)
+                .ok_or(anyhow::anyhow!("job: {} was not found", id))?;
+
+            job.status = status;
+            job.output = Some(output.into());
+            job.summary = summary.cloned();
+            job.exit_code = exit_code;
+            job.finished_at = Some(OffsetDateTime::now_utc());
+
+            Ok(())
+        })
+    }
+
+    fn get_job<'a>(
+        &'a self,
+        id: Uuid,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Job>>> + Send + 'a>> {
+        Box::pin(async move { Ok(self.jobs.lock().await.get(&id).cloned()) })
+    }
+
+    fn list_jobs<'a>(
+        &'a self,
+        repository: Option<&'a str>,
+        limit: i64,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Job>>> + Send + 'a>> {
+        Box::pin(async move {
+            let jobs = self.jobs.lock().await;
+
+            Ok(jobs
+                .values()
+                .filter(|j| repository.map(|r| j.repository == r).unwrap_or(true))
+                .sorted_by(|a, b| b.started_at.cmp(&a.started_at))
+                .take(limit.max(0) as usize)
+                .cloned()
+                .collect())
+        })
+    }
+
+    fn insert_logs<'a>(
+        &'a self,
+        id: Uuid,
+        entries: &'a [LogEntry],
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            self.logs
+                .lock()
+                .await
+                .entry(id)
+                .or_default()
+                .extend_from_slice(entries);
+
+            Ok(())
+        })
+    }
+
+    fn get_logs<'a>(
+        &'a self,
+        id: Uuid,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<LogEntry>>> + Send + 'a>> {
+        Box::pin(async move { Ok(self.logs.lock().await.get(&id).cloned().unwrap_or_default()) })
+    }
+
+    fn delete_logs_before<'a>(
+        &'a self,
+        before: OffsetDateTime,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<u64>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut jobs = self.jobs.lock().await;
+            let mut logs = self.logs.lock().await;
+
+            for job in jobs.values_mut().filter(|j| j.started_at < before) {
+                job.output = None;
+            }
+
+            let mut deleted = 0;
+            logs.retain(|id, entries| {
+                let expired = jobs.get(id).map(|j| j.started_at < before).unwrap_or(true);
+                if expired {
+                    deleted += entries.len() as u64;
+                }
+
+                !expired
+            });
+
+            Ok(deleted)
+        })
+    }
+}
+
+pub mod traits {
+    use std::pin::Pin;
+
+    use futures::Future;
+    use time::OffsetDateTime;
+    use uuid::Uuid;
+
+    use super::{Job, JobStatus, LogEntry, NewJob, RenovateRunSummary};
+
+    pub trait JobStore {
+        fn start_job<'a>(
+            &'a self,
+            job: &'a NewJob,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Uuid>> + Send + 'a>>;
+
+        fn finish_job<'a>(
+            &'a self,
+            id: Uuid,
+            status: JobStatus,
+            output: &'a str,
+            summary: Option<&'a RenovateRunSummary>,
+            exit_code: Option<i64>,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+        fn get_job<'a>(
+            &'a self,
+            id: Uuid,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Job>>> + Send + 'a>>;
+
+        fn list_jobs<'a>(
+            &'a self,
+            repository: Option<&'a str>,
+            limit: i64,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Job>>> + Send + 'a>>;
+
+        fn insert_logs<'a>(
+            &'a self,
+            id: Uuid,
+            entries: &'a [LogEntry],
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+        /// The logs of job `id` in the order they were written
+        fn get_logs<'a>(
+            &'a self,
+            id: Uuid,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<LogEntry>>> + Send + 'a>>;
+
+        /// Deletes the logs and output of jobs started before `before`,
+        /// returns the number of deleted log entries
+        fn delete_logs_before<'a>(
+            &'a self,
+            before: OffsetDateTime,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<u64>> + Send + 'a>>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::services::{
+        gitea::RepositoryMetadata,
+        renovate::{LogStream, RenovateLog},
+    };
+
+    use super::*;
+
+    fn new_job(repo: &str) -> NewJob {
+        let (owner, name) = repo.split_once('/').unwrap();
+
+        NewJob {
+            repo: Repository {
+                owner: owner.into(),
+                name: name.into(),
+                metadata: RepositoryMetadata::default(),
+            },
+            trigger: JobTrigger::Schedule,
+            requester: None,
+        }
+    }
+
+    fn run(output: &str, exit_code: i64) -> RenovateRun {
+        RenovateRun::new(RenovateLog::parse(output), exit_code)
+    }
+
+    #[test]
+    fn triggers_round_trip_through_their_names() {
+        for trigger in [
+            JobTrigger::BotComment,
+            JobTrigger::Schedule,
+            JobTrigger::Cli,
+            JobTrigger::Push,
+        ] {
+            assert_eq!(trigger.to_string().parse::<JobTrigger>().unwrap(), trigger);
+            assert_eq!(
+                serde_json::to_value(trigger).unwrap(),
+                serde_json::Value::String(trigger.to_string())
+            );
+        }
+
+        assert!("manual".parse::<JobTrigger>().is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_records_jobs() {
+        let store = JobStore::in_memory();
+
+        let first = store.start_job(&new_job("foo/a")).await.unwrap();
+        let second = store.start_job(&new_job("foo/b")).await.unwrap();
+
+        let job = store.get_job(first).await.unwrap().unwrap();
+        assert_eq!(job.repository, "foo/a");
+        assert_eq!(job.status, JobStatus::Running);
+        assert_eq!(job.finished_at, None);
+
+        store
+            .finish_job(second, JobStatus::Failed, "boom", None, Some(1))
+            .await
+            .unwrap();
+
+        let job = store.get_job(second).await.unwrap().unwrap();
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.output.as_deref(), Some("boom"));
+        assert_eq!(job.exit_code, Some(1));
+        assert!(job.finished_at.is_some());
+
+        assert_eq!(store.list_jobs(None, 10).await.unwrap().len(), 2);
+        assert_eq!(store.list_jobs(None, 1).await.unwrap().len(), 1);
+        let jobs = store.list_jobs(Some("foo/b"), 10).await.unwrap();
+        assert_eq!(jobs.iter().map(|j| j.id).collect::<Vec<_>>(), vec![second]);
+
+        assert!(store.get_job(Uuid::new_v4()).await.unwrap().is_none());
+        assert!(store
+            .finish_job(Uuid::new_v4(), JobStatus::Succeeded, "", None, None)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_expires_logs_and_output() {
+        let store = JobStore::in_memory();
+        let id = store.start_job(&new_job("foo/a")).await.unwrap();
+        let entries = RenovateLog::parse("one\ntwo").entries;
+
+        store.insert_logs(id, &entries).await.unwrap();
+        store
+            .finish_job(id, JobStatus::Succeeded, "one\ntwo", None, Some(0))
+            .await
+            .unwrap();
+        assert_eq!(store.get_logs(id).await.unwrap().len(), 2);
+
+        let before = OffsetDateTime::now_utc() - time::Duration::days(1);
+        assert_eq!(store.delete_logs_before(before).await.unwrap(), 0);
+
+        let before = OffsetDateTime::now_utc() + time::Duration::seconds(1);
+        assert_eq!(store.delete_logs_before(before).await.unwrap(), 2);
+        assert!(store.get_logs(id).await.unwrap().is_empty());
+
+        let job = store.get_job(id).await.unwrap().unwrap();
+        assert_eq!(job.output, None);
+        assert_eq!(job.status, JobStatus::Succeeded);
+    }
+
+    #[tokio::test]
+    async fn track_records_a_successful_run() {
+        let store = JobStore::in_memory();
+
+        let result = store
+            .track(new_job("foo/a"), async {
+                Ok(run(r#"{"level":30,"msg":"PR created","pr":3}"#, 0))
+            })
+            .await;
+        assert!(result.is_ok());
+
+        let job = store.list_jobs(None, 1).await.unwrap().remove(0);
+        assert_eq!(job.status, JobStatus::Succeeded);
+        assert_eq!(job.exit_code, Some(0));
+        assert_eq!(job.output.as_deref(), Some(" INFO PR created {\"pr\":3}"));
+        assert_eq!(job.summary.unwrap().prs_opened.len(), 1);
+        assert_eq!(store.get_logs(job.id).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn track_records_a_failed_run() {
+        let store = JobStore::in_memory();
+
+        let result = store
+            .track(new_job("foo/a"), async {
+                Err(RenovateFailed {
+                    run: run("[stderr] fatal", 2),
+                }
+                .into())
+            })
+            .await;
+        assert!(result.is_err());
+
+        let job = store.list_jobs(None, 1).await.unwrap().remove(0);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.exit_code, Some(2));
+        assert_eq!(
+            job.output.as_deref(),
+            Some("fatal\nrenovate exited with code: 2")
+        );
+        assert!(job.summary.is_some());
+
+        let logs = store.get_logs(job.id).await.unwrap();
+        assert_eq!(logs.len(), 1);
+        assert_eq!(logs[0].stream, LogStream::Stderr);
+    }
+
+    #[tokio::test]
+    async fn track_records_a_run_which_never_started() {
+        let store = JobStore::in_memory();
+
+        let result = store
+            .track(new_job("foo/a"), async {
+                Err(anyhow::anyhow!("dagger engine is unavailable"))
+            })
+            .await;
+        assert!(result.is_err());
+
+        let job = store.list_jobs(None, 1).await.unwrap().remove(0);
+        assert_eq!(job.status, JobStatus::Failed);
+        assert_eq!(job.exit_code, None);
+        assert_eq!(job.output.as_deref(), Some("dagger engine is unavailable"));
+        assert!(job.summary.is_none());
+        assert!(store.get_logs(job.id).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn track_keeps_the_tail_of_long_output() {
+        let store = JobStore::in_memory();
+        let output = format!("first line\n{}\nlast line", "é".repeat(MAX_OUTPUT_BYTES));
+
+        store
+            .track(new_job("foo/a"), async { Ok(run(&output, 0)) })
+            .await
+            .unwrap();
+
+        let job = store.list_jobs(None, 1).await.unwrap().remove(0);
+        let stored = job.output.unwrap();
+        assert!(stored.len() <= MAX_OUTPUT_BYTES);
+        assert!(stored.ends_with("\nlast line"));
+        assert!(!stored.contains("first line"));
+    }
+
+    #[test]
+    fn truncate_output_respects_char_boundaries() {
+        let output = "é".repeat(MAX_OUTPUT_BYTES);
+
+        let truncated = truncate_output(&output);
+        assert_eq!(truncated.len(), MAX_OUTPUT_BYTES);
+        assert!(truncated.chars().all(|c| c == 'é'));
+
+        assert_eq!(truncate_output("short"), "short");
+    }
+}
@@ -0,0 +1,253 @@
+use std::{
+    collections::HashMap,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use futures::Future;
+use sqlx::PgPool;
+use tokio::{sync::Mutex, time::Instant};
+
+const LEASE_NAME: &str = "scheduler";
+const LEASE_TTL: Duration = Duration::from_secs(30);
+const LEASE_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+type DynLeaseStore = Arc<dyn traits::LeaseStore + Send + Sync + 'static>;
+
+/// Elects a single replica to run scheduled work. The leader keeps renewing a
+/// lease, if it dies the lease expires after [`LEASE_TTL`] and another replica
+/// takes over.
+#[derive(Clone)]
+pub struct LeaderElection {
+    store: DynLeaseStore,
+    holder: String,
+    is_leader: Arc<AtomicBool>,
+}
+
+impl LeaderElection {
+    pub fn postgres(db: PgPool) -> Self {
+        Self::new(Arc::new(PostgresLeaseStore { db }))
+    }
+
+    pub fn in_memory() -> Self {
+        Self::new(Arc::new(InMemoryLeaseStore::default()))
+    }
+
+    fn new(store: DynLeaseStore) -> Self {
+        let hostname = std::env::var("HOSTNAME").unwrap_or("contractor".into());
+
+        Self {
+            store,
+            holder: format!("{}-{}", hostname, uuid::Uuid::new_v4()),
+            is_leader: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.is_leader.load(Ordering::SeqCst)
+    }
+
+    #[cfg(test)]
+    pub fn set_leader(&self, is_leader: bool) {
+        self.is_leader.store(is_leader, Ordering::SeqCst);
+    }
+
+    /// Keeps trying to acquire or renew the lease, runs until the process exits
+    pub async fn run(&self) -> anyhow::Result<()> {
+        loop {
+            self.renew().await;
+
+            tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+        }
+    }
+
+    /// Tries to acquire or renew the lease once, returns whether this replica
+    /// is the leader now
+    async fn renew(&self) -> bool {
+        let acquired = match self
+            .store
+            .try_acquire(LEASE_NAME, &self.holder, LEASE_TTL)
+            .await
+        {
+            Ok(acquired) => acquired,
+            Err(e) => {
+                tracing::warn!("failed to acquire lease: {}", e);
+                false
+            }
+        };
+
+        let was_leader = self.is_leader.swap(acquired, Ordering::SeqCst);
+        match (was_leader, acquired) {
+            (false, true) => tracing::info!(holder = &self.holder, "acquired leadership"),
+            (true, false) => tracing::warn!(holder = &self.holder, "lost leadership"),
+            _ => {}
+        }
+
+        acquired
+    }
+}
+
+pub struct PostgresLeaseStore {
+    db: PgPool,
+}
+
+impl traits::LeaseStore for PostgresLeaseStore {
+    fn try_acquire<'a>(
+        &'a self,
+        name: &'a str,
+        holder: &'a str,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>> {
+        Box::pin(async move {
+            let row = sqlx::query(
+                r#"
+                INSERT INTO leases (name, holder, expires_at)
+                VALUES ($1, $2, now() + $3::INT8 * INTERVAL '1 second')
+                ON CONFLICT (name) DO UPDATE
+                SET holder = excluded.holder, expires_at = excluded.expires_at
+                WHERE leases.holder = excluded.holder OR leases.expires_at < now()
+                RETURNING holder
+                "#,
+            )
+            .bind(name)
+            .bind(holder)
+            .bind(ttl.as_secs() as i64)
+            .fetch_optional(&self.db)
+            .await?;
+
+            Ok(row.is_some())
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryLeaseStore {
+    leases: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl traits::LeaseStore for InMemoryLeaseStore {
+    fn try_acquire<'a>(
+        &'a self,
+        name: &'a str,
+        holder: &'a str,
+        ttl: Duration,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut leases = self.leases.lock().await;
+            let now = Instant::now();
+
+            match leases.get(name) {
+                Some((current, expires_at)) if current != holder && *expires_at > now => Ok(false),
+                _ => {
+                    leases.insert(name.into(), (holder.into(), now + ttl));
+                    Ok(true)
+                }
+            }
+        })
+    }
+}
+
+pub mod traits {
+    use std::{pin::Pin, time::Duration};
+
+    use futures::Future;
+
+    pub trait LeaseStore {
+        /// Acquires or renews the lease `name` for `holder`, returns whether
+        /// `holder` now owns the lease.
+        fn try_acquire<'a>(
+            &'a self,
+            name: &'a str,
+            holder: &'a str,
+            ttl: Duration,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<bool>> + Send + 'a>>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traits::LeaseStore, *};
+
+    #[tokio::test]
+    async fn in_memory_lease_has_a_single_holder() {
+        let store = InMemoryLeaseStore::default();
+        let ttl = Duration::from_secs(30);
+
+        assert!(store.try_acquire("lease", "a", ttl).await.unwrap());
+        assert!(!store.try_acquire("lease", "b", ttl).await.unwrap());
+        assert!(store.try_acquire("lease", "a", ttl).await.unwrap());
+        assert!(store.try_acquire("other", "b", ttl).await.unwrap());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_memory_lease_is_taken_over_once_expired() {
+        let store = InMemoryLeaseStore::default();
+        let ttl = LEASE_TTL;
+
+        assert!(store.try_acquire("lease", "a", ttl).await.unwrap());
+        assert!(!store.try_acquire("lease", "b", ttl).await.unwrap());
+
+        tokio::time::advance(ttl - Duration::from_secs(1)).await;
+        assert!(!store.try_acquire("lease", "b", ttl).await.unwrap());
+
+        tokio::time::advance(Duration::from_secs(1)).await;
+
+        assert!(store.try_acquire("lease", "b", ttl).await.unwrap());
+        assert!(!store.try_acquire("lease", "a", ttl).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn elects_a_single_leader() {
+        let store: DynLeaseStore = Arc::new(InMemoryLeaseStore::default());
+        let first = LeaderElection::new(store.clone());
+        let second = LeaderElection::new(store);
+
+        assert!(!first.is_leader());
+
+        assert!(first.renew().await);
+        assert!(!second.renew().await);
+        assert!(first.renew().await);
+
+        assert!(first.is_leader());
+        assert!(!second.is_leader());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn the_leader_keeps_its_lease_while_running() {
+        let store: DynLeaseStore = Arc::new(InMemoryLeaseStore::default());
+        let first = LeaderElection::new(store.clone());
+        let second = LeaderElection::new(store);
+
+        let running = tokio::spawn({
+            let first = first.clone();
+            async move { first.run().await }
+        });
+        tokio::task::yield_now().await;
+        assert!(first.is_leader());
+
+        // Renewals keep the lease well past its ttl
+        for _ in 0..10 {
+            tokio::time::sleep(LEASE_RENEW_INTERVAL).await;
+            assert!(!second.renew().await);
+        }
+        assert!(first.is_leader());
+
+        // Once the leader stops renewing, the lease expires and moves on
+        running.abort();
+        tokio::time::sleep(LEASE_TTL).await;
+        assert!(second.renew().await);
+    }
+
+    #[tokio::test]
+    async fn every_in_memory_election_is_its_own_leader() {
+        let first = LeaderElection::in_memory();
+        let second = LeaderElection::in_memory();
+
+        assert!(first.renew().await);
+        assert!(second.renew().await);
+    }
+}
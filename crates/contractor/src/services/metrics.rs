@@ -0,0 +1,174 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+
+use super::renovate::RenovateRunSummary;
+
+/// Prometheus metrics served on `/metrics`. Clones record into the same
+/// registry.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+
+    /// Labels: event, outcome
+    pub webhook_requests: IntCounterVec,
+    /// Labels: command
+    pub bot_commands: IntCounterVec,
+    /// Labels: status (started, succeeded, failed)
+    pub renovate_runs: IntCounterVec,
+    /// Labels: status (succeeded, failed)
+    pub renovate_run_duration: HistogramVec,
+    /// Labels: action (opened, updated)
+    pub renovate_pull_requests: IntCounterVec,
+    pub renovate_branches_deleted: IntCounter,
+    /// Labels: level (warn, error)
+    pub renovate_problems: IntCounterVec,
+    pub queue_pending: IntGauge,
+    pub queue_running: IntGauge,
+    /// Labels: kind (discovered, enabled), as of the last reconcile
+    pub reconcile_repositories: IntGaugeVec,
+    /// Labels: action
+    pub reconcile_webhook_changes: IntCounterVec,
+    pub onboarding_pull_requests: IntCounter,
+    /// Labels: operation
+    pub gitea_request_duration: HistogramVec,
+    /// Labels: operation
+    pub gitea_request_errors: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> anyhow::Result<Self> {
+        let registry = Registry::new_custom(Some("contractor".into()), None)?;
+
+        let webhook_requests = IntCounterVec::new(
+            Opts::new("webhook_requests_total", "Webhook deliveries received"),
+            &["event", "outcome"],
+        )?;
+        let bot_commands = IntCounterVec::new(
+            Opts::new("bot_commands_total", "Bot commands handled"),
+            &["command"],
+        )?;
+        let renovate_runs = IntCounterVec::new(
+            Opts::new("renovate_runs_total", "Renovate runs by status"),
+            &["status"],
+        )?;
+        let renovate_run_duration = HistogramVec::new(
+            HistogramOpts::new("renovate_run_duration_seconds", "Duration of renovate runs")
+                .buckets(vec![
+                    5.0, 15.0, 30.0, 60.0, 120.0, 300.0, 600.0, 1200.0, 1800.0, 3600.0,
+                ]),
+            &["status"],
+        )?;
+        let renovate_pull_requests = IntCounterVec::new(
+            Opts::new(
+                "renovate_pull_requests_total",
+                "Pull requests opened or updated by renovate",
+            ),
+            &["action"],
+        )?;
+        let renovate_branches_deleted = IntCounter::new(
+            "renovate_branches_deleted_total",
+            "Branches deleted by renovate",
+        )?;
+        let renovate_problems = IntCounterVec::new(
+            Opts::new(
+                "renovate_problems_total",
+                "Distinct warnings and errors logged by renovate runs",
+            ),
+            &["level"],
+        )?;
+        let queue_pending = IntGauge::new("queue_pending", "Renovate runs waiting in the queue")?;
+        let queue_running = IntGauge::new("queue_running", "Renovate runs currently running")?;
+        let reconcile_repositories = IntGaugeVec::new(
+            Opts::new(
+                "reconcile_repositories",
+                "Repositories seen by the last reconcile",
+            ),
+            &["kind"],
+        )?;
+        let reconcile_webhook_changes = IntCounterVec::new(
+            Opts::new(
+                "reconcile_webhook_changes_total",
+                "Webhook changes applied by the reconciler",
+            ),
+            &["action"],
+        )?;
+        let onboarding_pull_requests = IntCounter::new(
+            "onboarding_pull_requests_total",
+            "Onboarding pull requests opened by the reconciler",
+        )?;
+        let gitea_request_duration = HistogramVec::new(
+            HistogramOpts::new(
+                "gitea_request_duration_seconds",
+                "Duration of gitea api operations, including retries",
+            ),
+            &["operation"],
+        )?;
+        let gitea_request_errors = IntCounterVec::new(
+            Opts::new(
+                "gitea_request_errors_total",
+                "Gitea api operations which failed",
+            ),
+            &["operation"],
+        )?;
+
+        registry.register(Box::new(webhook_requests.clone()))?;
+        registry.register(Box::new(bot_commands.clone()))?;
+        registry.register(Box::new(renovate_runs.clone()))?;
+        registry.register(Box::new(renovate_run_duration.clone()))?;
+        registry.register(Box::new(renovate_pull_requests.clone()))?;
+        registry.register(Box::new(renovate_branches_deleted.clone()))?;
+        registry.register(Box::new(renovate_problems.clone()))?;
+        registry.register(Box::new(queue_pending.clone()))?;
+        registry.register(Box::new(queue_running.clone()))?;
+        registry.register(Box::new(reconcile_repositories.clone()))?;
+        registry.register(Box::new(reconcile_webhook_changes.clone()))?;
+        registry.register(Box::new(onboarding_pull_requests.clone()))?;
+        registry.register(Box::new(gitea_request_duration.clone()))?;
+        registry.register(Box::new(gitea_request_errors.clone()))?;
+
+        Ok(Self {
+            registry,
+            webhook_requests,
+            bot_commands,
+            renovate_runs,
+            renovate_run_duration,
+            renovate_pull_requests,
+            renovate_branches_deleted,
+            renovate_problems,
+            queue_pending,
+            queue_running,
+            reconcile_repositories,
+            reconcile_webhook_changes,
+            onboarding_pull_requests,
+            gitea_request_duration,
+            gitea_request_errors,
+        })
+    }
+
+    pub fn record_summary(&self, summary: &RenovateRunSummary) {
+        self.renovate_pull_requests
+            .with_label_values(&["opened"])
+            .inc_by(summary.prs_opened.len() as u64);
+        self.renovate_pull_requests
+            .with_label_values(&["updated"])
+            .inc_by(summary.prs_updated.len() as u64);
+        self.renovate_branches_deleted
+            .inc_by(summary.branches_deleted.len() as u64);
+        self.renovate_problems
+            .with_label_values(&["warn"])
+            .inc_by(summary.warnings.len() as u64);
+        self.renovate_problems
+            .with_label_values(&["error"])
+            .inc_by(summary.errors.len() as u64);
+    }
+
+    /// Renders every metric in the prometheus text format
+    pub fn render(&self) -> anyhow::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+
+        Ok(String::from_utf8(buffer)?)
+    }
+}
@@ -0,0 +1,349 @@
+use std::{collections::HashMap, fmt::Display, pin::Pin, str::FromStr, sync::Arc};
+
+use futures::Future;
+use serde::Serialize;
+use sqlx::PgPool;
+use time::OffsetDateTime;
+use tokio::sync::Mutex;
+
+use super::gitea::Repository;
+
+type DynOnboardingStore = Arc<dyn traits::OnboardingStore + Send + Sync + 'static>;
+
+/// Records how far each repository without a renovate config got with its
+/// onboarding pull request
+#[derive(Clone)]
+pub struct OnboardingStore(DynOnboardingStore);
+
+impl OnboardingStore {
+    pub fn postgres(db: PgPool) -> Self {
+        Self(Arc::new(PostgresOnboardingStore { db }))
+    }
+
+    pub fn in_memory() -> Self {
+        Self(Arc::new(InMemoryOnboardingStore::default()))
+    }
+}
+
+impl std::ops::Deref for OnboardingStore {
+    type Target = DynOnboardingStore;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnboardingStatus {
+    /// The onboarding pull request is waiting to be merged
+    Open,
+    Merged,
+    /// The onboarding pull request was closed without merging, contractor
+    /// won't propose another one
+    Declined,
+}
+
+impl Display for OnboardingStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            OnboardingStatus::Open => "open",
+            OnboardingStatus::Merged => "merged",
+            OnboardingStatus::Declined => "declined",
+        })
+    }
+}
+
+impl FromStr for OnboardingStatus {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "open" => Ok(OnboardingStatus::Open),
+            "merged" => Ok(OnboardingStatus::Merged),
+            "declined" => Ok(OnboardingStatus::Declined),
+            _ => anyhow::bail!("{} is not a valid onboarding status", s),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Onboarding {
+    pub repository: String,
+    pub status: OnboardingStatus,
+    pub pull_request: Option<u64>,
+    #[serde(with = "time::serde::rfc3339")]
+    pub updated_at: OffsetDateTime,
+}
+
+#[derive(sqlx::FromRow)]
+struct OnboardingRow {
+    repository: String,
+    status: String,
+    pull_request: Option<i64>,
+    updated_at: OffsetDateTime,
+}
+
+impl TryFrom<OnboardingRow> for Onboarding {
+    type Error = anyhow::Error;
+
+    fn try_from(value: OnboardingRow) -> Result<Self, Self::Error> {
+        Ok(Onboarding {
+            repository: value.repository,
+            status: value.status.parse()?,
+            pull_request: value.pull_request.map(|p| p as u64),
+            updated_at: value.updated_at,
+        })
+    }
+}
+
+pub struct PostgresOnboardingStore {
+    db: PgPool,
+}
+
+impl traits::OnboardingStore for PostgresOnboardingStore {
+    fn set_status<'a>(
+        &'a self,
+        repo: &'a Repository,
+        status: OnboardingStatus,
+        pull_request: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            sqlx::query(
+                r#"
+                INSERT INTO onboarding (repository, status, pull_request)
+                VALUES ($1, $2, $3)
+                ON CONFLICT (repository)
+                DO UPDATE SET status = $2, pull_request = $3, updated_at = now()
+                WHERE onboarding.status <> $2 OR onboarding.pull_request IS DISTINCT FROM $3
+                "#,
+            )
+            .bind(repo.to_string())
+            .bind(status.to_string())
+            .bind(pull_request.map(|p| p as i64))
+            .execute(&self.db)
+            .await?;
+
+            Ok(())
+        })
+    }
+
+    fn get_status<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Onboarding>>> + Send + 'a>> {
+        Box::pin(async move {
+            let row = sqlx::query_as::<_, OnboardingRow>(
+                r#"
+                SELECT repository, status, pull_request, updated_at
+                FROM onboarding
+                WHERE repository = $1
+                "#,
+            )
+            .bind(repo.to_string())
+            .fetch_optional(&self.db)
+            .await?;
+
+            row.map(Onboarding::try_from).transpose()
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Onboarding>>> + Send + 'a>> {
+        Box::pin(async move {
+            let rows = sqlx::query_as::<_, OnboardingRow>(
+                r#"
+                SELECT repository, status, pull_request, updated_at
+                FROM onboarding
+                ORDER BY repository
+                "#,
+            )
+            .fetch_all(&self.db)
+            .await?;
+
+            rows.into_iter().map(Onboarding::try_from).collect()
+        })
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryOnboardingStore {
+    onboardings: Mutex<HashMap<String, Onboarding>>,
+}
+
+impl traits::OnboardingStore for InMemoryOnboardingStore {
+    fn set_status<'a>(
+        &'a self,
+        repo: &'a Repository,
+        status: OnboardingStatus,
+        pull_request: Option<u64>,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut onboardings = self.onboardings.lock().await;
+            let repository = repo.to_string();
+
+            match onboardings.get(&repository) {
+                Some(o) if o.status == status && o.pull_request == pull_request => {}
+                _ => {
+                    onboardings.insert(
+                        repository.clone(),
+                        Onboarding {
+                            repository,
+                            status,
+                            pull_request,
+                            updated_at: OffsetDateTime::now_utc(),
+                        },
+                    );
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    fn get_status<'a>(
+        &'a self,
+        repo: &'a Repository,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Onboarding>>> + Send + 'a>> {
+        Box::pin(async move {
+            Ok(self
+                .onboardings
+                .lock()
+                .await
+                .get(&repo.to_string())
+                .cloned())
+        })
+    }
+
+    fn list<'a>(
+        &'a self,
+    ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Onboarding>>> + Send + 'a>> {
+        Box::pin(async move {
+            let mut onboardings = self
+                .onboardings
+                .lock()
+                .await
+                .values()
+                .cloned()
+                .collect::<Vec<_>>();
+            onboardings.sort_by(|a, b| a.repository.cmp(&b.repository));
+
+            Ok(onboardings)
+        })
+    }
+}
+
+pub mod traits {
+    use std::pin::Pin;
+
+    use futures::Future;
+
+    use super::{Onboarding, OnboardingStatus, Repository};
+
+    pub trait OnboardingStore {
+        /// Records the onboarding status of `repo`, `updated_at` only changes
+        /// when the status or pull request does
+        fn set_status<'a>(
+            &'a self,
+            repo: &'a Repository,
+            status: OnboardingStatus,
+            pull_request: Option<u64>,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send + 'a>>;
+
+        fn get_status<'a>(
+            &'a self,
+            repo: &'a Repository,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Option<Onboarding>>> + Send + 'a>>;
+
+        fn list<'a>(
+            &'a self,
+        ) -> Pin<Box<dyn Future<Output = anyhow::Result<Vec<Onboarding>>> + Send + 'a>>;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{traits::OnboardingStore as _, *};
+    use crate::services::gitea::RepositoryMetadata;
+
+    fn repo(name: &str) -> Repository {
+        Repository {
+            owner: "someone".into(),
+            name: name.into(),
+            metadata: RepositoryMetadata::default(),
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_records_each_transition() {
+        let store = InMemoryOnboardingStore::default();
+        let repo = repo("repo");
+
+        assert!(store.get_status(&repo).await.unwrap().is_none());
+
+        for (status, pull_request) in [
+            (OnboardingStatus::Open, Some(1)),
+            (OnboardingStatus::Declined, Some(1)),
+            (OnboardingStatus::Open, Some(2)),
+            (OnboardingStatus::Merged, Some(2)),
+        ] {
+            store.set_status(&repo, status, pull_request).await.unwrap();
+
+            let onboarding = store.get_status(&repo).await.unwrap().unwrap();
+            assert_eq!(onboarding.repository, "someone/repo");
+            assert_eq!(
+                (onboarding.status, onboarding.pull_request),
+                (status, pull_request)
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_only_updates_on_changes() {
+        let store = InMemoryOnboardingStore::default();
+        let repo = repo("repo");
+
+        store
+            .set_status(&repo, OnboardingStatus::Open, Some(1))
+            .await
+            .unwrap();
+        let opened = store.get_status(&repo).await.unwrap().unwrap();
+
+        store
+            .set_status(&repo, OnboardingStatus::Open, Some(1))
+            .await
+            .unwrap();
+        let unchanged = store.get_status(&repo).await.unwrap().unwrap();
+        assert_eq!(unchanged.updated_at, opened.updated_at);
+
+        store
+            .set_status(&repo, OnboardingStatus::Merged, Some(1))
+            .await
+            .unwrap();
+        let merged = store.get_status(&repo).await.unwrap().unwrap();
+        assert!(merged.updated_at >= opened.updated_at);
+        assert_eq!(merged.status, OnboardingStatus::Merged);
+    }
+
+    #[tokio::test]
+    async fn in_memory_store_lists_by_repository() {
+        let store = InMemoryOnboardingStore::default();
+
+        for name in ["b", "c", "a"] {
+            store
+                .set_status(&repo(name), OnboardingStatus::Open, None)
+                .await
+                .unwrap();
+        }
+
+        let repositories = store
+            .list()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|o| o.repository)
+            .collect::<Vec<_>>();
+        assert_eq!(repositories, vec!["someone/a", "someone/b", "someone/c"]);
+    }
+}
@@ -0,0 +1,612 @@
+use std::{
+    collections::{HashSet, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use tokio::sync::{oneshot, Notify};
+
+use super::{
+    engines::dagger::{Dagger, EngineConnection, EngineUnavailable},
+    gitea::Repository,
+    images::RenovateImages,
+    jobs::{JobStore, JobTrigger, NewJob},
+    metrics::Metrics,
+    renovate::{RenovateConfig, RenovateFailed, RenovateRun},
+};
+
+pub type JobResult = Result<RenovateRun, Arc<anyhow::Error>>;
+
+/// Jobs finding the dagger engine unavailable are put back in the queue after
+/// this delay, up to [`ENGINE_RETRY_ATTEMPTS`] times
+const ENGINE_RETRY_DELAY: Duration = Duration::from_secs(30);
+const ENGINE_RETRY_ATTEMPTS: usize = 10;
+
+#[derive(Clone, Debug)]
+pub struct JobRequest {
+    pub repo: Repository,
+    pub trigger: JobTrigger,
+    pub requester: Option<String>,
+    /// Replaces the tag of the renovate image for this run
+    pub renovate_version: Option<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Submission {
+    /// The request was added to the back of the queue
+    Queued,
+    /// A request for the same repository was already pending, the request
+    /// will be served by that one instead
+    Coalesced,
+}
+
+#[derive(Clone, Copy, Debug, Serialize)]
+pub struct QueueDepth {
+    pub pending: usize,
+    pub running: usize,
+}
+
+struct PendingJob {
+    request: JobRequest,
+    waiters: Vec<oneshot::Sender<JobResult>>,
+    /// Earlier attempts which found the dagger engine unavailable
+    engine_retries: usize,
+    /// A job waiting for the engine to recover isn't run before this, it
+    /// stays pending meanwhile so new requests are coalesced with it
+    not_before: Option<tokio::time::Instant>,
+}
+
+#[derive(Default)]
+struct QueueState {
+    pending: VecDeque<PendingJob>,
+    running: HashSet<String>,
+}
+
+struct QueueInner {
+    dagger: Dagger,
+    images: RenovateImages,
+    jobs: JobStore,
+    metrics: Metrics,
+    max_concurrency: usize,
+
+    state: Mutex<QueueState>,
+    notify: Notify,
+}
+
+impl QueueInner {
+    /// The queue state stays consistent across a panic while it is held, so a
+    /// poisoned lock is used as is rather than failing every later submit
+    fn state(&self) -> MutexGuard<'_, QueueState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Frees the repository's running slot once its job is done, including when
+/// the job panics
+struct RunningJob {
+    inner: Arc<QueueInner>,
+    key: String,
+}
+
+impl Drop for RunningJob {
+    fn drop(&mut self) {
+        self.inner.state().running.remove(&self.key);
+        self.inner.notify.notify_one();
+    }
+}
+
+/// Queue all renovate runs go through. At most `max_concurrency` jobs run at
+/// once, and at most one per repository. Duplicate pending requests for the
+/// same repository are coalesced into a single run.
+#[derive(Clone)]
+pub struct JobQueue {
+    inner: Arc<QueueInner>,
+}
+
+impl JobQueue {
+    pub fn new(
+        dagger: Dagger,
+        images: RenovateImages,
+        jobs: JobStore,
+        metrics: Metrics,
+        max_concurrency: usize,
+    ) -> Self {
+        Self {
+            inner: Arc::new(QueueInner {
+                dagger,
+                images,
+                jobs,
+                metrics,
+                max_concurrency: max_concurrency.max(1),
+                state: Mutex::new(QueueState::default()),
+                notify: Notify::new(),
+            }),
+        }
+    }
+
+    pub fn submit(&self, request: JobRequest) -> (Submission, oneshot::Receiver<JobResult>) {
+        let (tx, rx) = oneshot::channel();
+        let key = request.repo.to_string();
+
+        let submission = self.enqueue(PendingJob {
+            request,
+            waiters: vec![tx],
+            engine_retries: 0,
+            not_before: None,
+        });
+
+        tracing::debug!("submitted job for: {}, {:?}", key, submission);
+
+        (submission, rx)
+    }
+
+    /// Adds `job` to the back of the queue, or hands its waiters to a pending
+    /// job for the same repository and renovate version
+    fn enqueue(&self, job: PendingJob) -> Submission {
+        let submission = {
+            let mut state = self.inner.state();
+
+            match state.pending.iter_mut().find(|p| {
+                p.request.repo == job.request.repo
+                    && p.request.renovate_version == job.request.renovate_version
+            }) {
+                Some(pending) => {
+                    pending.waiters.extend(job.waiters);
+                    Submission::Coalesced
+                }
+                None => {
+                    state.pending.push_back(job);
+                    Submission::Queued
+                }
+            }
+        };
+
+        self.inner.notify.notify_one();
+
+        submission
+    }
+
+    /// Puts `job` back in the queue once the engine had some time to recover,
+    /// returns it when it ran out of attempts
+    fn retry_later(&self, mut job: PendingJob) -> Option<PendingJob> {
+        if job.engine_retries >= ENGINE_RETRY_ATTEMPTS {
+            return Some(job);
+        }
+        job.engine_retries += 1;
+
+        tracing::warn!(
+            "dagger engine is unavailable, retrying renovate for: {} in {} seconds, attempt: {}/{}",
+            job.request.repo,
+            ENGINE_RETRY_DELAY.as_secs(),
+            job.engine_retries,
+            ENGINE_RETRY_ATTEMPTS
+        );
+
+        job.not_before = Some(tokio::time::Instant::now() + ENGINE_RETRY_DELAY);
+        self.enqueue(job);
+
+        // Wakes the dispatcher up once the job can run again
+        let inner = self.inner.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(ENGINE_RETRY_DELAY).await;
+            inner.notify.notify_one();
+        });
+
+        None
+    }
+
+    pub fn depth(&self) -> QueueDepth {
+        let state = self.inner.state();
+
+        QueueDepth {
+            pending: state.pending.len(),
+            running: state.running.len(),
+        }
+    }
+
+    /// Dispatches pending jobs as capacity frees up, runs until the process exits
+    pub async fn run(&self) -> anyhow::Result<()> {
+        loop {
+            match self.next_job() {
+                Some(job) => {
+                    let queue = self.clone();
+                    tokio::spawn(async move { queue.execute(job).await });
+                }
+                None => self.inner.notify.notified().await,
+            }
+        }
+    }
+
+    fn next_job(&self) -> Option<PendingJob> {
+        let mut state = self.inner.state();
+
+        if state.running.len() >= self.inner.max_concurrency {
+            return None;
+        }
+
+        let now = tokio::time::Instant::now();
+        let index = state.pending.iter().position(|p| {
+            !state.running.contains(&p.request.repo.to_string())
+                && p.not_before.is_none_or(|not_before| not_before <= now)
+        })?;
+
+        let job = state.pending.remove(index)?;
+        state.running.insert(job.request.repo.to_string());
+
+        Some(job)
+    }
+
+    async fn execute(&self, job: PendingJob) {
+        let running = RunningJob {
+            inner: self.inner.clone(),
+            key: job.request.repo.to_string(),
+        };
+
+        // Waiting for the engine isn't recorded as a job until the last attempt
+        let job = match self.inner.dagger.connection() {
+            EngineConnection::Connected => job,
+            _ => match self.retry_later(job) {
+                Some(job) => job,
+                None => return,
+            },
+        };
+        let PendingJob {
+            request,
+            waiters,
+            engine_retries,
+            ..
+        } = job;
+
+        tracing::info!("running renovate for: {}", request.repo);
+
+        let new_job = NewJob {
+            repo: request.repo.clone(),
+            trigger: request.trigger,
+            requester: request.requester.clone(),
+        };
+
+        let metrics = &self.inner.metrics;
+        metrics.renovate_runs.with_label_values(&["started"]).inc();
+        let started = Instant::now();
+
+        let result = self
+            .inner
+            .jobs
+            .track(new_job, async {
+                let image = self
+                    .inner
+                    .images
+                    .resolve(&request.repo, request.renovate_version.as_deref())
+                    .await?;
+
+                tracing::info!("using renovate image: {} for: {}", image, request.repo);
+
+                let config = RenovateConfig {
+                    repo: request.repo.to_string(),
+                    image,
+                };
+
+                self.inner.dagger.execute_renovate(&config).await
+            })
+            .await
+            .map_err(Arc::new);
+
+        let status = if result.is_ok() {
+            "succeeded"
+        } else {
+            "failed"
+        };
+        metrics.renovate_runs.with_label_values(&[status]).inc();
+        let run = match &result {
+            Ok(run) => Some(run),
+            Err(e) => e.downcast_ref::<RenovateFailed>().map(|f| &f.run),
+        };
+        if let Some(run) = run {
+            metrics.record_summary(&run.summary);
+        }
+        metrics
+            .renovate_run_duration
+            .with_label_values(&[status])
+            .observe(started.elapsed().as_secs_f64());
+
+        if let Err(e) = &result {
+            tracing::error!(
+                "failed to execute renovate for: {}, error: {}",
+                request.repo,
+                e
+            );
+        }
+
+        drop(running);
+
+        // The engine went away during the run
+        let waiters = match &result {
+            Err(e) if e.downcast_ref::<EngineUnavailable>().is_some() => {
+                match self.retry_later(PendingJob {
+                    request,
+                    waiters,
+                    engine_retries,
+                    not_before: None,
+                }) {
+                    Some(job) => job.waiters,
+                    None => return,
+                }
+            }
+            _ => waiters,
+        };
+
+        for waiter in waiters {
+            let _ = waiter.send(result.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+    use axum::Router;
+    use tokio::sync::Semaphore;
+
+    use super::*;
+    use crate::services::{
+        engines::dagger::traits,
+        gitea::{testing, GiteaClient, RepositoryMetadata},
+        renovate::RenovateLog,
+    };
+
+    /// A dagger engine whose runs each wait for a permit of `release`. Runs of
+    /// repositories named `panic` panic, and runs fail with
+    /// [`EngineUnavailable`] while it isn't connected.
+    struct FakeEngine {
+        connected: AtomicBool,
+        release: Semaphore,
+        runs: AtomicUsize,
+        running: AtomicUsize,
+        max_running: AtomicUsize,
+    }
+
+    impl FakeEngine {
+        fn new(connected: bool) -> Arc<Self> {
+            Arc::new(Self {
+                connected: AtomicBool::new(connected),
+                release: Semaphore::new(0),
+                runs: AtomicUsize::new(0),
+                running: AtomicUsize::new(0),
+                max_running: AtomicUsize::new(0),
+            })
+        }
+
+        fn runs(&self) -> usize {
+            self.runs.load(Ordering::SeqCst)
+        }
+    }
+
+    impl traits::Dagger for FakeEngine {
+        fn execute_renovate<'a>(
+            &'a self,
+            config: &'a RenovateConfig,
+        ) -> std::pin::Pin<Box<dyn futures::Future<Output = anyhow::Result<RenovateRun>> + Send + 'a>>
+        {
+            Box::pin(async move {
+                self.runs.fetch_add(1, Ordering::SeqCst);
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                self.max_running.fetch_max(running, Ordering::SeqCst);
+
+                self.release.acquire().await.unwrap().forget();
+                self.running.fetch_sub(1, Ordering::SeqCst);
+
+                if config.repo.ends_with("/panic") {
+                    panic!("renovate panicked");
+                }
+                if !self.connected.load(Ordering::SeqCst) {
+                    return Err(EngineUnavailable {
+                        connection: self.connection(),
+                    }
+                    .into());
+                }
+
+                Ok(RenovateRun::new(RenovateLog::parse(""), 0))
+            })
+        }
+
+        fn connection(&self) -> EngineConnection {
+            match self.connected.load(Ordering::SeqCst) {
+                true => EngineConnection::Connected,
+                false => EngineConnection::Connecting,
+            }
+        }
+    }
+
+    /// A queue running renovate on `engine`, gitea doesn't have any
+    /// repository config so the default image is used
+    async fn queue(engine: &Arc<FakeEngine>, max_concurrency: usize) -> JobQueue {
+        let config = testing::config(&testing::fake_gitea(Router::new()).await);
+        let metrics = Metrics::new().unwrap();
+        let engine: Arc<dyn traits::Dagger + Send + Sync> = engine.clone();
+
+        JobQueue::new(
+            Dagger::from(engine),
+            RenovateImages::new(GiteaClient::new(&config, metrics.clone()), &config.renovate),
+            JobStore::in_memory(),
+            metrics,
+            max_concurrency,
+        )
+    }
+
+    fn request(name: &str, renovate_version: Option<&str>) -> JobRequest {
+        JobRequest {
+            repo: Repository {
+                owner: "someone".into(),
+                name: name.into(),
+                metadata: RepositoryMetadata::default(),
+            },
+            trigger: JobTrigger::Push,
+            requester: None,
+            renovate_version: renovate_version.map(Into::into),
+        }
+    }
+
+    fn run(queue: &JobQueue) {
+        let queue = queue.clone();
+        tokio::spawn(async move { queue.run().await });
+    }
+
+    async fn wait_for_runs(engine: &FakeEngine, runs: usize) {
+        while engine.runs() < runs {
+            tokio::time::sleep(Duration::from_millis(5)).await;
+        }
+
+        // Give the queue the chance to start more runs than it should
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    #[tokio::test]
+    async fn runs_at_most_max_concurrency_jobs() {
+        let engine = FakeEngine::new(true);
+        let queue = queue(&engine, 2).await;
+
+        let results = (0..5)
+            .map(|i| queue.submit(request(&format!("repo-{}", i), None)).1)
+            .collect::<Vec<_>>();
+        run(&queue);
+
+        wait_for_runs(&engine, 2).await;
+        assert_eq!(engine.runs(), 2);
+        let depth = queue.depth();
+        assert_eq!((depth.pending, depth.running), (3, 2));
+
+        engine.release.add_permits(5);
+        for result in results {
+            assert!(result.await.unwrap().is_ok());
+        }
+        assert_eq!(engine.max_running.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn runs_one_job_per_repository_at_a_time() {
+        let engine = FakeEngine::new(true);
+        let queue = queue(&engine, 4).await;
+
+        let (first, first_result) = queue.submit(request("repo", None));
+        let (second, second_result) = queue.submit(request("repo", Some("38")));
+        assert_eq!((first, second), (Submission::Queued, Submission::Queued));
+        run(&queue);
+
+        wait_for_runs(&engine, 1).await;
+        assert_eq!(engine.runs(), 1);
+
+        engine.release.add_permits(2);
+        assert!(first_result.await.unwrap().is_ok());
+        assert!(second_result.await.unwrap().is_ok());
+        assert_eq!(engine.max_running.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn coalesces_requests_for_the_same_repository_and_version() {
+        let engine = FakeEngine::new(true);
+        let queue = queue(&engine, 4).await;
+
+        let (submissions, results): (Vec<_>, Vec<_>) = [
+            request("repo", None),
+            request("repo", None),
+            request("repo", Some("38")),
+            request("other", None),
+        ]
+        .into_iter()
+        .map(|request| queue.submit(request))
+        .unzip();
+
+        assert_eq!(
+            submissions,
+            vec![
+                Submission::Queued,
+                Submission::Coalesced,
+                Submission::Queued,
+                Submission::Queued
+            ]
+        );
+        assert_eq!(queue.depth().pending, 3);
+
+        engine.release.add_permits(3);
+        run(&queue);
+        for result in results {
+            assert!(result.await.unwrap().is_ok());
+        }
+        assert_eq!(engine.runs(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_while_the_engine_is_unavailable() {
+        let engine = FakeEngine::new(false);
+        engine.release.add_permits(1);
+        let queue = queue(&engine, 1).await;
+
+        let started = tokio::time::Instant::now();
+        let (_, first) = queue.submit(request("repo", None));
+        run(&queue);
+
+        // The job waits for the engine in the queue, so later requests
+        // are still coalesced with it
+        tokio::time::sleep(ENGINE_RETRY_DELAY / 2).await;
+        let depth = queue.depth();
+        assert_eq!((depth.pending, depth.running), (1, 0));
+        let (submission, second) = queue.submit(request("repo", None));
+        assert_eq!(submission, Submission::Coalesced);
+
+        for result in [first, second] {
+            let error = result.await.unwrap().unwrap_err();
+            assert!(
+                error.downcast_ref::<EngineUnavailable>().is_some(),
+                "{}",
+                error
+            );
+        }
+
+        // Only the last attempt is run, once the engine is given up on
+        assert_eq!(engine.runs(), 1);
+        assert!(started.elapsed() >= ENGINE_RETRY_DELAY * ENGINE_RETRY_ATTEMPTS as u32);
+        assert_eq!(queue.depth().pending, 0);
+    }
+
+    #[tokio::test]
+    async fn frees_the_slot_of_a_panicking_job() {
+        let engine = FakeEngine::new(true);
+        engine.release.add_permits(2);
+        let queue = queue(&engine, 1).await;
+
+        let (_, panicked) = queue.submit(request("panic", None));
+        let (_, next) = queue.submit(request("repo", None));
+        run(&queue);
+
+        assert!(panicked.await.is_err());
+        assert!(next.await.unwrap().is_ok());
+        assert_eq!(queue.depth().running, 0);
+    }
+
+    #[tokio::test]
+    async fn keeps_working_with_a_poisoned_lock() {
+        let engine = FakeEngine::new(true);
+        let queue = queue(&engine, 1).await;
+
+        std::thread::scope(|s| {
+            let panicked = s
+                .spawn(|| {
+                    let _state = queue.inner.state.lock().unwrap();
+                    panic!("panicked while holding the queue state");
+                })
+                .join();
+            assert!(panicked.is_err());
+        });
+        assert!(queue.inner.state.is_poisoned());
+
+        let (submission, result) = queue.submit(request("repo", None));
+        assert_eq!(submission, Submission::Queued);
+        assert_eq!(queue.depth().pending, 1);
+
+        engine.release.add_permits(1);
+        run(&queue);
+        assert!(result.await.unwrap().is_ok());
+    }
+}
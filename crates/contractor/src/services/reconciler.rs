@@ -1,54 +1,341 @@
+use std::fmt::Display;
+
 use anyhow::Context;
-use futures::{stream::FuturesUnordered, StreamExt};
+use futures::{stream::FuturesUnordered, Future, StreamExt, TryStreamExt};
 use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{config::OnboardingConfig, SharedState};
 
-use crate::SharedState;
+use super::{
+    gitea::{GiteaClient, GiteaClientState, GiteaWebhook, Repository},
+    metrics::Metrics,
+    onboarding::{Onboarding, OnboardingStatus, OnboardingStore},
+};
 
-use super::gitea::{GiteaClient, GiteaClientState, Repository};
+/// The file onboarding pull requests add
+const ONBOARDING_CONFIG_FILE: &str = "renovate.json";
 
+#[derive(Clone)]
 pub struct Reconciler {
     gitea_client: GiteaClient,
+    metrics: Metrics,
+    onboarding: OnboardingStore,
+    onboarding_config: OnboardingConfig,
+}
+
+#[derive(clap::Args, Clone, Debug)]
+pub struct ReconcileOptions {
+    /// Include the repositories of this user
+    #[arg(long, env = "CONTRACTOR_USER")]
+    pub user: Option<String>,
+    /// Include the repositories of the owner of GITEA_TOKEN
+    #[arg(long = "self", env = "CONTRACTOR_SELF")]
+    pub own: bool,
+    /// Include the repositories of these orgs
+    #[arg(long, env = "CONTRACTOR_ORGS", value_delimiter = ',')]
+    pub org: Option<Vec<String>>,
+
+    #[arg(long, env = "CONTRACTOR_FILTER")]
+    pub filter: Option<String>,
+
+    #[arg(long = "force-refresh", env = "CONTRACTOR_FORCE_REFRESH")]
+    pub force_refresh: bool,
+
+    /// Remove the contractor webhook from discovered repositories which no
+    /// longer match the filter or have renovate enabled
+    #[arg(long, env = "CONTRACTOR_PRUNE")]
+    pub prune: bool,
+
+    /// Include archived repositories
+    #[arg(long = "include-archived", env = "CONTRACTOR_INCLUDE_ARCHIVED")]
+    pub include_archived: bool,
+    /// Include mirrored repositories
+    #[arg(long = "include-mirrors", env = "CONTRACTOR_INCLUDE_MIRRORS")]
+    pub include_mirrors: bool,
+    /// Include forked repositories
+    #[arg(
+        long = "include-forks",
+        env = "CONTRACTOR_INCLUDE_FORKS",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub include_forks: bool,
+    /// Include repositories without any commits
+    #[arg(
+        long = "include-empty",
+        env = "CONTRACTOR_INCLUDE_EMPTY",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub include_empty: bool,
+    /// Include private repositories
+    #[arg(
+        long = "include-private",
+        env = "CONTRACTOR_INCLUDE_PRIVATE",
+        default_value_t = true,
+        action = clap::ArgAction::Set
+    )]
+    pub include_private: bool,
+
+    /// Repositories with this topic are included even if they don't match the filter
+    #[arg(
+        long = "enabled-topic",
+        env = "CONTRACTOR_ENABLED_TOPIC",
+        default_value = "contractor-enabled"
+    )]
+    pub enabled_topic: String,
+    /// Repositories with this topic are always excluded
+    #[arg(
+        long = "disabled-topic",
+        env = "CONTRACTOR_DISABLED_TOPIC",
+        default_value = "contractor-disabled"
+    )]
+    pub disabled_topic: String,
+
+    /// Open a pull request adding a renovate config to repositories which
+    /// don't have one yet
+    #[arg(long, env = "CONTRACTOR_ONBOARD")]
+    pub onboard: bool,
+}
+
+/// The options with every flag at its clap default, for tests
+#[cfg(test)]
+impl Default for ReconcileOptions {
+    fn default() -> Self {
+        use clap::Parser;
+
+        #[derive(Parser)]
+        struct Defaults {
+            #[command(flatten)]
+            options: ReconcileOptions,
+        }
+
+        Defaults::parse_from(["contractor"]).options
+    }
+}
+
+impl ReconcileOptions {
+    /// Why `repo` is excluded by its metadata, `None` if it is included
+    fn excluded_by_metadata(&self, repo: &Repository) -> Option<&'static str> {
+        let metadata = &repo.metadata;
+
+        if metadata.archived && !self.include_archived {
+            Some("archived")
+        } else if metadata.mirror && !self.include_mirrors {
+            Some("a mirror")
+        } else if metadata.fork && !self.include_forks {
+            Some("a fork")
+        } else if metadata.empty && !self.include_empty {
+            Some("empty")
+        } else if metadata.private && !self.include_private {
+            Some("private")
+        } else {
+            None
+        }
+    }
+
+    fn filter(&self) -> anyhow::Result<Option<regex::Regex>> {
+        self.filter
+            .as_deref()
+            .map(regex::Regex::new)
+            .transpose()
+            .context(
+                "filter regex failed to compile, make sure it is valid against rust-lang/regex",
+            )
+    }
+
+    /// Whether `repo` passes the metadata, topic and `filter` checks
+    fn includes(&self, filter: Option<&regex::Regex>, repo: &Repository) -> bool {
+        if let Some(reason) = self.excluded_by_metadata(repo) {
+            tracing::trace!("repository: {}, is excluded as it is {}", repo, reason);
+            return false;
+        }
+
+        match self.opted_in_by_topic(repo) {
+            Some(true) => {
+                tracing::trace!("repository: {}, opted in by topic", repo);
+                return true;
+            }
+            Some(false) => {
+                tracing::trace!("repository: {}, opted out by topic", repo);
+                return false;
+            }
+            None => {}
+        }
+
+        match filter {
+            Some(re) if !re.is_match(&repo.to_string()) => {
+                tracing::trace!(
+                    filter = re.as_str(),
+                    "repository: {}, didn't match filter",
+                    repo.to_string(),
+                );
+                false
+            }
+            _ => true,
+        }
+    }
+
+    /// Whether `repo` opted in or out through its topics, `None` if it did neither
+    fn opted_in_by_topic(&self, repo: &Repository) -> Option<bool> {
+        let topics = repo.metadata.topics.as_deref().unwrap_or_default();
+
+        if topics.contains(&self.disabled_topic) {
+            Some(false)
+        } else if topics.contains(&self.enabled_topic) {
+            Some(true)
+        } else {
+            None
+        }
+    }
+}
+
+/// The changes a reconcile would make, computed without changing anything in
+/// Gitea
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct ReconcilePlan {
+    pub discovered: Vec<Repository>,
+    pub filtered_out: Vec<Repository>,
+    pub renovate_enabled: Vec<RenovateRepository>,
+    pub webhooks: Vec<WebhookChange>,
+    pub onboarding: Vec<OnboardingChange>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct RenovateRepository {
+    pub repository: Repository,
+    /// The renovate config file found in the repository
+    pub config_file: String,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct WebhookChange {
+    pub repository: Repository,
+    pub action: WebhookAction,
+    #[serde(skip)]
+    webhook: Option<GiteaWebhook>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookAction {
+    Create,
+    Update,
+    Delete,
+}
+
+impl Display for WebhookAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            WebhookAction::Create => "create",
+            WebhookAction::Update => "update",
+            WebhookAction::Delete => "delete",
+        })
+    }
+}
+
+/// The outcome of [`Reconciler::recheck`]
+#[derive(Clone, Debug)]
+pub enum Recheck {
+    /// The repository as gitea has it now
+    Included(Repository),
+    /// Why the repository is excluded now, e.g. "archived"
+    Excluded(String),
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct OnboardingChange {
+    pub repository: Repository,
+    #[serde(flatten)]
+    pub action: OnboardingAction,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum OnboardingAction {
+    /// Open an onboarding pull request
+    Open,
+    /// Record a change in the state of an existing onboarding pull request
+    Record {
+        status: OnboardingStatus,
+        pull_request: Option<u64>,
+    },
+}
+
+impl Display for OnboardingChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.action {
+            OnboardingAction::Open => write!(f, "open {}", self.repository),
+            OnboardingAction::Record {
+                status,
+                pull_request: Some(pull_request),
+            } => write!(f, "record {} #{} {}", self.repository, pull_request, status),
+            OnboardingAction::Record {
+                status,
+                pull_request: None,
+            } => write!(f, "record {} {}", self.repository, status),
+        }
+    }
+}
+
+impl Display for ReconcilePlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "discovered repositories: {}", self.discovered.len())?;
+
+        writeln!(f, "filtered out: {}", self.filtered_out.len())?;
+        for repo in &self.filtered_out {
+            writeln!(f, "  - {}", repo)?;
+        }
+
+        writeln!(f, "renovate enabled: {}", self.renovate_enabled.len())?;
+        for repo in &self.renovate_enabled {
+            writeln!(f, "  - {} ({})", repo.repository, repo.config_file)?;
+        }
+
+        writeln!(f, "webhook changes: {}", self.webhooks.len())?;
+        for change in &self.webhooks {
+            writeln!(f, "  {} {}", change.action, change.repository)?;
+        }
+
+        writeln!(f, "onboarding changes: {}", self.onboarding.len())?;
+        for change in &self.onboarding {
+            writeln!(f, "  {}", change)?;
+        }
+
+        Ok(())
+    }
 }
 
 impl Reconciler {
-    pub fn new(gitea_client: GiteaClient) -> Self {
-        Self { gitea_client }
+    pub fn new(
+        gitea_client: GiteaClient,
+        metrics: Metrics,
+        onboarding: OnboardingStore,
+        onboarding_config: OnboardingConfig,
+    ) -> Self {
+        Self {
+            gitea_client,
+            metrics,
+            onboarding,
+            onboarding_config,
+        }
     }
 
-    pub async fn reconcile(
-        &self,
-        user: Option<String>,
-        orgs: Option<Vec<String>>,
-        filter: Option<String>,
-        force_refresh: bool,
-    ) -> anyhow::Result<()> {
-        let repos = self.get_repos(user, orgs).await?;
+    pub async fn plan(&self, options: &ReconcileOptions) -> anyhow::Result<ReconcilePlan> {
+        let repos = self
+            .get_repos(options.user.clone(), options.own, options.org.clone())
+            .await?;
         tracing::debug!("found repositories: {}", repos.len());
 
-        let filtered_repos = match filter {
-            Some(filter) => {
-                let re = regex::Regex::new(&filter).context(
-                    "filter regex failed to compile, make sure it is valid against rust-lang/regex",
-                )?;
-
-                repos
-                    .into_iter()
-                    .filter(|r| {
-                        if re.is_match(&r.to_string()) {
-                            true
-                        } else {
-                            tracing::trace!(
-                                filter = &filter,
-                                "repository: {}, didn't match filter",
-                                r.to_string(),
-                            );
-                            false
-                        }
-                    })
-                    .collect()
-            }
-            None => repos,
-        };
+        let repos = self.with_topics(repos).await?;
+
+        let filter = options.filter()?;
+
+        let (filtered_repos, filtered_out): (Vec<_>, Vec<_>) = repos
+            .iter()
+            .cloned()
+            .partition(|r| options.includes(filter.as_ref(), r));
         tracing::debug!("filtered repositories: {}", filtered_repos.len());
 
         let renovate_enabled = self.get_renovate_enabled(&filtered_repos).await?;
@@ -57,19 +344,294 @@ impl Reconciler {
             renovate_enabled.len()
         );
 
-        self.ensure_webhook(&renovate_enabled, force_refresh)
+        let enabled_repos = renovate_enabled
+            .iter()
+            .map(|r| r.repository.clone())
+            .collect::<Vec<_>>();
+
+        let mut webhooks = self
+            .plan_webhooks(&enabled_repos, options.force_refresh)
             .await?;
 
+        if options.prune {
+            let undesired = repos
+                .iter()
+                .filter(|r| !enabled_repos.contains(r))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            webhooks.append(&mut self.plan_prune(&undesired).await?);
+        }
+
+        let onboarding = if options.onboard {
+            let without_config = filtered_repos
+                .iter()
+                .filter(|r| !enabled_repos.contains(r))
+                .cloned()
+                .collect::<Vec<_>>();
+
+            self.plan_onboarding(&without_config, &enabled_repos)
+                .await?
+        } else {
+            Vec::new()
+        };
+
+        self.metrics
+            .reconcile_repositories
+            .with_label_values(&["discovered"])
+            .set(repos.len() as i64);
+        self.metrics
+            .reconcile_repositories
+            .with_label_values(&["enabled"])
+            .set(renovate_enabled.len() as i64);
+
+        Ok(ReconcilePlan {
+            discovered: repos,
+            filtered_out,
+            renovate_enabled,
+            webhooks,
+            onboarding,
+        })
+    }
+
+    /// Whether the reconciler would install a webhook on `repo`, i.e. it is
+    /// included by `options` and has renovate enabled
+    pub async fn wants_webhook(
+        &self,
+        options: &ReconcileOptions,
+        repo: Repository,
+    ) -> anyhow::Result<bool> {
+        let filter = options.filter()?;
+
+        let repos = self.with_topics(vec![repo]).await?;
+        if !repos.iter().all(|r| options.includes(filter.as_ref(), r)) {
+            return Ok(false);
+        }
+
+        Ok(!self.get_renovate_enabled(&repos).await?.is_empty())
+    }
+
+    /// Whether `repo` opted out through `options.disabled_topic`. Runs not
+    /// started by a reconcile check this, as the topic is meant to stop every
+    /// renovate run and not only scheduled ones.
+    pub async fn opted_out(
+        &self,
+        options: &ReconcileOptions,
+        repo: &Repository,
+    ) -> anyhow::Result<bool> {
+        let repos = self.with_topics(vec![repo.clone()]).await?;
+
+        Ok(repos
+            .iter()
+            .any(|r| options.opted_in_by_topic(r) == Some(false)))
+    }
+
+    /// Fetches `repo` again and checks it is still included by its metadata
+    /// and topics, for runs submitted a while after they were requested. The
+    /// filter isn't checked, like for the other runs not started by a
+    /// reconcile.
+    pub async fn recheck(
+        &self,
+        options: &ReconcileOptions,
+        repo: &Repository,
+    ) -> anyhow::Result<Recheck> {
+        let Some(current) = self.gitea_client.get_repository(repo).await? else {
+            return Ok(Recheck::Excluded("deleted".into()));
+        };
+
+        let Some(current) = self.with_topics(vec![current]).await?.pop() else {
+            anyhow::bail!("gitea didn't return topics for: {}", repo);
+        };
+
+        if let Some(reason) = options.excluded_by_metadata(&current) {
+            return Ok(Recheck::Excluded(reason.into()));
+        }
+
+        if options.opted_in_by_topic(&current) == Some(false) {
+            return Ok(Recheck::Excluded(format!(
+                "opted out with the `{}` topic",
+                options.disabled_topic
+            )));
+        }
+
+        Ok(Recheck::Included(current))
+    }
+
+    /// Applies every change of `plan`, a failing change is logged without
+    /// abandoning the others
+    pub async fn apply(&self, plan: &ReconcilePlan) -> anyhow::Result<()> {
+        tracing::debug!("applying webhook changes: {}", plan.webhooks.len());
+
+        let tasks = plan
+            .webhooks
+            .iter()
+            .map(|change| async move {
+                self.apply_webhook(change).await.with_context(|| {
+                    format!(
+                        "failed to {} webhook for {}",
+                        change.action, change.repository
+                    )
+                })
+            })
+            .collect::<FuturesUnordered<_>>();
+        let webhook_failures = Self::log_failures(tasks, "webhook").await;
+
+        tracing::debug!("applying onboarding changes: {}", plan.onboarding.len());
+
+        let tasks = plan
+            .onboarding
+            .iter()
+            .map(|change| async move {
+                self.apply_onboarding(change)
+                    .await
+                    .with_context(|| format!("failed to {}", change))
+            })
+            .collect::<FuturesUnordered<_>>();
+        let onboarding_failures = Self::log_failures(tasks, "onboarding").await;
+
+        let failures = webhook_failures + onboarding_failures;
+        if failures > 0 {
+            anyhow::bail!(
+                "{} of {} changes failed to apply",
+                failures,
+                plan.webhooks.len() + plan.onboarding.len()
+            );
+        }
+
+        Ok(())
+    }
+
+    async fn apply_webhook(&self, change: &WebhookChange) -> anyhow::Result<()> {
+        let repo = &change.repository;
+
+        match (change.action, &change.webhook) {
+            (WebhookAction::Create, _) => {
+                tracing::trace!("webhook was not found for {} adding", repo);
+                self.gitea_client.add_webhook(repo).await?;
+            }
+            (WebhookAction::Update, Some(webhook)) => {
+                tracing::trace!("webhook already found for {} refreshing it", repo);
+                self.gitea_client.update_webhook(repo, webhook).await?;
+            }
+            (WebhookAction::Delete, Some(webhook)) => {
+                tracing::info!("removing webhook from: {}", repo);
+                self.gitea_client.delete_webhook(repo, webhook).await?;
+            }
+            (action, None) => {
+                anyhow::bail!("cannot {} webhook for {} without a webhook", action, repo)
+            }
+        }
+
+        self.metrics
+            .reconcile_webhook_changes
+            .with_label_values(&[&change.action.to_string()])
+            .inc();
+
+        Ok(())
+    }
+
+    async fn apply_onboarding(&self, change: &OnboardingChange) -> anyhow::Result<()> {
+        let repo = &change.repository;
+
+        match &change.action {
+            OnboardingAction::Open => {
+                let pull_request = self.open_onboarding(repo).await?;
+
+                self.onboarding
+                    .set_status(repo, OnboardingStatus::Open, Some(pull_request))
+                    .await?;
+            }
+            OnboardingAction::Record {
+                status,
+                pull_request,
+            } => {
+                tracing::info!("onboarding of: {} is {}", repo, status);
+                self.onboarding
+                    .set_status(repo, *status, *pull_request)
+                    .await?;
+            }
+        }
+
         Ok(())
     }
 
+    /// Waits for all `tasks`, logging the failed ones instead of abandoning the
+    /// rest, returns how many failed
+    async fn log_failures(
+        mut tasks: FuturesUnordered<impl Future<Output = anyhow::Result<()>>>,
+        kind: &str,
+    ) -> usize {
+        let mut failures = 0;
+
+        while let Some(res) = tasks.next().await {
+            if let Err(e) = res {
+                tracing::warn!("failed to apply {} change: {:#}", kind, e);
+                failures += 1;
+            }
+        }
+
+        failures
+    }
+
+    /// Commits the onboarding template to the onboarding branch and opens a pull
+    /// request for it, returns the number of the pull request
+    async fn open_onboarding(&self, repo: &Repository) -> anyhow::Result<u64> {
+        let config = &self.onboarding_config;
+
+        // A previous attempt may have failed after creating the branch
+        if !self
+            .gitea_client
+            .branch_exists(repo, &config.branch)
+            .await?
+        {
+            self.gitea_client
+                .create_file(
+                    repo,
+                    &config.branch,
+                    ONBOARDING_CONFIG_FILE,
+                    config.template_for(&repo.owner),
+                    "Add renovate config",
+                )
+                .await?;
+        }
+
+        let body = format!(
+            "Merging this pull request adds a `{}` to the repository, after which \
+            contractor runs Renovate for it.\n\n\
+            Close this pull request without merging to opt out, contractor won't \
+            propose it again.",
+            ONBOARDING_CONFIG_FILE
+        );
+
+        let pull_request = self
+            .gitea_client
+            .create_pull_request(repo, &config.branch, &config.title, &body)
+            .await?;
+
+        tracing::info!(
+            "opened onboarding pull request #{} for: {}",
+            pull_request.number,
+            repo
+        );
+        self.metrics.onboarding_pull_requests.inc();
+
+        Ok(pull_request.number)
+    }
+
     async fn get_repos(
         &self,
         user: Option<String>,
+        own: bool,
         orgs: Option<Vec<String>>,
     ) -> anyhow::Result<Vec<Repository>> {
         let mut repos = Vec::new();
 
+        if own {
+            let mut r = self.gitea_client.get_own_repositories().await?;
+
+            repos.append(&mut r);
+        }
+
         if let Some(user) = user {
             let mut r = self.gitea_client.get_user_repositories(&user).await?;
 
@@ -86,18 +648,46 @@ impl Reconciler {
         Ok(repos.into_iter().unique().collect())
     }
 
-    async fn get_renovate_enabled(&self, repos: &[Repository]) -> anyhow::Result<Vec<Repository>> {
+    /// Fills in the topics of repositories gitea didn't include them for
+    async fn with_topics(&self, repos: Vec<Repository>) -> anyhow::Result<Vec<Repository>> {
+        futures::stream::iter(repos)
+            .map(|mut repo| async move {
+                if repo.metadata.topics.is_none() {
+                    repo.metadata.topics = Some(self.gitea_client.get_topics(&repo).await?);
+                }
+
+                Ok::<Repository, anyhow::Error>(repo)
+            })
+            .buffered(10)
+            .try_collect()
+            .await
+    }
+
+    async fn get_renovate_enabled(
+        &self,
+        repos: &[Repository],
+    ) -> anyhow::Result<Vec<RenovateRepository>> {
         let mut futures = FuturesUnordered::new();
 
         for repo in repos {
             futures.push(async move {
-                let enabled = self.gitea_client.renovate_enabled(repo).await?;
+                match self.gitea_client.renovate_enabled(repo).await? {
+                    Some(config_file) => {
+                        tracing::trace!(
+                            "repository: {}, has renovate config: {}",
+                            repo,
+                            config_file
+                        );
 
-                if enabled {
-                    Ok::<Option<Repository>, anyhow::Error>(Some(repo.to_owned()))
-                } else {
-                    tracing::trace!("repository: {:?}, doesn't have renovate enabled", repo);
-                    Ok(None)
+                        Ok::<Option<RenovateRepository>, anyhow::Error>(Some(RenovateRepository {
+                            repository: repo.to_owned(),
+                            config_file,
+                        }))
+                    }
+                    None => {
+                        tracing::trace!("repository: {:?}, doesn't have renovate enabled", repo);
+                        Ok(None)
+                    }
                 }
             })
         }
@@ -115,30 +705,191 @@ impl Reconciler {
         Ok(enabled)
     }
 
-    async fn ensure_webhook(
+    async fn plan_webhooks(
         &self,
         repos: &[Repository],
         force_refresh: bool,
-    ) -> anyhow::Result<()> {
-        tracing::debug!("ensuring webhooks are setup for repos");
+    ) -> anyhow::Result<Vec<WebhookChange>> {
+        tracing::debug!("planning webhooks for repos");
 
         let mut tasks = FuturesUnordered::new();
 
         for repo in repos {
             tasks.push(async move {
-                self.gitea_client
-                    .ensure_webhook(repo, force_refresh)
-                    .await?;
+                let change = match (self.gitea_client.get_webhook(repo).await?, force_refresh) {
+                    (Some(_), false) => {
+                        tracing::trace!("webhook already found for {} skipping...", repo);
+                        None
+                    }
+                    (Some(webhook), true) => Some(WebhookChange {
+                        repository: repo.to_owned(),
+                        action: WebhookAction::Update,
+                        webhook: Some(webhook),
+                    }),
+                    (None, _) => Some(WebhookChange {
+                        repository: repo.to_owned(),
+                        action: WebhookAction::Create,
+                        webhook: None,
+                    }),
+                };
 
-                Ok::<(), anyhow::Error>(())
+                Ok::<Option<WebhookChange>, anyhow::Error>(change)
             })
         }
 
+        let mut changes = Vec::new();
         while let Some(res) = tasks.next().await {
-            res?;
+            if let Some(change) = res? {
+                changes.push(change);
+            }
         }
 
-        Ok(())
+        Ok(changes)
+    }
+
+    /// Plans onboarding pull requests for `repos`, which don't have a renovate
+    /// config. Gitea is the source of truth for existing pull requests, so a
+    /// second one is never opened even if the recorded status was lost.
+    async fn plan_onboarding(
+        &self,
+        repos: &[Repository],
+        enabled: &[Repository],
+    ) -> anyhow::Result<Vec<OnboardingChange>> {
+        tracing::debug!("planning onboarding for repos: {}", repos.len());
+
+        let mut tasks = FuturesUnordered::new();
+
+        for repo in repos {
+            tasks.push(async move {
+                if repo.metadata.empty || repo.metadata.archived {
+                    tracing::trace!("repository: {}, can't be onboarded, skipping", repo);
+                    return Ok(None);
+                }
+
+                // Declined and merged are final, even if the pull request was
+                // deleted from gitea since
+                let recorded = self.onboarding.get_status(repo).await?;
+                if let Some(onboarding) = &recorded {
+                    if onboarding.status != OnboardingStatus::Open {
+                        tracing::trace!(
+                            "repository: {}, onboarding is {}, skipping",
+                            repo,
+                            onboarding.status
+                        );
+                        return Ok(None);
+                    }
+                }
+
+                let Some(pull_request) = self
+                    .gitea_client
+                    .get_pull_request(repo, &self.onboarding_config.branch)
+                    .await?
+                else {
+                    return Ok(Some(OnboardingChange {
+                        repository: repo.to_owned(),
+                        action: OnboardingAction::Open,
+                    }));
+                };
+
+                let status = match (pull_request.state.as_str(), pull_request.merged) {
+                    (_, true) => OnboardingStatus::Merged,
+                    ("open", _) => OnboardingStatus::Open,
+                    _ => OnboardingStatus::Declined,
+                };
+
+                Ok::<Option<OnboardingChange>, anyhow::Error>(Self::record_change(
+                    repo,
+                    recorded.as_ref(),
+                    status,
+                    Some(pull_request.number),
+                ))
+            })
+        }
+
+        let mut changes = Vec::new();
+        while let Some(res) = tasks.next().await {
+            if let Some(change) = res? {
+                changes.push(change);
+            }
+        }
+
+        // Renovate was enabled through an onboarding pull request which was
+        // merged since the last reconcile
+        let mut tasks = FuturesUnordered::new();
+        for repo in enabled {
+            tasks.push(async move {
+                match self.onboarding.get_status(repo).await? {
+                    Some(onboarding) if onboarding.status == OnboardingStatus::Open => {
+                        Ok(Self::record_change(
+                            repo,
+                            Some(&onboarding),
+                            OnboardingStatus::Merged,
+                            onboarding.pull_request,
+                        ))
+                    }
+                    _ => Ok::<Option<OnboardingChange>, anyhow::Error>(None),
+                }
+            })
+        }
+
+        while let Some(res) = tasks.next().await {
+            if let Some(change) = res? {
+                changes.push(change);
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// A change recording `status`, `None` if `recorded` already has it
+    fn record_change(
+        repo: &Repository,
+        recorded: Option<&Onboarding>,
+        status: OnboardingStatus,
+        pull_request: Option<u64>,
+    ) -> Option<OnboardingChange> {
+        if recorded.is_some_and(|r| r.status == status && r.pull_request == pull_request) {
+            return None;
+        }
+
+        Some(OnboardingChange {
+            repository: repo.to_owned(),
+            action: OnboardingAction::Record {
+                status,
+                pull_request,
+            },
+        })
+    }
+
+    async fn plan_prune(&self, repos: &[Repository]) -> anyhow::Result<Vec<WebhookChange>> {
+        tracing::debug!("planning webhook pruning for repos: {}", repos.len());
+
+        let mut tasks = FuturesUnordered::new();
+
+        for repo in repos {
+            tasks.push(async move {
+                let change =
+                    self.gitea_client
+                        .get_webhook(repo)
+                        .await?
+                        .map(|webhook| WebhookChange {
+                            repository: repo.to_owned(),
+                            action: WebhookAction::Delete,
+                            webhook: Some(webhook),
+                        });
+
+                Ok::<Option<WebhookChange>, anyhow::Error>(change)
+            })
+        }
+
+        let mut changes = Vec::new();
+        while let Some(res) = tasks.next().await {
+            if let Some(change) = res? {
+                changes.push(change);
+            }
+        }
+
+        Ok(changes)
     }
 }
 
@@ -148,6 +899,493 @@ pub trait ReconcilerState {
 
 impl ReconcilerState for SharedState {
     fn reconciler(&self) -> Reconciler {
-        Reconciler::new(self.gitea_client())
+        Reconciler::new(
+            self.gitea_client(),
+            self.metrics.clone(),
+            self.onboarding.clone(),
+            self.config.onboarding.clone(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::services::gitea::testing::{self, contractor_hook, writes, FakeGitea, Requests};
+
+    fn repo(name: &str) -> serde_json::Value {
+        serde_json::json!({
+            "full_name": format!("someone/{}", name),
+            "default_branch": "main",
+            "topics": [],
+        })
+    }
+
+    fn options() -> ReconcileOptions {
+        ReconcileOptions {
+            user: Some("someone".into()),
+            ..Default::default()
+        }
+    }
+
+    async fn reconciler(gitea: FakeGitea) -> (Reconciler, Requests) {
+        reconciler_with(gitea, OnboardingStore::in_memory()).await
+    }
+
+    async fn reconciler_with(
+        gitea: FakeGitea,
+        onboarding: OnboardingStore,
+    ) -> (Reconciler, Requests) {
+        let (url, requests) = gitea.serve().await;
+        let config = testing::config(&url);
+        let metrics = Metrics::new().unwrap();
+
+        let reconciler = Reconciler::new(
+            GiteaClient::new(&config, metrics.clone()),
+            metrics,
+            onboarding,
+            config.onboarding.clone(),
+        );
+
+        (reconciler, requests)
+    }
+
+    /// A fake gitea with a repository for each of `names`, those in `enabled`
+    /// have a renovate config and those in `hooked` contractor's webhook
+    fn gitea(names: &[&str], enabled: &[&str], hooked: &[&str]) -> FakeGitea {
+        FakeGitea {
+            repos: names.iter().map(|name| repo(name)).collect(),
+            files: enabled
+                .iter()
+                .map(|name| (format!("someone/{}/renovate.json", name), "{}".into()))
+                .collect(),
+            hooks: hooked
+                .iter()
+                .enumerate()
+                .map(|(id, name)| (format!("someone/{}", name), vec![contractor_hook(id)]))
+                .collect(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn plans_webhook_changes() {
+        struct Case {
+            name: &'static str,
+            enabled: bool,
+            hooked: bool,
+            force_refresh: bool,
+            prune: bool,
+            filter: Option<&'static str>,
+            expected: Option<WebhookAction>,
+        }
+
+        let case = Case {
+            name: "",
+            enabled: true,
+            hooked: false,
+            force_refresh: false,
+            prune: false,
+            filter: None,
+            expected: None,
+        };
+
+        for case in [
+            Case {
+                name: "creates missing webhooks",
+                expected: Some(WebhookAction::Create),
+                ..case
+            },
+            Case {
+                name: "skips existing webhooks",
+                hooked: true,
+                ..case
+            },
+            Case {
+                name: "updates existing webhooks when refreshing",
+                hooked: true,
+                force_refresh: true,
+                expected: Some(WebhookAction::Update),
+                ..case
+            },
+            Case {
+                name: "skips repositories without renovate",
+                enabled: false,
+                ..case
+            },
+            Case {
+                name: "keeps webhooks without renovate unless pruning",
+                enabled: false,
+                hooked: true,
+                ..case
+            },
+            Case {
+                name: "prunes webhooks without renovate",
+                enabled: false,
+                hooked: true,
+                prune: true,
+                expected: Some(WebhookAction::Delete),
+                ..case
+            },
+            Case {
+                name: "prunes webhooks of filtered out repositories",
+                hooked: true,
+                prune: true,
+                filter: Some("^other/"),
+                expected: Some(WebhookAction::Delete),
+                ..case
+            },
+            Case {
+                name: "skips filtered out repositories",
+                filter: Some("^other/"),
+                ..case
+            },
+            Case {
+                name: "has nothing to prune without a webhook",
+                enabled: false,
+                prune: true,
+                ..case
+            },
+        ] {
+            let enabled: &[&str] = if case.enabled { &["repo"] } else { &[] };
+            let hooked: &[&str] = if case.hooked { &["repo"] } else { &[] };
+            let (reconciler, _) = reconciler(gitea(&["repo"], enabled, hooked)).await;
+
+            let plan = reconciler
+                .plan(&ReconcileOptions {
+                    force_refresh: case.force_refresh,
+                    prune: case.prune,
+                    filter: case.filter.map(Into::into),
+                    ..options()
+                })
+                .await
+                .unwrap();
+
+            let actions = plan.webhooks.iter().map(|c| c.action).collect::<Vec<_>>();
+            assert_eq!(
+                actions,
+                case.expected.into_iter().collect::<Vec<_>>(),
+                "{}",
+                case.name
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn applies_the_remaining_changes_after_a_failure() {
+        let mut gitea = gitea(&["a", "b", "c"], &["a", "b"], &["c"]);
+        gitea
+            .failing
+            .insert("POST /api/v1/repos/someone/a/hooks".into());
+        let (reconciler, requests) = reconciler(gitea).await;
+
+        let plan = reconciler
+            .plan(&ReconcileOptions {
+                prune: true,
+                ..options()
+            })
+            .await
+            .unwrap();
+        let error = reconciler.apply(&plan).await.unwrap_err();
+
+        assert_eq!(error.to_string(), "1 of 3 changes failed to apply");
+        let mut writes = writes(&requests);
+        writes.sort();
+        assert_eq!(
+            writes,
+            vec![
+                "DELETE /api/v1/repos/someone/c/hooks/0",
+                "POST /api/v1/repos/someone/a/hooks",
+                "POST /api/v1/repos/someone/b/hooks",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn planning_doesnt_change_anything() {
+        let (reconciler, requests) =
+            reconciler(gitea(&["a", "b", "c", "d"], &["a", "b"], &["b", "c"])).await;
+
+        let plan = reconciler
+            .plan(&ReconcileOptions {
+                force_refresh: true,
+                prune: true,
+                onboard: true,
+                ..options()
+            })
+            .await
+            .unwrap();
+
+        // create a, update b, delete c
+        assert_eq!(plan.webhooks.len(), 3);
+        // onboard c and d
+        assert_eq!(plan.onboarding.len(), 2);
+        assert!(!requests.lock().unwrap().is_empty());
+        assert_eq!(writes(&requests), Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn includes_repositories_by_their_metadata() {
+        type Include = fn(&mut ReconcileOptions, bool);
+
+        let flags: [(&str, bool, Include); 5] = [
+            ("archived", false, |o, v| o.include_archived = v),
+            ("mirror", false, |o, v| o.include_mirrors = v),
+            ("fork", true, |o, v| o.include_forks = v),
+            ("empty", true, |o, v| o.include_empty = v),
+            ("private", true, |o, v| o.include_private = v),
+        ];
+
+        for (flag, included_by_default, include) in flags {
+            let mut repo = repo("repo");
+            repo[flag] = true.into();
+            let gitea = FakeGitea {
+                repos: vec![repo],
+                files: [("someone/repo/renovate.json".into(), "{}".into())].into(),
+                ..Default::default()
+            };
+            let (reconciler, _) = reconciler(gitea).await;
+
+            for included in [None, Some(true), Some(false)] {
+                let mut options = options();
+                if let Some(included) = included {
+                    include(&mut options, included);
+                }
+
+                let plan = reconciler.plan(&options).await.unwrap();
+
+                let expected = included.unwrap_or(included_by_default);
+                assert_eq!(
+                    (plan.renovate_enabled.len(), plan.filtered_out.len()),
+                    if expected { (1, 0) } else { (0, 1) },
+                    "{} repository, included: {:?}",
+                    flag,
+                    included
+                );
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn topics_take_precedence_over_the_filter() {
+        for (topics, filter, archived, included) in [
+            (vec![], None, false, true),
+            (vec![], Some("^other/"), false, false),
+            (vec!["contractor-enabled"], Some("^other/"), false, true),
+            (vec!["contractor-disabled"], None, false, false),
+            (
+                vec!["contractor-enabled", "contractor-disabled"],
+                None,
+                false,
+                false,
+            ),
+            // Opting in doesn't override the metadata checks
+            (vec!["contractor-enabled"], None, true, false),
+        ] {
+            let mut repo = repo("repo");
+            repo["topics"] = topics.clone().into();
+            repo["archived"] = archived.into();
+            let gitea = FakeGitea {
+                repos: vec![repo],
+                files: [("someone/repo/renovate.json".into(), "{}".into())].into(),
+                ..Default::default()
+            };
+            let (reconciler, _) = reconciler(gitea).await;
+
+            let plan = reconciler
+                .plan(&ReconcileOptions {
+                    filter: filter.map(Into::into),
+                    ..options()
+                })
+                .await
+                .unwrap();
+
+            assert_eq!(
+                plan.renovate_enabled.len() == 1,
+                included,
+                "topics: {:?}, filter: {:?}, archived: {}",
+                topics,
+                filter,
+                archived
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn only_fetches_topics_gitea_left_out() {
+        let mut without_topics = repo("without-topics");
+        without_topics.as_object_mut().unwrap().remove("topics");
+        let mut null_topics = repo("null-topics");
+        null_topics["topics"] = serde_json::Value::Null;
+
+        let gitea = FakeGitea {
+            repos: vec![without_topics, null_topics, repo("no-topics")],
+            topics: [(
+                "someone/without-topics".into(),
+                vec!["contractor-disabled".into()],
+            )]
+            .into(),
+            ..Default::default()
+        };
+        let (reconciler, requests) = reconciler(gitea).await;
+
+        let plan = reconciler.plan(&options()).await.unwrap();
+
+        let topic_requests = requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|r| r.ends_with("/topics"))
+            .cloned()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            topic_requests,
+            vec!["GET /api/v1/repos/someone/without-topics/topics"]
+        );
+        assert_eq!(
+            plan.filtered_out
+                .iter()
+                .map(|r| r.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["without-topics"]
+        );
+    }
+
+    fn onboard() -> ReconcileOptions {
+        ReconcileOptions {
+            onboard: true,
+            ..options()
+        }
+    }
+
+    fn someone(name: &str) -> Repository {
+        Repository {
+            owner: "someone".into(),
+            name: name.into(),
+            metadata: Default::default(),
+        }
+    }
+
+    /// The onboarding pull request of `someone/repo`
+    fn onboarding_pull(state: &str, merged: bool) -> (String, serde_json::Value) {
+        (
+            "someone/repo/contractor/onboarding".into(),
+            serde_json::json!({ "number": 3, "state": state, "merged": merged }),
+        )
+    }
+
+    async fn recorded(store: &OnboardingStore) -> Option<(OnboardingStatus, Option<u64>)> {
+        store
+            .get_status(&someone("repo"))
+            .await
+            .unwrap()
+            .map(|o| (o.status, o.pull_request))
+    }
+
+    #[tokio::test]
+    async fn opens_an_onboarding_pull_request() {
+        let store = OnboardingStore::in_memory();
+        let (reconciler, requests) =
+            reconciler_with(gitea(&["repo"], &[], &[]), store.clone()).await;
+
+        let plan = reconciler.plan(&onboard()).await.unwrap();
+        assert_eq!(
+            plan.onboarding
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>(),
+            vec!["open someone/repo"]
+        );
+
+        reconciler.apply(&plan).await.unwrap();
+        assert_eq!(
+            writes(&requests),
+            vec![
+                "POST /api/v1/repos/someone/repo/contents/renovate.json",
+                "POST /api/v1/repos/someone/repo/pulls",
+            ]
+        );
+        assert_eq!(
+            recorded(&store).await,
+            Some((OnboardingStatus::Open, Some(1)))
+        );
+    }
+
+    #[tokio::test]
+    async fn records_the_state_of_the_onboarding_pull_request() {
+        for (state, merged, status) in [
+            ("open", false, OnboardingStatus::Open),
+            ("closed", false, OnboardingStatus::Declined),
+            ("closed", true, OnboardingStatus::Merged),
+        ] {
+            let store = OnboardingStore::in_memory();
+            let mut gitea = gitea(&["repo"], &[], &[]);
+            gitea.pulls = [onboarding_pull(state, merged)].into();
+            let (reconciler, _) = reconciler_with(gitea, store.clone()).await;
+
+            let plan = reconciler.plan(&onboard()).await.unwrap();
+            reconciler.apply(&plan).await.unwrap();
+            assert_eq!(recorded(&store).await, Some((status, Some(3))), "{}", state);
+
+            // Recorded already, so there is nothing left to do
+            let plan = reconciler.plan(&onboard()).await.unwrap();
+            assert!(plan.onboarding.is_empty(), "{}", state);
+        }
+    }
+
+    #[tokio::test]
+    async fn declined_and_merged_onboardings_are_final() {
+        for status in [OnboardingStatus::Declined, OnboardingStatus::Merged] {
+            let store = OnboardingStore::in_memory();
+            store
+                .set_status(&someone("repo"), status, Some(3))
+                .await
+                .unwrap();
+
+            // The pull request was deleted, or reopened, since
+            for pulls in [vec![], vec![onboarding_pull("open", false)]] {
+                let mut gitea = gitea(&["repo"], &[], &[]);
+                gitea.pulls = pulls.into_iter().collect();
+                let (reconciler, requests) = reconciler_with(gitea, store.clone()).await;
+
+                let plan = reconciler.plan(&onboard()).await.unwrap();
+                reconciler.apply(&plan).await.unwrap();
+
+                assert!(plan.onboarding.is_empty(), "{}", status);
+                assert_eq!(writes(&requests), Vec::<String>::new());
+                assert_eq!(recorded(&store).await, Some((status, Some(3))));
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn records_onboardings_merged_since_the_last_reconcile() {
+        for pull_request in [Some(3), None] {
+            let store = OnboardingStore::in_memory();
+            store
+                .set_status(&someone("repo"), OnboardingStatus::Open, pull_request)
+                .await
+                .unwrap();
+            let (reconciler, _) =
+                reconciler_with(gitea(&["repo"], &["repo"], &["repo"]), store.clone()).await;
+
+            let plan = reconciler.plan(&onboard()).await.unwrap();
+            let changes = plan
+                .onboarding
+                .iter()
+                .map(|c| c.to_string())
+                .collect::<Vec<_>>();
+            match pull_request {
+                Some(_) => assert_eq!(changes, vec!["record someone/repo #3 merged"]),
+                // Without a pull request there is no #0 to record
+                None => assert_eq!(changes, vec!["record someone/repo merged"]),
+            }
+
+            reconciler.apply(&plan).await.unwrap();
+            assert_eq!(
+                recorded(&store).await,
+                Some((OnboardingStatus::Merged, pull_request))
+            );
+        }
     }
 }
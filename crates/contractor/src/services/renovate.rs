@@ -1,3 +1,410 @@
+use std::{fmt::Display, str::FromStr};
+
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+
 pub struct RenovateConfig {
     pub repo: String,
+    pub image: String,
+}
+
+/// The files renovate reads a repository config from, in the order renovate
+/// looks for them. `package.json` only counts when it has a `renovate` key.
+pub const RENOVATE_CONFIG_FILES: &[&str] = &[
+    "renovate.json",
+    "renovate.json5",
+    ".github/renovate.json",
+    ".github/renovate.json5",
+    ".gitlab/renovate.json",
+    ".gitlab/renovate.json5",
+    ".renovaterc",
+    ".renovaterc.json",
+    ".renovaterc.json5",
+    "package.json",
+];
+
+/// Renovate logs are kept up to this many entries, dropping the oldest
+const MAX_LOG_ENTRIES: usize = 20_000;
+
+/// Marks lines renovate wrote to stderr. Both streams are captured as a single
+/// output, so lines keep roughly the order they were written in.
+pub const STDERR_PREFIX: &str = "[stderr] ";
+
+/// Fields of renovate's json log lines which are already part of [`LogEntry`]
+/// or carry no information
+const BUNYAN_FIELDS: &[&str] = &["name", "hostname", "pid", "level", "msg", "time", "v"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogStream {
+    Stdout,
+    Stderr,
+}
+
+impl Display for LogStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            LogStream::Stdout => "stdout",
+            LogStream::Stderr => "stderr",
+        })
+    }
+}
+
+impl FromStr for LogStream {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stdout" => Ok(LogStream::Stdout),
+            "stderr" => Ok(LogStream::Stderr),
+            _ => anyhow::bail!("{} is not a valid log stream", s),
+        }
+    }
+}
+
+/// A single line of renovate output. Lines renovate printed as json
+/// (`LOG_FORMAT=json`) are split into level, time, message and the remaining
+/// fields, anything else is kept as the message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogEntry {
+    pub stream: LogStream,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub level: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time: Option<String>,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fields: Option<serde_json::Value>,
+}
+
+impl LogEntry {
+    pub fn parse(stream: LogStream, line: &str) -> Self {
+        let raw = || LogEntry {
+            stream,
+            level: None,
+            time: None,
+            message: line.to_string(),
+            fields: None,
+        };
+
+        let Ok(serde_json::Value::Object(mut obj)) = serde_json::from_str(line) else {
+            return raw();
+        };
+        let Some(message) = obj.get("msg").and_then(|m| m.as_str()).map(str::to_string) else {
+            return raw();
+        };
+
+        let level = obj.get("level").and_then(|l| l.as_u64()).map(|l| {
+            match l {
+                0..=10 => "trace",
+                11..=20 => "debug",
+                21..=30 => "info",
+                31..=40 => "warn",
+                41..=50 => "error",
+                _ => "fatal",
+            }
+            .to_string()
+        });
+        let time = obj.get("time").and_then(|t| t.as_str()).map(str::to_string);
+
+        obj.retain(|k, _| !BUNYAN_FIELDS.contains(&k.as_str()));
+        let fields = (!obj.is_empty()).then_some(serde_json::Value::Object(obj));
+
+        LogEntry {
+            stream,
+            level,
+            time,
+            message,
+            fields,
+        }
+    }
+}
+
+impl Display for LogEntry {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(time) = &self.time {
+            write!(f, "{} ", time)?;
+        }
+        if let Some(level) = &self.level {
+            write!(f, "{:>5} ", level.to_uppercase())?;
+        }
+        f.write_str(&self.message)?;
+        if let Some(fields) = &self.fields {
+            write!(f, " {}", fields)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The captured output of a renovate run
+#[derive(Clone, Debug, Default)]
+pub struct RenovateLog {
+    pub entries: Vec<LogEntry>,
+}
+
+impl RenovateLog {
+    /// Parses the combined output of a run, where stderr lines start with
+    /// [`STDERR_PREFIX`]
+    pub fn parse(output: &str) -> Self {
+        let mut entries = output
+            .lines()
+            .map(|l| match l.strip_prefix(STDERR_PREFIX) {
+                Some(l) => (LogStream::Stderr, l),
+                None => (LogStream::Stdout, l),
+            })
+            .filter(|(_, l)| !l.trim().is_empty())
+            .map(|(stream, line)| LogEntry::parse(stream, line))
+            .collect::<Vec<_>>();
+
+        if entries.len() > MAX_LOG_ENTRIES {
+            entries.drain(..entries.len() - MAX_LOG_ENTRIES);
+        }
+
+        Self { entries }
+    }
+
+    pub fn to_text(&self) -> String {
+        self.entries.iter().map(|e| e.to_string()).join("\n")
+    }
+}
+
+/// Warnings and errors kept in a [`RenovateRunSummary`]
+const MAX_SUMMARY_MESSAGES: usize = 20;
+
+/// A pull request renovate opened or updated
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PullRequestRef {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub number: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+
+impl Display for PullRequestRef {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (&self.number, &self.title) {
+            (Some(number), Some(title)) => write!(f, "#{} {}", number, title),
+            (Some(number), None) => write!(f, "#{}", number),
+            (None, Some(title)) => f.write_str(title),
+            (None, None) => f.write_str("unknown pull request"),
+        }
+    }
+}
+
+/// What a renovate run did, parsed from its json log lines
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct RenovateRunSummary {
+    pub prs_opened: Vec<PullRequestRef>,
+    pub prs_updated: Vec<PullRequestRef>,
+    pub branches_deleted: Vec<String>,
+    /// `None` when renovate didn't get as far as extracting dependencies
+    pub dependencies_found: Option<u64>,
+    /// The first warnings logged, deduplicated
+    pub warnings: Vec<String>,
+    /// The first errors logged, deduplicated
+    pub errors: Vec<String>,
+}
+
+impl RenovateRunSummary {
+    pub fn from_log(log: &RenovateLog) -> Self {
+        let mut summary = Self::default();
+
+        for entry in &log.entries {
+            let field = |name: &str| entry.fields.as_ref().and_then(|f| f.get(name));
+            let pull_request = || PullRequestRef {
+                number: field("pr").and_then(|p| p.as_u64()),
+                title: field("prTitle")
+                    .and_then(|t| t.as_str())
+                    .map(str::to_string),
+            };
+
+            match entry.message.as_str() {
+                "PR created" => summary.prs_opened.push(pull_request()),
+                "PR updated" => summary.prs_updated.push(pull_request()),
+                "Deleting orphan branch" | "Branch deleted" => {
+                    if let Some(branch) = field("branch").and_then(|b| b.as_str()) {
+                        summary.branches_deleted.push(branch.to_string());
+                    }
+                }
+                "Dependency extraction complete" => {
+                    if let Some(count) = field("stats")
+                        .and_then(|s| s.pointer("/total/depCount"))
+                        .and_then(|c| c.as_u64())
+                    {
+                        *summary.dependencies_found.get_or_insert(0) += count;
+                    }
+                }
+                _ => {}
+            }
+
+            let messages = match entry.level.as_deref() {
+                Some("warn") => &mut summary.warnings,
+                Some("error") | Some("fatal") => &mut summary.errors,
+                _ => continue,
+            };
+            if messages.len() < MAX_SUMMARY_MESSAGES && !messages.contains(&entry.message) {
+                messages.push(entry.message.clone());
+            }
+        }
+
+        summary
+    }
+}
+
+impl Display for RenovateRunSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "- PRs opened: {}", self.prs_opened.len())?;
+        for pr in &self.prs_opened {
+            writeln!(f, "  - {}", pr)?;
+        }
+        writeln!(f, "- PRs updated: {}", self.prs_updated.len())?;
+        for pr in &self.prs_updated {
+            writeln!(f, "  - {}", pr)?;
+        }
+        writeln!(f, "- Branches deleted: {}", self.branches_deleted.len())?;
+        if let Some(dependencies) = self.dependencies_found {
+            writeln!(f, "- Dependencies found: {}", dependencies)?;
+        }
+        writeln!(f, "- Warnings: {}", self.warnings.len())?;
+        write!(f, "- Errors: {}", self.errors.len())?;
+        for error in &self.errors {
+            write!(f, "\n  - {}", error)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The result of a renovate run
+#[derive(Clone, Debug, Default)]
+pub struct RenovateRun {
+    pub log: RenovateLog,
+    pub summary: RenovateRunSummary,
+    pub exit_code: i64,
+}
+
+impl RenovateRun {
+    pub fn new(log: RenovateLog, exit_code: i64) -> Self {
+        Self {
+            summary: RenovateRunSummary::from_log(&log),
+            log,
+            exit_code,
+        }
+    }
+}
+
+/// Returned when renovate exits with a non-zero code. Carries the run so its
+/// logs and summary are kept for failed runs too.
+#[derive(Debug)]
+pub struct RenovateFailed {
+    pub run: RenovateRun,
+}
+
+impl Display for RenovateFailed {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "renovate exited with code: {}", self.run.exit_code)
+    }
+}
+
+impl std::error::Error for RenovateFailed {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RUN_LOG: &str = include_str!("../../fixtures/renovate_run.log");
+    const FAILED_RUN_LOG: &str = include_str!("../../fixtures/renovate_failed_run.log");
+
+    #[test]
+    fn parses_json_lines_into_entries() {
+        let log = RenovateLog::parse(RUN_LOG);
+
+        assert_eq!(log.entries.len(), 8);
+
+        let entry = &log.entries[4];
+        assert_eq!(entry.stream, LogStream::Stdout);
+        assert_eq!(entry.level.as_deref(), Some("info"));
+        assert_eq!(entry.time.as_deref(), Some("2026-10-18T08:00:07.731Z"));
+        assert_eq!(entry.message, "PR created");
+
+        let fields = entry.fields.as_ref().unwrap();
+        assert_eq!(fields["pr"], 41);
+        assert!(fields.get("hostname").is_none());
+        assert!(fields.get("msg").is_none());
+    }
+
+    #[test]
+    fn keeps_stderr_lines_in_order() {
+        let log = RenovateLog::parse(FAILED_RUN_LOG);
+
+        let streams = log.entries.iter().map(|e| e.stream).collect::<Vec<_>>();
+        assert_eq!(
+            streams,
+            vec![
+                LogStream::Stdout,
+                LogStream::Stdout,
+                LogStream::Stderr,
+                LogStream::Stdout
+            ]
+        );
+
+        let entry = &log.entries[2];
+        assert_eq!(entry.level, None);
+        assert_eq!(
+            entry.message,
+            "FATAL: Renovate fatal error: authentication failure"
+        );
+    }
+
+    #[test]
+    fn summarises_a_run() {
+        let summary = RenovateRunSummary::from_log(&RenovateLog::parse(RUN_LOG));
+
+        assert_eq!(
+            summary.prs_opened,
+            vec![PullRequestRef {
+                number: Some(41),
+                title: Some("fix(deps): update rust crate serde to 1.0.210".into()),
+            }]
+        );
+        assert_eq!(
+            summary.prs_updated,
+            vec![PullRequestRef {
+                number: Some(37),
+                title: Some("fix(deps): update rust crate tokio to 1.40.0".into()),
+            }]
+        );
+        assert_eq!(summary.branches_deleted, vec!["renovate/anyhow-1.x"]);
+        assert_eq!(summary.dependencies_found, Some(14));
+        assert_eq!(
+            summary.warnings,
+            vec!["Failed to look up npm package left-pad"]
+        );
+        assert!(summary.errors.is_empty());
+    }
+
+    #[test]
+    fn summarises_a_failed_run() {
+        let run = RenovateRun::new(RenovateLog::parse(FAILED_RUN_LOG), 1);
+
+        assert!(run.summary.prs_opened.is_empty());
+        assert_eq!(run.summary.dependencies_found, None);
+        assert_eq!(run.summary.errors, vec!["Repository has unknown error"]);
+
+        let failed = RenovateFailed { run };
+        assert_eq!(failed.to_string(), "renovate exited with code: 1");
+    }
+
+    #[test]
+    fn summary_keeps_the_first_messages() {
+        let output = (0..MAX_SUMMARY_MESSAGES + 5)
+            .map(|i| format!(r#"{{"level":50,"msg":"error {}"}}"#, i))
+            .join("\n");
+
+        let summary = RenovateRunSummary::from_log(&RenovateLog::parse(&output));
+
+        assert_eq!(summary.errors.len(), MAX_SUMMARY_MESSAGES);
+        assert_eq!(summary.errors[0], "error 0");
+    }
 }
@@ -0,0 +1,372 @@
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use crate::config::{CredentialsConfig, RenovateRunnerConfig};
+
+/// A value which must never end up in logs. `Debug` and `Display` are
+/// redacted, the value is only available through [`Secret::expose`].
+#[derive(Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(transparent)]
+pub struct Secret(String);
+
+impl Secret {
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Secret {
+    fn from(value: String) -> Self {
+        Self(value)
+    }
+}
+
+impl std::fmt::Debug for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+impl std::fmt::Display for Secret {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
+/// The credentials renovate runs with for a single repository
+#[derive(Clone, Debug)]
+pub struct RenovateCredentials {
+    pub github_com_token: Secret,
+    pub token: Secret,
+    /// A json object of secrets renovate can reference in repository configs
+    pub secrets: Secret,
+}
+
+/// Resolves the credentials of a repository from the global credentials and
+/// the `renovate.credentials` entries whose pattern matches `owner/name`. When
+/// several patterns match, the more specific one wins, and `secrets` are
+/// merged key by key.
+#[derive(Clone)]
+pub struct RenovateSecrets {
+    github_com_token: Secret,
+    token: Secret,
+    secrets: Secret,
+    /// Ordered from the least to the most specific pattern
+    overrides: Vec<(String, CredentialsConfig)>,
+}
+
+impl RenovateSecrets {
+    pub fn new(config: &RenovateRunnerConfig) -> Self {
+        let mut overrides = config
+            .credentials
+            .iter()
+            .map(|(pattern, credentials)| (pattern.clone(), credentials.clone()))
+            .collect::<Vec<_>>();
+        overrides.sort_by(|(a, _), (b, _)| specificity(a).cmp(&specificity(b)).then(a.cmp(b)));
+
+        Self {
+            github_com_token: config.github_com_token.clone().into(),
+            token: config.token.clone().into(),
+            secrets: config.secrets.clone().into(),
+            overrides,
+        }
+    }
+
+    /// Errors never contain the values of secrets
+    pub async fn resolve(&self, repo: &str) -> anyhow::Result<RenovateCredentials> {
+        let mut credentials = RenovateCredentials {
+            github_com_token: self.github_com_token.clone(),
+            token: self.token.clone(),
+            secrets: self.secrets.clone(),
+        };
+        let mut secrets: Option<serde_json::Map<String, serde_json::Value>> = None;
+
+        for (pattern, overrides) in &self.overrides {
+            if !glob_match(pattern, repo) {
+                continue;
+            }
+
+            tracing::debug!("using renovate credentials: {} for: {}", pattern, repo);
+
+            let field = |name: &str| format!("renovate.credentials.\"{}\".{}", pattern, name);
+
+            if let Some(token) = read_secret(
+                &overrides.github_com_token,
+                &overrides.github_com_token_file,
+                &field("github_com_token"),
+            )
+            .await?
+            {
+                credentials.github_com_token = token;
+            }
+
+            if let Some(token) =
+                read_secret(&overrides.token, &overrides.token_file, &field("token")).await?
+            {
+                credentials.token = token;
+            }
+
+            if let Some(overrides) = read_secret(
+                &overrides.secrets,
+                &overrides.secrets_file,
+                &field("secrets"),
+            )
+            .await?
+            {
+                let merged = match secrets.as_mut() {
+                    Some(merged) => merged,
+                    None => secrets.insert(parse_secrets(&self.secrets, "renovate.secrets")?),
+                };
+
+                merged.extend(parse_secrets(&overrides, &field("secrets"))?);
+            }
+        }
+
+        if let Some(secrets) = secrets {
+            credentials.secrets = serde_json::Value::Object(secrets).to_string().into();
+        }
+
+        Ok(credentials)
+    }
+}
+
+/// The value of `inline`, or the content of `file` read now so rotated files
+/// are picked up without a restart
+async fn read_secret(
+    inline: &Option<Secret>,
+    file: &Option<PathBuf>,
+    field: &str,
+) -> anyhow::Result<Option<Secret>> {
+    if let Some(secret) = inline {
+        return Ok(Some(secret.clone()));
+    }
+
+    let Some(path) = file else {
+        return Ok(None);
+    };
+
+    let content = tokio::fs::read_to_string(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("failed to read {}_file: {}, {}", field, path.display(), e))?;
+
+    Ok(Some(
+        content.trim_end_matches(['\n', '\r']).to_string().into(),
+    ))
+}
+
+/// Parses a secrets json object, the error only mentions the kind of problem
+/// as serde errors may quote the input
+fn parse_secrets(
+    secrets: &Secret,
+    field: &str,
+) -> anyhow::Result<serde_json::Map<String, serde_json::Value>> {
+    match serde_json::from_str::<serde_json::Value>(secrets.expose()) {
+        Ok(serde_json::Value::Object(secrets)) => Ok(secrets),
+        _ => anyhow::bail!("{} should be a json object", field),
+    }
+}
+
+/// Patterns with more literal characters are more specific
+fn specificity(pattern: &str) -> usize {
+    pattern.chars().filter(|c| !matches!(c, '*' | '?')).count()
+}
+
+/// Matches `text` against `pattern`, where `*` matches any number of
+/// characters and `?` a single one
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern = pattern.chars().collect::<Vec<_>>();
+    let text = text.chars().collect::<Vec<_>>();
+
+    let (mut p, mut t) = (0, 0);
+    // Where the last `*` was seen and the text position it currently covers up to
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match backtrack {
+                Some((star, covered)) => {
+                    p = star + 1;
+                    t = covered + 1;
+                    backtrack = Some((star, covered + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+/// Maps a secret's name to a dagger secret name unique to a single run, so
+/// concurrent runs with different credentials never share a secret
+pub fn dagger_secret_name(name: &str, run: &uuid::Uuid) -> String {
+    format!("{}_{}", name, run.simple())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    #[test]
+    fn glob_matches_owner_and_name() {
+        assert!(glob_match("my-org/*", "my-org/api"));
+        assert!(glob_match("*", "my-org/api"));
+        assert!(glob_match("my-org/api", "my-org/api"));
+        assert!(glob_match("my-org/api-?", "my-org/api-1"));
+        assert!(glob_match("*/api", "other/api"));
+        assert!(glob_match("my-*-x", "my-org/team-x"));
+
+        assert!(!glob_match("my-org/api-?", "my-org/api-10"));
+        assert!(!glob_match("my-org/*", "other/api"));
+        assert!(!glob_match("my-org/api", "my-org/api2"));
+        assert!(!glob_match("", "my-org/api"));
+    }
+
+    #[test]
+    fn glob_star_crosses_slashes() {
+        assert!(glob_match("my*api", "my-org/api"));
+        assert!(glob_match("*/*", "my-org/api"));
+        assert!(!glob_match("*/*/*", "my-org/api"));
+    }
+
+    #[test]
+    fn specificity_counts_literal_characters() {
+        assert_eq!(specificity("*"), 0);
+        assert_eq!(specificity("my-org/*"), 7);
+        assert_eq!(specificity("my-org/api-?"), 11);
+        assert!(specificity("my-org/api") > specificity("my-org/*"));
+    }
+
+    fn credentials(token: &str, secrets: Option<&str>) -> CredentialsConfig {
+        CredentialsConfig {
+            token: Some(token.to_string().into()),
+            secrets: secrets.map(|s| s.to_string().into()),
+            ..Default::default()
+        }
+    }
+
+    fn renovate_secrets(credentials: HashMap<String, CredentialsConfig>) -> RenovateSecrets {
+        RenovateSecrets::new(&RenovateRunnerConfig {
+            github_com_token: "github".into(),
+            token: "global".into(),
+            secrets: r#"{"A": "global", "B": "global"}"#.into(),
+            credentials,
+            ..Default::default()
+        })
+    }
+
+    fn parsed(secrets: &Secret) -> serde_json::Value {
+        serde_json::from_str(secrets.expose()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn most_specific_match_wins_and_secrets_merge_key_by_key() {
+        let secrets = renovate_secrets(HashMap::from([
+            (
+                "*".into(),
+                credentials("any", Some(r#"{"B": "any", "C": "any"}"#)),
+            ),
+            (
+                "my-org/*".into(),
+                credentials("org", Some(r#"{"C": "org"}"#)),
+            ),
+            ("other/*".into(), credentials("other", None)),
+        ]));
+
+        let resolved = secrets.resolve("my-org/api").await.unwrap();
+        assert_eq!(resolved.token.expose(), "org");
+        assert_eq!(resolved.github_com_token.expose(), "github");
+        assert_eq!(
+            parsed(&resolved.secrets),
+            serde_json::json!({"A": "global", "B": "any", "C": "org"})
+        );
+
+        let resolved = secrets.resolve("other/api").await.unwrap();
+        assert_eq!(resolved.token.expose(), "other");
+        assert_eq!(
+            parsed(&resolved.secrets),
+            serde_json::json!({"A": "global", "B": "any", "C": "any"})
+        );
+    }
+
+    #[tokio::test]
+    async fn ties_are_broken_by_pattern() {
+        // Both have 7 literal characters, the pattern sorting last wins
+        let secrets = renovate_secrets(HashMap::from([
+            ("my-org/*".into(), credentials("org", None)),
+            ("*/api-10".into(), credentials("api", None)),
+        ]));
+
+        assert_eq!(specificity("my-org/*"), specificity("*/api-10"));
+        assert_eq!(
+            secrets
+                .resolve("my-org/api-10")
+                .await
+                .unwrap()
+                .token
+                .expose(),
+            "org"
+        );
+    }
+
+    #[tokio::test]
+    async fn unmatched_repositories_use_the_global_credentials() {
+        let secrets = renovate_secrets(HashMap::from([(
+            "my-org/*".into(),
+            credentials("org", Some(r#"{"C": "org"}"#)),
+        )]));
+
+        let resolved = secrets.resolve("other/api").await.unwrap();
+        assert_eq!(resolved.token.expose(), "global");
+        assert_eq!(
+            parsed(&resolved.secrets),
+            serde_json::json!({"A": "global", "B": "global"})
+        );
+    }
+
+    #[tokio::test]
+    async fn secret_files_are_read_on_resolve() {
+        let path = std::env::temp_dir().join(format!("contractor-token-{}", uuid::Uuid::new_v4()));
+        tokio::fs::write(&path, "from-file\n").await.unwrap();
+
+        let secrets = renovate_secrets(HashMap::from([(
+            "my-org/*".into(),
+            CredentialsConfig {
+                token_file: Some(path.clone()),
+                ..Default::default()
+            },
+        )]));
+
+        let resolved = secrets.resolve("my-org/api").await;
+        tokio::fs::remove_file(&path).await.unwrap();
+        assert_eq!(resolved.unwrap().token.expose(), "from-file");
+
+        let err = secrets.resolve("my-org/api").await.unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("failed to read renovate.credentials.\"my-org/*\".token_file"));
+    }
+
+    #[tokio::test]
+    async fn invalid_secrets_are_reported_without_their_value() {
+        let secrets = renovate_secrets(HashMap::from([(
+            "my-org/*".into(),
+            credentials("org", Some("not-json-hunter2")),
+        )]));
+
+        let err = secrets.resolve("my-org/api").await.unwrap_err().to_string();
+        assert!(err.contains("should be a json object"));
+        assert!(!err.contains("hunter2"));
+    }
+}
@@ -0,0 +1,85 @@
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Verifies the `X-Gitea-Signature` header sent along with each webhook
+/// delivery. Multiple secrets are accepted so that a secret can be rotated
+/// while `reconcile --force-refresh` pushes the new one to every repository.
+#[derive(Clone)]
+pub struct WebhookSignature {
+    secrets: Vec<String>,
+}
+
+impl WebhookSignature {
+    pub fn new(secrets: Vec<String>) -> Self {
+        Self { secrets }
+    }
+
+    pub fn verify(&self, signature: Option<&str>, body: &[u8]) -> anyhow::Result<()> {
+        let signature = signature.ok_or(anyhow::anyhow!("signature header is missing"))?;
+        let signature = hex::decode(signature.trim()).context("signature is not valid hex")?;
+
+        for secret in &self.secrets {
+            let mut mac = HmacSha256::new_from_slice(secret.as_bytes())
+                .context("failed to construct hmac from secret")?;
+            mac.update(body);
+
+            if mac.verify_slice(&signature).is_ok() {
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("signature did not match any configured secret")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = br#"{"ref":"refs/heads/main"}"#;
+
+    fn sign(secret: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).unwrap();
+        mac.update(body);
+
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn signature() -> WebhookSignature {
+        WebhookSignature::new(vec!["current".into(), "previous".into()])
+    }
+
+    #[test]
+    fn accepts_the_current_and_previous_secret() {
+        assert!(signature()
+            .verify(Some(&sign("current", BODY)), BODY)
+            .is_ok());
+        assert!(signature()
+            .verify(Some(&sign("previous", BODY)), BODY)
+            .is_ok());
+    }
+
+    #[test]
+    fn rejects_other_secrets_and_bodies() {
+        assert!(signature()
+            .verify(Some(&sign("other", BODY)), BODY)
+            .is_err());
+        assert!(signature()
+            .verify(Some(&sign("current", BODY)), b"{}")
+            .is_err());
+    }
+
+    #[test]
+    fn rejects_missing_and_malformed_signatures() {
+        let missing = signature().verify(None, BODY).unwrap_err();
+        assert!(missing.to_string().contains("missing"), "{}", missing);
+
+        let invalid = signature().verify(Some("not hex"), BODY).unwrap_err();
+        assert!(invalid.to_string().contains("hex"), "{}", invalid);
+
+        assert!(signature().verify(Some(""), BODY).is_err());
+    }
+}
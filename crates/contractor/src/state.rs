@@ -1,6 +1,24 @@
-use std::{ops::Deref, sync::Arc};
+use std::{ops::Deref, sync::Arc, time::Duration};
 
-use crate::services::engines::dagger::Dagger;
+use anyhow::Context;
+use sqlx::{PgPool, Pool, Postgres};
+
+use crate::{
+    config::Config,
+    services::{
+        debouncer::PushDebouncer,
+        engines::dagger::Dagger,
+        gitea::GiteaClient,
+        images::RenovateImages,
+        jobs::JobStore,
+        leader::LeaderElection,
+        metrics::Metrics,
+        onboarding::OnboardingStore,
+        queue::JobQueue,
+        reconciler::{ReconcileOptions, Reconciler},
+        signature::WebhookSignature,
+    },
+};
 
 #[derive(Clone)]
 pub struct SharedState(Arc<State>);
@@ -20,27 +38,142 @@ impl Deref for SharedState {
 }
 
 pub struct State {
-    // pub db: Pool<Postgres>,
+    pub config: Config,
+    pub db: Option<Pool<Postgres>>,
     pub engine: Dagger,
+    pub jobs: JobStore,
+    pub leader: LeaderElection,
+    pub metrics: Metrics,
+    pub onboarding: OnboardingStore,
+    pub queue: JobQueue,
+    /// The options of scheduled reconciles, also applied to created repositories
+    pub reconcile: ReconcileOptions,
+    pub push_debouncer: PushDebouncer,
+    pub webhook_signature: WebhookSignature,
 }
 
 impl State {
-    pub async fn new() -> anyhow::Result<Self> {
-        // let db = sqlx::PgPool::connect(
-        //     &std::env::var("DATABASE_URL").context("DATABASE_URL is not set")?,
-        // )
-        // .await?;
+    pub async fn new(config: Config, reconcile: ReconcileOptions) -> anyhow::Result<Self> {
+        let db = match &config.database.url {
+            Some(database_url) => Some(connect_database(database_url, true).await?),
+            None => {
+                tracing::warn!("DATABASE_URL is not set, job history will only be kept in memory");
+                None
+            }
+        };
+
+        let engine = Dagger::new(config.renovate.clone());
+
+        Self::with_engine(config, reconcile, db, engine)
+    }
+
+    /// In-memory stores are used when `db` isn't set
+    fn with_engine(
+        config: Config,
+        reconcile: ReconcileOptions,
+        db: Option<PgPool>,
+        engine: Dagger,
+    ) -> anyhow::Result<Self> {
+        let (jobs, leader, onboarding) = match &db {
+            Some(db) => (
+                JobStore::postgres(db.clone()),
+                LeaderElection::postgres(db.clone()),
+                OnboardingStore::postgres(db.clone()),
+            ),
+            None => (
+                JobStore::in_memory(),
+                LeaderElection::in_memory(),
+                OnboardingStore::in_memory(),
+            ),
+        };
+
+        let metrics = Metrics::new()?;
+
+        let images =
+            RenovateImages::new(GiteaClient::new(&config, metrics.clone()), &config.renovate);
+
+        let queue = JobQueue::new(
+            engine.clone(),
+            images,
+            jobs.clone(),
+            metrics.clone(),
+            config.jobs.max_concurrent,
+        );
+
+        let reconciler = Reconciler::new(
+            GiteaClient::new(&config, metrics.clone()),
+            metrics.clone(),
+            onboarding.clone(),
+            config.onboarding.clone(),
+        );
 
-        // sqlx::migrate!("migrations/crdb")
-        //     .set_locking(false)
-        //     .run(&db)
-        //     .await?;
+        let push_debounce = Duration::from_secs(config.jobs.push_debounce_seconds);
+        let push_debouncer = match &db {
+            Some(db) => PushDebouncer::postgres(
+                db.clone(),
+                queue.clone(),
+                reconciler,
+                reconcile.clone(),
+                push_debounce,
+            ),
+            None => PushDebouncer::in_memory(
+                queue.clone(),
+                reconciler,
+                reconcile.clone(),
+                push_debounce,
+            ),
+        };
 
-        // let _ = sqlx::query("SELECT 1;").fetch_one(&db).await?;
+        let webhook_signature = WebhookSignature::new(
+            std::iter::once(config.webhook.secret.clone())
+                .chain(config.webhook.previous_secret.clone())
+                .collect(),
+        );
 
-        // Ok(Self { db })
-        let engine = Dagger::new();
+        Ok(Self {
+            config,
+            db,
+            engine,
+            jobs,
+            leader,
+            metrics,
+            onboarding,
+            queue,
+            reconcile,
+            push_debouncer,
+            webhook_signature,
+        })
+    }
+}
 
-        Ok(Self { engine })
+#[cfg(test)]
+impl State {
+    /// A state without a database or dagger engine
+    pub fn in_memory(config: Config) -> anyhow::Result<SharedState> {
+        Self::with_engine(
+            config,
+            ReconcileOptions::default(),
+            None,
+            Dagger::disconnected(),
+        )
+        .map(|state| Arc::new(state).into())
     }
 }
+
+/// Connects to the database, migrating it first when `migrate` is set
+pub async fn connect_database(database_url: &str, migrate: bool) -> anyhow::Result<PgPool> {
+    let db = sqlx::PgPool::connect(database_url)
+        .await
+        .context("failed to connect to database")?;
+
+    if migrate {
+        sqlx::migrate!("migrations/crdb")
+            .set_locking(false)
+            .run(&db)
+            .await?;
+    }
+
+    let _ = sqlx::query("SELECT 1;").fetch_one(&db).await?;
+
+    Ok(db)
+}